@@ -0,0 +1,144 @@
+//! Local anchors (`.loop`), anonymous anchors (`:`) and anchor references with an offset (`loop+3`)
+//! A local anchor belongs to the global anchor before it and is stored in the anchor table as `<global>.<local>`, so after `@anchor(main)` both `@goto(.loop)` and `@goto(main.loop)` go to `@anchor(.loop)`
+//! `@anchor(:)` is anonymous, `:+` is the next one after the reference and `:-` the one before it, `:++`, `:--` and so on skip more
+//! Offsets go after the direction characters, `:-+1` is `:-` plus 1 and `:++1` is `:++` plus 1, the `+` is left out after `:+` since it would be another direction character
+//! Anchors made by the assembler for user macros and blocks begin with `%`, which can't be written in source, so they can't clash with anchors from the source
+
+use crate::prelude::*;
+use super::program_skeleton::{AnchorTable, ProgramSkeletonBuildError};
+
+pub const LOCAL_ANCHOR_BEGIN: char = '.';
+pub const ANONYMOUS_ANCHOR: char = ':';
+pub const ANCHOR_OFFSET_BEGIN: char = '+';
+/// Begins the names of anchors made by the assembler, not allowed in source
+pub const GENERATED_ANCHOR_BEGIN: char = '%';
+/// Allowed in macro arguments along with `IDENTIFIER_CHARS` so that anchor references can be parsed
+pub const ANCHOR_REFERENCE_CHARS: [char; 3] = [LOCAL_ANCHOR_BEGIN, ANONYMOUS_ANCHOR, ANCHOR_OFFSET_BEGIN];
+
+/// Where in the program an anchor is defined or referenced, needed to work out which local or anonymous anchor a name means
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AnchorScope {
+	/// Most recent global anchor, empty at the start of the program
+	pub global: String,
+	/// Number of anonymous anchors before this point
+	pub anonymous_before: usize
+}
+
+impl AnchorScope {
+	/// Name that `@anchor(<name>)` is stored as in the anchor table, or None if it is anonymous, and moves the scope past it
+	pub fn define(&mut self, name: &str) -> Result<Option<String>, ProgramSkeletonBuildError> {
+		if name == ANONYMOUS_ANCHOR.to_string() {
+			self.anonymous_before += 1;
+			return Ok(None);
+		}
+		// Local, or already qualified with its global anchor like `main.loop` (from the disassembler), which doesn't change the scope
+		let is_identifier = |part: &str| -> bool {!part.is_empty() && part.chars().all(|c| IDENTIFIER_CHARS.contains(&c))};
+		match name.split_once(LOCAL_ANCHOR_BEGIN) {
+			Some((global, local)) if (global.is_empty() || is_identifier(global.strip_prefix(GENERATED_ANCHOR_BEGIN).unwrap_or(global))) && is_identifier(local) => Ok(Some(match global.is_empty() {
+				true => format!("{}{}{}", self.global, LOCAL_ANCHOR_BEGIN, local),
+				false => name.to_owned()
+			})),
+			None if is_identifier(name) => {
+				self.global = name.to_owned();
+				Ok(Some(name.to_owned()))
+			},
+			_ => Err(ProgramSkeletonBuildError::AnchorNameInvalid(name.to_owned()))
+		}
+	}
+	/// Address of `reference` as used here, `anonymous` is the addresses of all anonymous anchors in order
+	pub fn resolve(&self, reference: &str, anchors: &AnchorTable, anonymous: &[usize]) -> Result<usize, ProgramSkeletonBuildError> {
+		let (target, offset) = self.target(reference)?;
+		let address: usize = match target {
+			AnchorTarget::Anonymous(index) => match anonymous.get(index) {
+				Some(address) => *address,
+				None => {return Err(ProgramSkeletonBuildError::AnonymousAnchorMissing(reference.to_owned()));}
+			},
+			AnchorTarget::Named(name) => match anchors.get(&name) {
+				Some(address) => *address,
+				None => {return Err(ProgramSkeletonBuildError::MacroInvalidAnchor(name));}
+			}
+		};
+		// Done
+		match address + offset {
+			address if address < POWER_16 => Ok(address),
+			_ => Err(ProgramSkeletonBuildError::AnchorReferenceInvalid(reference.to_owned()))
+		}
+	}
+	/// Which anchor `reference` means as used here and the offset added to it, without looking it up
+	pub fn target(&self, reference: &str) -> Result<(AnchorTarget, usize), ProgramSkeletonBuildError> {
+		let invalid = || ProgramSkeletonBuildError::AnchorReferenceInvalid(reference.to_owned());
+		// Split off offset, `:+` and `:-` are made of the same characters so they are counted first
+		let target_len: usize = match reference.strip_prefix(ANONYMOUS_ANCHOR) {
+			Some(direction) => {
+				let direction_char: char = direction.chars().next().ok_or_else(invalid)?;
+				if !['+', '-'].contains(&direction_char) {
+					return Err(invalid());
+				}
+				1 + direction.chars().take_while(|c| *c == direction_char).count()
+			},
+			None => reference.find(ANCHOR_OFFSET_BEGIN).unwrap_or(reference.len())
+		};
+		let (target, offset_raw) = reference.split_at(target_len);
+		let offset_raw_opt: Option<&str> = match target.starts_with(ANONYMOUS_ANCHOR) && target.ends_with(ANCHOR_OFFSET_BEGIN) {
+			true => Some(offset_raw),
+			false => offset_raw.strip_prefix(ANCHOR_OFFSET_BEGIN)
+		};
+		let offset: usize = match offset_raw_opt {
+			_ if offset_raw.is_empty() => 0,
+			Some(offset_raw) => parse_offset(offset_raw).ok_or_else(invalid)? as usize,
+			None => {return Err(invalid());}
+		};
+		let target: AnchorTarget = match target.strip_prefix(ANONYMOUS_ANCHOR) {
+			Some(direction) => {
+				let n: usize = direction.len();
+				let index_opt: Option<usize> = match direction.starts_with('+') {
+					true => Some(self.anonymous_before + n - 1),
+					false => self.anonymous_before.checked_sub(n)
+				};
+				match index_opt {
+					Some(index) => AnchorTarget::Anonymous(index),
+					None => {return Err(ProgramSkeletonBuildError::AnonymousAnchorMissing(reference.to_owned()));}
+				}
+			},
+			None => AnchorTarget::Named(match target.starts_with(LOCAL_ANCHOR_BEGIN) {
+				true => format!("{}{}", self.global, target),
+				false => target.to_owned()
+			})
+		};
+		// Done
+		Ok((target, offset))
+	}
+}
+
+/// Anchor that a reference means
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AnchorTarget {
+	/// Name in the anchor table, local anchors include their global anchor
+	Named(String),
+	/// Index of the anonymous anchor, counting from the start of the program
+	Anonymous(usize)
+}
+
+/// Global part of the names of anchors made by the assembler, like `%if-0` for the first block
+pub fn generated_anchor_name(name: &str, n: usize) -> String {
+	format!("{}{}-{}", GENERATED_ANCHOR_BEGIN, name, n)
+}
+
+/// Whether `reference` has a `+offset`, which means it can be somewhere other than at an anchor
+pub fn has_offset(reference: &str) -> bool {
+	match reference.strip_prefix(ANONYMOUS_ANCHOR) {
+		Some(direction) => direction.chars().skip(1).any(|c| !direction.starts_with(c)),
+		None => reference.contains(ANCHOR_OFFSET_BEGIN)
+	}
+}
+
+/// Hex (`0x10`), binary (`0b...`) or decimal (`0d16` or `16`)
+fn parse_offset(raw: &str) -> Option<u16> {
+	let (digits, radix): (&str, u32) = match raw.get(0..2) {
+		Some("0x") => (&raw[2..], 16),
+		Some("0b") => (&raw[2..], 2),
+		Some("0d") => (&raw[2..], 10),
+		_ => (raw, 10)
+	};
+	u16::from_str_radix(digits, radix).ok()
+}
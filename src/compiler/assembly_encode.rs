@@ -1,7 +1,12 @@
 //! Translating assembly directly to machine code, the last step in compilation
 
+use std::{collections::HashSet, fmt};
+
 use serde::Deserialize;
 
+use crate::prelude::HardwareVersion;
+use super::expression::Expression;
+
 /// For each "unit" of the assembly code, names of opcodes and devices to read/write the bus, etc.
 #[derive(Deserialize, Clone, Debug)]
 pub struct AssemblyWord {
@@ -11,17 +16,14 @@ pub struct AssemblyWord {
 	pub name: String
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AssemblerWordContext {
 	Opcode,
 	AluOpcode,
 	ToBus,
 	FromBus,
-	#[cfg(feature = "version_2")]
 	AfterCall,
-	#[cfg(feature = "version_2")]
 	AfterReturn,
-	#[cfg(feature = "version_2")]
 	GenericAfterOpcode
 }
 
@@ -32,15 +34,56 @@ pub struct AssemblerConfig {
 	pub alu_opcodes: Vec<AssemblyWord>,
 	pub to_bus: Vec<AssemblyWord>,
 	pub from_bus: Vec<AssemblyWord>,
-	#[cfg(feature = "version_2")]
+	/// Version 2 only
+	#[serde(default)]
 	pub after_call: Vec<AssemblyWord>,
-	#[cfg(feature = "version_2")]
+	/// Version 2 only
+	#[serde(default)]
 	pub after_return: Vec<AssemblyWord>,
-	#[cfg(feature = "version_2")]
-	pub generic_after_opcode: Vec<AssemblyWord>
+	/// Version 2 only
+	#[serde(default)]
+	pub generic_after_opcode: Vec<AssemblyWord>,
+	/// Not part of the config file, set by `resources::load_assembler_config()`
+	#[serde(skip)]
+	pub version: HardwareVersion,
+	/// Names for `@ifdef`, from `-D NAME` on the command line
+	#[serde(skip)]
+	pub defines: HashSet<String>,
+	/// Run the peephole optimizer, `-optimize` on the command line
+	#[serde(skip)]
+	pub optimize: bool,
+	/// Make lints errors, `--deny-warnings` on the command line
+	#[serde(skip)]
+	pub deny_warnings: bool,
+	/// Lowercase names that are only in the version 2 config, set by `resources::load_assembler_config()` for version 1 so that errors can say why a word doesn't exist
+	#[serde(skip)]
+	pub version_2_only_words: HashSet<String>
+}
+
+impl fmt::Display for AssemblerWordContext {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", match self {
+			Self::Opcode => "opcode",
+			Self::AluOpcode => "ALU opcode",
+			Self::ToBus => "bus device to write to the bus (TX)",
+			Self::FromBus => "bus device to read from the bus (RX)",
+			Self::AfterCall => "word after `call`",
+			Self::AfterReturn => "word after `return`",
+			Self::GenericAfterOpcode => "word after the opcode"
+		})
+	}
 }
 
 impl AssemblerConfig {
+	/// For `@ifdef`, names given with `-D` and `HARDWARE_V1` or `HARDWARE_V2` for the target hardware, `REPLICATE_STACK_ISSUE` is also defined when the `replicate_stack_issue` feature is enabled
+	pub fn is_defined(&self, name: &str) -> bool {
+		match name {
+			"HARDWARE_V1" => self.version == HardwareVersion::V1,
+			"HARDWARE_V2" => self.version == HardwareVersion::V2,
+			"REPLICATE_STACK_ISSUE" if cfg!(feature = "replicate_stack_issue") => true,
+			_ => self.defines.contains(name)
+		}
+	}
 	pub fn encode_word(&self, word_type: AssemblerWordContext, raw: &str) -> Option<AssemblyWord> {
 		self.encode_generic_word(
 			match word_type {
@@ -48,16 +91,26 @@ impl AssemblerConfig {
 				AssemblerWordContext::AluOpcode => &self.alu_opcodes,
 				AssemblerWordContext::ToBus => &self.to_bus,
 				AssemblerWordContext::FromBus => &self.from_bus,
-				#[cfg(feature = "version_2")]
 				AssemblerWordContext::AfterCall => &self.after_call,
-				#[cfg(feature = "version_2")]
 				AssemblerWordContext::AfterReturn => &self.after_return,
-				#[cfg(feature = "version_2")]
 				AssemblerWordContext::GenericAfterOpcode => &self.generic_after_opcode
 			},
 			raw
 		)
 	}
+	/// Inverse of `Self::encode_word()`, finds the word with a given ID
+	pub fn decode_word(&self, word_type: AssemblerWordContext, id_: u8) -> Option<AssemblyWord> {
+		let words: &Vec<AssemblyWord> = match word_type {
+			AssemblerWordContext::Opcode => &self.opcodes,
+			AssemblerWordContext::AluOpcode => &self.alu_opcodes,
+			AssemblerWordContext::ToBus => &self.to_bus,
+			AssemblerWordContext::FromBus => &self.from_bus,
+			AssemblerWordContext::AfterCall => &self.after_call,
+			AssemblerWordContext::AfterReturn => &self.after_return,
+			AssemblerWordContext::GenericAfterOpcode => &self.generic_after_opcode
+		};
+		words.iter().find(|word| word.id_ == id_).cloned()
+	}
 	fn encode_generic_word(&self, words: &Vec<AssemblyWord>, raw: &str) -> Option<AssemblyWord> {
 		for word_config in words {
 			if word_config.name.to_lowercase() == raw.to_lowercase() {
@@ -93,7 +146,9 @@ pub enum TokenEnum {
 		bit_size: u8
 	},
 	/// Regular assembly word
-	AssemblyWord(String)
+	AssemblyWord(String),
+	/// Constant expression, replaced with a literal when the program skeleton is built
+	Expression(Expression)
 }
 
 impl TokenEnum {
@@ -138,17 +193,18 @@ impl Token {
 }
 
 /// Takes the instruction as a vec of tokens, tries to assemble it into an instruction
-/// This is the only function that is compiled differently for versions 1 and 2
-pub fn assemble_instruction(line: &Vec<Token>, config: &AssemblerConfig) -> Result<u16, (AssemblyEncodeErrorEnum, Option<String>)> {
+/// Version 2 specific encoding is selected by `config.version`
+/// Errors include the index of the token in `line` that couldn't be encoded, None if it's the whole line
+pub fn assemble_instruction(line: &Vec<Token>, config: &AssemblerConfig) -> Result<u16, (AssemblyEncodeErrorEnum, Option<String>, Option<usize>)> {
 	// Opcode
 	let opcode: AssemblyWord = if let TokenEnum::AssemblyWord(word) = &line[0].enum_ {
 		match config.encode_word(AssemblerWordContext::Opcode, &word) {
 			Some(opc) => opc,
-			None => {return Err((AssemblyEncodeErrorEnum::InvalidToken(line[0].raw.clone()), None));}
+			None => {return Err((AssemblyEncodeErrorEnum::InvalidToken(line[0].raw.clone()), None, Some(0)));}
 		}
 	}
 	else {// First token in line is not an assembly word
-		return Err((AssemblyEncodeErrorEnum::InvalidToken(line[0].raw.clone()), None));
+		return Err((AssemblyEncodeErrorEnum::InvalidToken(line[0].raw.clone()), None, Some(0)));
 	};
 	let mut instruction: u16 = (opcode.id_ & 0x0Fu8) as u16;
 	// Anything after it
@@ -159,14 +215,14 @@ pub fn assemble_instruction(line: &Vec<Token>, config: &AssemblerConfig) -> Resu
 				3 => false,// MOVE <source> <destination>
 				4 => true,// MOVE <ALU opcode> <source> <destination>
 				n => {
-					return Err((AssemblyEncodeErrorEnum::IncorrectNumberOfTokensForOpcode{opcode: opcode.clone(), n_tokens_in_line: n}, Some("There must be either 3 or 4 tokens in a `MOVE` line".to_owned())));
+					return Err((AssemblyEncodeErrorEnum::IncorrectNumberOfTokensForOpcode{opcode: opcode.clone(), n_tokens_in_line: n}, Some("There must be either 3 or 4 tokens in a `MOVE` line".to_owned()), None));
 				}
 			};
 			let alu_opcode: u8 = match alu_opcode_included {
 				true => {
 					match config.get_assembly_word(&line[1], AssemblerWordContext::AluOpcode) {
 						Ok(word) => word.id_,
-						Err(err_enum) => {return Err((err_enum, None));}
+						Err(err_enum) => {return Err((err_enum, None, Some(1)));}
 					}
 				},
 				false => {// TODO
@@ -174,21 +230,20 @@ pub fn assemble_instruction(line: &Vec<Token>, config: &AssemblerConfig) -> Resu
 				}
 			};
 			// From and to address
-			let (write_addr_token, read_addr_token): (&Token, &Token) = match alu_opcode_included {
-				true => (&line[2], &line[3]),
-				false => (&line[1], &line[2])
+			let write_addr_i: usize = match alu_opcode_included {
+				true => 2,
+				false => 1
 			};
-			let mut write_addr: u8 = match config.get_assembly_word(write_addr_token, AssemblerWordContext::ToBus) {
+			let mut write_addr: u8 = match config.get_assembly_word(&line[write_addr_i], AssemblerWordContext::ToBus) {
 				Ok(word) => word.id_,
-				Err(err_enum) => {return Err((err_enum, None));}
+				Err(err_enum) => {return Err((err_enum, None, Some(write_addr_i)));}
 			};
-			let mut read_addr: u8 = match config.get_assembly_word(read_addr_token, AssemblerWordContext::FromBus) {
+			let mut read_addr: u8 = match config.get_assembly_word(&line[write_addr_i + 1], AssemblerWordContext::FromBus) {
 				Ok(word) => word.id_,
-				Err(err_enum) => {return Err((err_enum, None));}
+				Err(err_enum) => {return Err((err_enum, None, Some(write_addr_i + 1)));}
 			};
 			// FOR VERSION 2: Check if move opcode needs to be changed for 5-bit bus address compatibility
-			#[cfg(feature = "version_2")]
-			{
+			if config.version == HardwareVersion::V2 {
 				/*
 				8. `MOVE` - MSBs TX=1, RX=0
 				9. `MOVE` - MSBs TX=0, RX=1
@@ -214,7 +269,7 @@ pub fn assemble_instruction(line: &Vec<Token>, config: &AssemblerConfig) -> Resu
 		"write" => {
 			// Check number of tokens
 			if line.len() != 3 {
-				return Err((AssemblyEncodeErrorEnum::IncorrectNumberOfTokensForOpcode{opcode, n_tokens_in_line: line.len()}, None));
+				return Err((AssemblyEncodeErrorEnum::IncorrectNumberOfTokensForOpcode{opcode, n_tokens_in_line: line.len()}, None, None));
 			}
 			// Bits 4 - 11 raw hex value
 			let write_value_token = &line[1];
@@ -223,25 +278,24 @@ pub fn assemble_instruction(line: &Vec<Token>, config: &AssemblerConfig) -> Resu
 					*n
 				}
 				else {
-					return Err((AssemblyEncodeErrorEnum::HexLiteralWrongLength{correct_len_bits: 8, actual_len_bits: *bit_size as u32}, None));
+					return Err((AssemblyEncodeErrorEnum::HexLiteralWrongLength{correct_len_bits: 8, actual_len_bits: *bit_size as u32}, None, Some(1)));
 				}
 			}
 			else {
-				return Err((AssemblyEncodeErrorEnum::LiteralTokenInWrongContext{token: write_value_token.clone()}, None));
+				return Err((AssemblyEncodeErrorEnum::LiteralTokenInWrongContext{token: write_value_token.clone()}, None, Some(1)));
 			};
 			// Bus read addr
 			let read_addr_token = &line[2];
 			let mut read_addr: u8 = match config.get_assembly_word(read_addr_token, AssemblerWordContext::FromBus) {
 				Ok(word) => word.id_,
-				Err(err_enum) => {return Err((err_enum, None));}
+				Err(err_enum) => {return Err((err_enum, None, Some(2)));}
 			};
 			// Check if writing to the stack offset
 			if read_addr == 13 {
 				write_value = 0xFF - write_value;
 			}
 			// FOR VERSION 2: Check if move opcode needs to be changed for 5-bit bus address compatibility
-			#[cfg(feature = "version_2")]
-			{
+			if config.version == HardwareVersion::V2 {
 				/*
 				8. `MOVE` - MSBs TX=1, RX=0
 				9. `MOVE` - MSBs TX=0, RX=1
@@ -256,9 +310,7 @@ pub fn assemble_instruction(line: &Vec<Token>, config: &AssemblerConfig) -> Resu
 			// Add to instruction
 			instruction |= ((write_value as u16) << 4) | ((read_addr as u16) << 12);
 		},
-		#[allow(unused)]// In case of not version 2 where `other` would not be used
 		other => {// For call, return, config-int
-			#[cfg(feature = "version_2")]
 			if let Some(word_context) = match other {
 				"call" => Some(AssemblerWordContext::AfterCall),
 				"return" => Some(AssemblerWordContext::AfterReturn),
@@ -267,12 +319,12 @@ pub fn assemble_instruction(line: &Vec<Token>, config: &AssemblerConfig) -> Resu
 			} {
 				// Check number of tokens
 				if !(line.len() == 1 || line.len() == 2) {
-					return Err((AssemblyEncodeErrorEnum::IncorrectNumberOfTokensForOpcode{opcode, n_tokens_in_line: line.len()}, None));
+					return Err((AssemblyEncodeErrorEnum::IncorrectNumberOfTokensForOpcode{opcode, n_tokens_in_line: line.len()}, None, None));
 				}
 				if line.len() == 2 {
 					let after_opcode_flag: u8 = match config.get_assembly_word(&line[1], word_context) {
 						Ok(word) => word.id_,
-						Err(err_enum) => {return Err((err_enum, None));}
+						Err(err_enum) => {return Err((err_enum, None, Some(1)));}
 					};
 					instruction |= (after_opcode_flag as u16 & 0xF) << 4;
 				}
@@ -283,6 +335,80 @@ pub fn assemble_instruction(line: &Vec<Token>, config: &AssemblerConfig) -> Resu
 	Ok(instruction)
 }
 
+/// Bit fields of an instruction, the inverse of `assemble_instruction()` before any names are looked up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InstructionFields {
+	/// Bits 0 - 3 as stored
+	pub raw_opcode: u8,
+	/// Opcode as executed, for version 2 opcodes 8 - 10 are MOVE (0) and 11 is WRITE (1), None if invalid for the hardware version
+	pub opcode: Option<u8>,
+	/// MOVE only
+	pub alu_opcode: Option<u8>,
+	/// MOVE only, device that writes to the bus (TX), 5 bits for version 2
+	pub to_bus: Option<u8>,
+	/// MOVE and WRITE, device that reads from the bus (RX), 5 bits for version 2
+	pub from_bus: Option<u8>,
+	/// WRITE only, value as encoded, so still inverted if written to `set-stack-offset`
+	pub literal: Option<u8>,
+	/// Version 2 CALL, RETURN and CONFIG-INT, bits 4 - 7
+	pub flag: Option<u8>
+}
+
+impl InstructionFields {
+	/// ```
+	/// use stack_machine::{prelude::HardwareVersion, compiler::assembly_encode::InstructionFields};
+	/// // write 0x42 int-goto-b
+	/// let fields = InstructionFields::decode(0x142B, HardwareVersion::V2);
+	/// assert_eq!((fields.opcode, fields.literal, fields.from_bus), (Some(1), Some(0x42), Some(17)));
+	/// // Opcode 11 doesn't exist for version 1
+	/// assert_eq!(InstructionFields::decode(0x142B, HardwareVersion::V1).opcode, None);
+	/// ```
+	pub fn decode(instruction: u16, version: HardwareVersion) -> Self {
+		let raw_opcode: u8 = (instruction & 0xF) as u8;
+		let bits_4_to_7: u8 = ((instruction >> 4) & 0xF) as u8;
+		let bits_4_to_11: u8 = ((instruction >> 4) & 0xFF) as u8;
+		let tx: u8 = ((instruction >> 8) & 0xF) as u8;
+		let rx: u8 = ((instruction >> 12) & 0xF) as u8;
+		let mut out = Self {
+			raw_opcode,
+			opcode: None,
+			alu_opcode: None,
+			to_bus: None,
+			from_bus: None,
+			literal: None,
+			flag: None
+		};
+		// Same as in the emulator
+		let (opcode, tx_msb, rx_msb): (Option<u8>, u8, u8) = match (version, raw_opcode) {
+			(_, 0..=6) => (Some(raw_opcode), 0, 0),
+			(HardwareVersion::V2, 7) => (Some(7), 0, 0),
+			(HardwareVersion::V2, 8) => (Some(0), 0x10, 0),
+			(HardwareVersion::V2, 9) => (Some(0), 0, 0x10),
+			(HardwareVersion::V2, 10) => (Some(0), 0x10, 0x10),
+			(HardwareVersion::V2, 11) => (Some(1), 0, 0x10),
+			_ => (None, 0, 0)
+		};
+		out.opcode = opcode;
+		match opcode {
+			Some(0) => {// MOVE
+				out.alu_opcode = Some(bits_4_to_7);
+				out.to_bus = Some(tx | tx_msb);
+				out.from_bus = Some(rx | rx_msb);
+			},
+			Some(1) => {// WRITE
+				out.literal = Some(bits_4_to_11);
+				out.from_bus = Some(rx | rx_msb);
+			},
+			Some(5) | Some(6) | Some(7) if version == HardwareVersion::V2 => {// CALL, RETURN, CONFIG-INT
+				out.flag = Some(bits_4_to_7);
+			},
+			_ => {}
+		}
+		// Done
+		out
+	}
+}
+
 #[derive(Debug)]
 pub enum AssemblyEncodeErrorEnum {
 	HexLiteralInvalid(hex::FromHexError),
@@ -303,4 +429,20 @@ pub enum AssemblyEncodeErrorEnum {
 		token: Token
 	},
     UnspecifiedAluOpcode
-}
\ No newline at end of file
+}
+
+impl fmt::Display for AssemblyEncodeErrorEnum {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::HexLiteralInvalid(e) => write!(f, "Invalid hex literal, {}", e),
+			Self::HexLiteralWrongLength{correct_len_bits, actual_len_bits} => write!(f, "Literal is {} bits, must be {} bits", actual_len_bits, correct_len_bits),
+			Self::InvalidToken(raw) => write!(f, "Invalid token \"{}\"", raw),
+			Self::IncorrectNumberOfTokensForOpcode{opcode, n_tokens_in_line} => write!(f, "Wrong number of tokens ({}) for opcode `{}`", n_tokens_in_line, opcode.name),
+			Self::TokenWordInWrongContext{token_raw, correct_context} => write!(f, "\"{}\" is not a valid {}", token_raw, correct_context),
+			Self::LiteralTokenInWrongContext{token} => write!(f, "Literal \"{}\" is not allowed here", token.raw),
+			Self::UnspecifiedAluOpcode => write!(f, "ALU opcode not specified")
+		}
+	}
+}
+
+impl std::error::Error for AssemblyEncodeErrorEnum {}
\ No newline at end of file
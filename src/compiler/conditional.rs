@@ -0,0 +1,50 @@
+//! Conditional assembly, `@ifdef(NAME)`/`@ifndef(NAME)` with `@else` and `@endif`
+//! Names are defined with `-D NAME` on the command line (`AssemblerConfig::defines`), or by the assembler for the target hardware, see `AssemblerConfig::is_defined()`
+
+use crate::prelude::*;
+use super::{macros::{Macro, MacroEnum}, syntax_tree::{SyntaxTreeNode, SyntaxTreeNodeType}};
+
+/// Keeps only the nodes in branches that are taken, the `@ifdef`, `@ifndef` and their `@else` and `@endif` are removed
+/// `@if` blocks use the same `@else` and `@endif` so they are tracked to know which ones belong to them, and left in for `program_skeleton::expand_blocks()`
+/// Nesting has already been checked by the parser (see `syntax_tree::check_block_nesting()`)
+pub fn select_conditional(nodes: Vec<SyntaxTreeNode>, config: &AssemblerConfig) -> Vec<SyntaxTreeNode> {
+	let mut out = Vec::<SyntaxTreeNode>::new();
+	// For each open block, None for `@if` or Some(if the current branch is taken)
+	let mut open = Vec::<Option<bool>>::new();
+	for node in nodes {
+		let taken: bool = open.iter().all(|branch_taken_opt| *branch_taken_opt != Some(false));
+		let macro_opt: Option<&Macro> = match &node.type_ {
+			SyntaxTreeNodeType::Macro(macro_) => Some(macro_),
+			_ => None
+		};
+		match macro_opt.map(|macro_| &macro_.type_) {
+			Some(type_ @ (MacroEnum::IfDef | MacroEnum::IfNDef)) => {
+				let defined: bool = config.is_defined(&macro_opt.unwrap().args[0].to_string());
+				open.push(Some(defined == (*type_ == MacroEnum::IfDef)));
+			},
+			Some(MacroEnum::If) => {
+				open.push(None);
+				if taken {
+					out.push(node);
+				}
+			},
+			Some(MacroEnum::Else) => match open.last_mut() {
+				Some(Some(branch_taken)) => *branch_taken = !*branch_taken,
+				_ => if taken {
+					out.push(node);
+				}
+			},
+			Some(MacroEnum::EndIf) => match open.pop() {
+				Some(Some(_)) => {},
+				_ => if taken {
+					out.push(node);
+				}
+			},
+			_ => if taken {
+				out.push(node);
+			}
+		}
+	}
+	// Done
+	out
+}
@@ -0,0 +1,196 @@
+//! Turns machine code back into assembly that can be assembled again into the same machine code
+//! Works from a `.dec_list`, a dumped flash image or the emulator's `prog_mem`
+
+use std::fmt::{self, Write};
+
+use crate::prelude::*;
+use super::{anchor, program_skeleton::AnchorTable, assembly_encode::{AssemblerWordContext, InstructionFields}};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DisassemblyErrorEnum {
+	InvalidOpcode(u8),
+	/// (context, id)
+	UnknownWord(AssemblerWordContext, u8),
+	/// The assembler always leaves these bits as 0
+	UnusedBitsSet(u16)
+}
+
+impl fmt::Display for DisassemblyErrorEnum {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::InvalidOpcode(opcode) => write!(f, "Invalid opcode {:#03X}", opcode),
+			Self::UnknownWord(context, id_) => write!(f, "No {} with id {}", context, id_),
+			Self::UnusedBitsSet(bits) => write!(f, "Unused bits set ({:#06X})", bits)
+		}
+	}
+}
+
+impl std::error::Error for DisassemblyErrorEnum {}
+
+/// Disassembles a single instruction, for example `0x1421` -> `write 0x42 stack-push`
+/// ```
+/// use stack_machine::{prelude::*, compiler::disassemble::disassemble_instruction};
+/// let config = resources::load_assembler_config(HardwareVersion::V2).unwrap();
+/// assert_eq!(disassemble_instruction(0x1421, &config).unwrap(), "write 0x42 stack-push");
+/// // `set-stack-offset` values are inverted by the assembler
+/// assert_eq!(disassemble_instruction(0xDFC1, &config).unwrap(), "write 0x03 set-stack-offset");
+/// ```
+pub fn disassemble_instruction(instruction: u16, config: &AssemblerConfig) -> Result<String, DisassemblyErrorEnum> {
+	let fields = InstructionFields::decode(instruction, config.version);
+	let opcode: u8 = match fields.opcode {
+		Some(opcode) => opcode,
+		None => {return Err(DisassemblyErrorEnum::InvalidOpcode(fields.raw_opcode));}
+	};
+	let opcode_name: String = name(config, AssemblerWordContext::Opcode, opcode)?;
+	Ok(match opcode {
+		0 => {// MOVE
+			let to_bus: String = name(config, AssemblerWordContext::ToBus, fields.to_bus.unwrap())?;
+			let from_bus: String = name(config, AssemblerWordContext::FromBus, fields.from_bus.unwrap())?;
+			match fields.alu_opcode.unwrap() {
+				0 => format!("{} {} {}", opcode_name, to_bus, from_bus),// Same as leaving out the ALU opcode
+				alu_opcode => format!("{} {} {} {}", opcode_name, name(config, AssemblerWordContext::AluOpcode, alu_opcode)?, to_bus, from_bus)
+			}
+		},
+		1 => {// WRITE
+			let from_bus_id: u8 = fields.from_bus.unwrap();
+			let mut value: u8 = fields.literal.unwrap();
+			// Undo what `assemble_instruction()` does for `set-stack-offset`
+			if from_bus_id == 13 {
+				value = 0xFF - value;
+			}
+			format!("{} {:#04X} {}", opcode_name, value, name(config, AssemblerWordContext::FromBus, from_bus_id)?)
+		},
+		_ => {
+			match fields.flag {
+				Some(flag) => {// Version 2 CALL, RETURN and CONFIG-INT
+					let context: AssemblerWordContext = match opcode {
+						5 => AssemblerWordContext::AfterCall,
+						6 => AssemblerWordContext::AfterReturn,
+						_ => AssemblerWordContext::GenericAfterOpcode
+					};
+					match config.decode_word(context, flag) {
+						Some(word) => format!("{} {}", opcode_name, word.name.to_lowercase()),
+						None => match flag {
+							0 => opcode_name,
+							_ => {return Err(DisassemblyErrorEnum::UnknownWord(context, flag));}
+						}
+					}
+				},
+				None => {
+					if instruction & 0xFFF0 != 0 {
+						return Err(DisassemblyErrorEnum::UnusedBitsSet(instruction & 0xFFF0));
+					}
+					opcode_name
+				}
+			}
+		}
+	})
+}
+
+/// Disassembles a whole program, one instruction per line
+/// If `anchors_opt` is given, `@anchor()`s are put back in and GOTO/CALL sequences that jump to an anchor are turned back into `@goto()`, `@goto_if()`, `@call()` and `@set_interrupt()`
+/// Invalid words can't be assembled so they are written as comments, the output will only assemble to the same program if there are none
+pub fn disassemble(program: &[u16], config: &AssemblerConfig, anchors_opt: Option<&AnchorTable>) -> String {
+	// Address -> anchor names, sorted so the output doesn't depend on HashMap order
+	// Anchors made by the assembler can't be written in source, the jumps to them are left as instructions
+	let mut anchors_by_address: Vec<(usize, String)> = match anchors_opt {
+		Some(anchors) => anchors.iter().filter(|(name, _)| !name.starts_with(anchor::GENERATED_ANCHOR_BEGIN)).map(|(name, address)| (*address, name.clone())).collect(),
+		None => Vec::new()
+	};
+	anchors_by_address.sort();
+	let anchors_at = |address: usize| -> Vec<&String> {
+		anchors_by_address.iter().filter(|(anchor_address, _)| *anchor_address == address).map(|(_, name)| name).collect()
+	};
+	let mut out = String::new();
+	let mut address: usize = 0;
+	while address < program.len() {
+		for name in anchors_at(address) {
+			writeln!(out, "@anchor({});", name).unwrap();
+		}
+		// Macro, can't have an anchor in the middle of it
+		if anchors_opt.is_some() && anchors_at(address + 1).is_empty() && anchors_at(address + 2).is_empty() {
+			if let Some((macro_text, n_words)) = match_address_macro(&program[address..], config, &anchors_by_address) {
+				writeln!(out, "{};", macro_text).unwrap();
+				address += n_words;
+				continue;
+			}
+		}
+		match disassemble_instruction(program[address], config) {
+			Ok(text) => {writeln!(out, "{};", text).unwrap();},
+			Err(e) => {writeln!(out, "# {:#06X}: Could not disassemble {:#06X}, {}", address, program[address], e).unwrap();}
+		}
+		address += 1;
+	}
+	// Anchors at the very end
+	for name in anchors_at(program.len()) {
+		writeln!(out, "@anchor({});", name).unwrap();
+	}
+	// Done
+	out
+}
+
+/// Checks if the beginning of `program` is the expansion of an anchor address macro (see `program_skeleton::load_goto_instructions()`), returns (macro text, number of words)
+fn match_address_macro(program: &[u16], config: &AssemblerConfig, anchors_by_address: &[(usize, String)]) -> Option<(String, usize)> {
+	if program.len() < 2 {
+		return None;
+	}
+	let first = InstructionFields::decode(program[0], config.version);
+	let second = InstructionFields::decode(program[1], config.version);
+	if first.opcode != Some(1) || second.opcode != Some(1) {
+		return None;
+	}
+	// Address as written into the latches is 1 less than the anchor
+	let address: usize = ((first.literal? as u16) | ((second.literal? as u16) << 8)).wrapping_add(1) as usize;
+	let anchor_name: &String = &anchors_by_address.iter().find(|(anchor_address, _)| *anchor_address == address)?.1;
+	match (first.from_bus?, second.from_bus?) {
+		(4, 5) => {// GOTO-A, GOTO-B
+			let third = InstructionFields::decode(*program.get(2)?, config.version);
+			// Must be exactly what the macro creates, `call int` is not
+			if program[2] & 0xFFF0 != 0 {
+				return None;
+			}
+			let macro_name: &str = match third.opcode? {
+				2 => "goto",
+				3 => "goto_if",
+				5 => "call",
+				_ => {return None;}
+			};
+			Some((format!("{}{}({})", MACRO_BEGIN, macro_name, anchor_name), 3))
+		},
+		(16, 17) if config.version == HardwareVersion::V2 => Some((format!("{}set_interrupt({})", MACRO_BEGIN, anchor_name), 2)),// INT-GOTO-A, INT-GOTO-B
+		_ => None
+	}
+}
+
+/// Reads the `# Anchors` table at the end of a listing (see `listing::listing()`), or any lines of `<address> <name>`
+pub fn parse_symbol_table(text: &str) -> Result<AnchorTable, String> {
+	let table_text: &str = match text.split_once("# Anchors") {
+		Some((_, table)) => table,
+		None => text
+	};
+	let mut anchors = AnchorTable::new();
+	for line in table_text.lines() {
+		let words: Vec<&str> = line.split_whitespace().collect();
+		if words.is_empty() {
+			continue;
+		}
+		if words.len() != 2 {
+			return Err(format!("Invalid symbol table line \"{}\", expected \"<address> <name>\"", line));
+		}
+		let address: usize = match words[0].strip_prefix("0x").or(words[0].strip_prefix("0X")) {
+			Some(hex_raw) => to_string_err(usize::from_str_radix(hex_raw, 16))?,
+			None => to_string_err(words[0].parse::<usize>())?
+		};
+		anchors.insert(words[1].to_owned(), address);
+	}
+	// Done
+	Ok(anchors)
+}
+
+/// Names are lowercase like in the assembly sources, the config has some in uppercase but the assembler doesn't care
+fn name(config: &AssemblerConfig, context: AssemblerWordContext, id_: u8) -> Result<String, DisassemblyErrorEnum> {
+	match config.decode_word(context, id_) {
+		Some(word) => Ok(word.name.to_lowercase()),
+		None => Err(DisassemblyErrorEnum::UnknownWord(context, id_))
+	}
+}
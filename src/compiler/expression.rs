@@ -0,0 +1,277 @@
+//! Constant expressions, used in place of a literal such as `write (lo(table) + 3) alu-a;`
+//! Operators from lowest to highest precedence: `|`, `&`, `<<` `>>`, `+` `-`, `*`
+//! Values are numbers (`0x1F`, `0b101`, `0d200`, `300`), characters (`'A'`) and names of `@const`s, `@var`s or anchors (local ones like `.loop` too), `lo()` and `hi()` are bits 0 - 7 and 8 - 15
+//! `-` is allowed in names, so it needs spaces around it when it comes after a name, `(end - 1)` not `(end-1)`
+//! Outside of parenthesis there can't be any spaces because spaces separate instruction tokens
+
+use std::fmt;
+
+use crate::prelude::*;
+use super::{anchor, program_skeleton::ProgramSkeletonBuildError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+	Or,
+	And,
+	ShiftLeft,
+	ShiftRight,
+	Add,
+	Subtract,
+	Multiply
+}
+
+impl Operator {
+	/// Higher is done first
+	fn precedence(&self) -> u8 {
+		match self {
+			Self::Or => 1,
+			Self::And => 2,
+			Self::ShiftLeft | Self::ShiftRight => 3,
+			Self::Add | Self::Subtract => 4,
+			Self::Multiply => 5
+		}
+	}
+	fn symbol(&self) -> &'static str {
+		match self {
+			Self::Or => "|",
+			Self::And => "&",
+			Self::ShiftLeft => "<<",
+			Self::ShiftRight => ">>",
+			Self::Add => "+",
+			Self::Subtract => "-",
+			Self::Multiply => "*"
+		}
+	}
+	/// Operator at `source[i]` and how many characters it is
+	fn parse(source: &[char], i: usize) -> Option<(Self, usize)> {
+		Some(match (source.get(i)?, source.get(i + 1)) {
+			('<', Some('<')) => (Self::ShiftLeft, 2),
+			('>', Some('>')) => (Self::ShiftRight, 2),
+			('|', _) => (Self::Or, 1),
+			('&', _) => (Self::And, 1),
+			('+', _) => (Self::Add, 1),
+			('-', _) => (Self::Subtract, 1),
+			('*', _) => (Self::Multiply, 1),
+			_ => {return None;}
+		})
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expression {
+	Number(i64),
+	Char(char),
+	/// `@const`, `@var` or anchor
+	Name(String),
+	/// Bits 0 - 7
+	Lo(Box<Self>),
+	/// Bits 8 - 15
+	Hi(Box<Self>),
+	Negate(Box<Self>),
+	Binary(Box<Self>, Operator, Box<Self>)
+}
+
+impl Expression {
+	/// Parses an expression starting at `start`, returns it and where it ends (exclusive)
+	pub fn parse(source: &Vec<char>, start: usize) -> Result<(Self, usize), ParseError> {
+		let mut parser = ExpressionParser {
+			source,
+			i: start,
+			depth: 0
+		};
+		let expression = parser.parse_binary(0)?;
+		Ok((expression, parser.i))
+	}
+	/// Parses all of `raw`, used for macro arguments
+	pub fn parse_str(raw: &str) -> Option<Self> {
+		let source: Vec<char> = raw.chars().collect();
+		match Self::parse(&source, 0) {
+			Ok((expression, end)) if end == source.len() => Some(expression),
+			_ => None
+		}
+	}
+	/// `resolve` gives the value of a name, or None if it isn't defined
+	pub fn evaluate(&self, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<i64, ProgramSkeletonBuildError> {
+		Ok(match self {
+			Self::Number(n) => *n,
+			Self::Char(char_) => *char_ as i64,
+			Self::Name(name) => match resolve(name) {
+				Some(value) => value,
+				None => {return Err(ProgramSkeletonBuildError::SymbolUndefined(name.clone()));}
+			},
+			Self::Lo(inner) => inner.evaluate(resolve)? & 0xFF,
+			Self::Hi(inner) => (inner.evaluate(resolve)? >> 8) & 0xFF,
+			Self::Negate(inner) => match inner.evaluate(resolve)?.checked_neg() {
+				Some(result) => result,
+				None => {return Err(ProgramSkeletonBuildError::ExpressionOverflow(self.to_string()));}
+			},
+			Self::Binary(a, operator, b) => {
+				let (a, b) = (a.evaluate(resolve)?, b.evaluate(resolve)?);
+				let result_opt: Option<i64> = match operator {
+					Operator::Or => Some(a | b),
+					Operator::And => Some(a & b),
+					Operator::ShiftLeft => u32::try_from(b).ok().and_then(|b| a.checked_shl(b)),
+					Operator::ShiftRight => u32::try_from(b).ok().and_then(|b| a.checked_shr(b)),
+					Operator::Add => a.checked_add(b),
+					Operator::Subtract => a.checked_sub(b),
+					Operator::Multiply => a.checked_mul(b)
+				};
+				match result_opt {
+					Some(result) => result,
+					None => {return Err(ProgramSkeletonBuildError::ExpressionOverflow(self.to_string()));}
+				}
+			}
+		})
+	}
+	/// Evaluates and checks that it fits in an 8-bit literal, negative numbers down to -128 are allowed as two's complement
+	pub fn evaluate_u8(&self, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<u8, ProgramSkeletonBuildError> {
+		let value: i64 = self.evaluate(resolve)?;
+		match value {
+			-128..=255 => Ok(value as u8),
+			_ => Err(ProgramSkeletonBuildError::LiteralOutOfRange{expression: self.to_string(), value})
+		}
+	}
+	/// Same expression with every name changed by `f`, for user macro arguments
+	pub fn map_names(&self, f: &dyn Fn(&str) -> Self) -> Self {
+		match self {
+			Self::Name(name) => f(name),
+			Self::Lo(inner) => Self::Lo(Box::new(inner.map_names(f))),
+			Self::Hi(inner) => Self::Hi(Box::new(inner.map_names(f))),
+			Self::Negate(inner) => Self::Negate(Box::new(inner.map_names(f))),
+			Self::Binary(a, operator, b) => Self::Binary(Box::new(a.map_names(f)), *operator, Box::new(b.map_names(f))),
+			other => other.clone()
+		}
+	}
+}
+
+/// How it would be written in the source, binary operations are always in parenthesis
+impl fmt::Display for Expression {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Number(n) => write!(f, "{}", n),
+			Self::Char(char_) => write!(f, "{:?}", char_),
+			Self::Name(name) => write!(f, "{}", name),
+			Self::Lo(inner) => write!(f, "lo({})", inner),
+			Self::Hi(inner) => write!(f, "hi({})", inner),
+			Self::Negate(inner) => write!(f, "-{}", inner),
+			Self::Binary(a, operator, b) => write!(f, "({} {} {})", a, operator.symbol(), b)
+		}
+	}
+}
+
+struct ExpressionParser<'a> {
+	source: &'a Vec<char>,
+	i: usize,
+	/// Number of open parenthesis, whitespace is only allowed inside them
+	depth: usize
+}
+
+impl ExpressionParser<'_> {
+	/// Precedence climbing, parses operators with at least `min_precedence`
+	fn parse_binary(&mut self, min_precedence: u8) -> Result<Expression, ParseError> {
+		let mut left = self.parse_primary()?;
+		loop {
+			self.skip_whitespace();
+			let (operator, operator_len) = match Operator::parse(self.source, self.i) {
+				Some((operator, operator_len)) if operator.precedence() >= min_precedence => (operator, operator_len),
+				_ => break
+			};
+			self.i += operator_len;
+			self.skip_whitespace();
+			// Left associative
+			let right = self.parse_binary(operator.precedence() + 1)?;
+			left = Expression::Binary(Box::new(left), operator, Box::new(right));
+		}
+		Ok(left)
+	}
+	fn parse_primary(&mut self) -> Result<Expression, ParseError> {
+		let start: usize = self.i;
+		let char_: char = match self.source.get(self.i) {
+			Some(char_) => *char_,
+			None => {return Err(ParseError::new(start, start, ParseErrorType::UnfinishedExpression, None));}
+		};
+		match char_ {
+			'(' => {
+				self.i += 1;
+				self.depth += 1;
+				self.skip_whitespace();
+				let inner = self.parse_binary(0)?;
+				self.expect_closing_parenthesis()?;
+				self.depth -= 1;
+				Ok(inner)
+			},
+			'\'' => {
+				let (mut value, mut end): (Option<char>, usize) = (self.source.get(start + 1).copied(), start + 2);
+				if value == Some('\\') {
+					value = self.source.get(start + 2).copied().and_then(escape_substitution);
+					end += 1;
+				}
+				match (value, self.source.get(end)) {
+					(Some(value), Some('\'')) => {
+						self.i = end + 1;
+						Ok(Expression::Char(value))
+					},
+					_ => Err(ParseError::new(start, end.min(self.source.len()), ParseErrorType::InvalidCharacterLiteral, None))
+				}
+			},
+			'-' => {
+				self.i += 1;
+				Ok(Expression::Negate(Box::new(self.parse_primary()?)))
+			},
+			'0'..='9' => {
+				// Only alphanumeric so that `5-1` is 5 - 1
+				let (raw, end) = parse_until_false(self.source, start, |c: &char| -> bool {c.is_ascii_alphanumeric()}, None, true)?;
+				self.i = end;
+				let (digits, radix, error_type): (&str, u32, ParseErrorType) = match raw.get(0..2) {
+					Some("0x") => (&raw[2..], 16, ParseErrorType::HexParseError),
+					Some("0b") => (&raw[2..], 2, ParseErrorType::BinaryParseError),
+					Some("0d") => (&raw[2..], 10, ParseErrorType::DecimalParseError),
+					_ => (&raw, 10, ParseErrorType::DecimalParseError)
+				};
+				match i64::from_str_radix(digits, radix) {
+					Ok(n) => Ok(Expression::Number(n)),
+					Err(e) => Err(ParseError::new(start, end, error_type, Some(e.to_string())))
+				}
+			},
+			char_ if IDENTIFIER_CHARS.contains(&char_) || char_ == anchor::LOCAL_ANCHOR_BEGIN => {
+				// Local and qualified anchors (`.loop`, `main.loop`) too
+				let (name, end) = parse_until_false(self.source, start, |c: &char| -> bool {IDENTIFIER_CHARS.contains(c) || *c == anchor::LOCAL_ANCHOR_BEGIN}, None, true)?;
+				self.i = end;
+				if self.source.get(end) != Some(&'(') {
+					return Ok(Expression::Name(name));
+				}
+				// Function
+				self.i += 1;
+				self.depth += 1;
+				self.skip_whitespace();
+				let inner = Box::new(self.parse_binary(0)?);
+				self.expect_closing_parenthesis()?;
+				self.depth -= 1;
+				match name.as_str() {
+					"lo" => Ok(Expression::Lo(inner)),
+					"hi" => Ok(Expression::Hi(inner)),
+					_ => Err(ParseError::new(start, end, ParseErrorType::InvalidExpressionFunction(name), None))
+				}
+			},
+			other => Err(ParseError::new(start, start+1, ParseErrorType::InvalidCharacterInExpression(other), None))
+		}
+	}
+	fn expect_closing_parenthesis(&mut self) -> Result<(), ParseError> {
+		self.skip_whitespace();
+		match self.source.get(self.i) {
+			Some(')') => {
+				self.i += 1;
+				Ok(())
+			},
+			Some(other) => Err(ParseError::new(self.i, self.i+1, ParseErrorType::InvalidCharacterInExpression(*other), Some("Expected \")\"".to_string()))),
+			None => Err(ParseError::new(self.i, self.i, ParseErrorType::UnfinishedExpression, None))
+		}
+	}
+	fn skip_whitespace(&mut self) {
+		if self.depth > 0 {
+			while self.source.get(self.i).is_some_and(|c| WHITESPACE_CHARS.contains(c)) {
+				self.i += 1;
+			}
+		}
+	}
+}
@@ -0,0 +1,205 @@
+//! Source formatter (`-fmt`), prints the syntax tree back out in one style
+//! * One instruction or macro per line, each ending with ";"
+//! * Assembly words spelled like they are in `AssemblerConfig`, literals and expressions are left as they are
+//! * Code after an `@anchor` is indented one tab, and so is everything inside `@if`, `@while`, `@ifdef`, `@ifndef` and `@macro` blocks
+//! * Comments are kept exactly, trailing comments on lines next to each other are lined up and comments on their own line are indented like the code after them
+//! * Runs of blank lines become one blank line
+
+use crate::prelude::*;
+use super::{CompilerError, CompilerErrorEnum, assembly_encode::{AssemblerWordContext, Token, TokenEnum}, macros::MacroEnum, syntax_tree::{SyntaxTreeNode, SyntaxTreeNodeType}};
+
+/// Width of a tab when lining up trailing comments
+const TAB_WIDTH: usize = 4;
+
+/// Line of output before trailing comments are lined up
+struct Line {
+	indent: usize,
+	/// Instruction, macro or comment on its own line, empty for a blank line, `@data` values can continue over more than one line
+	code: String,
+	/// Text after the "#"
+	comment_opt: Option<String>
+}
+
+impl Line {
+	/// Width of the last line of the code, with tabs `TAB_WIDTH` wide
+	fn width(&self) -> usize {
+		match self.code.rsplit_once('\n') {
+			Some((_, last)) => (self.indent + 1) * TAB_WIDTH + last.chars().count(),
+			None => self.indent * TAB_WIDTH + self.code.chars().count()
+		}
+	}
+}
+
+/// Open block, so the indentation can go back to what it was at the end
+struct Block {
+	/// Indentation of the block macro
+	indent: usize,
+	/// The block is inside code after an anchor
+	in_anchor: bool
+}
+
+/// Formats a whole source file, returns parse errors instead if it doesn't parse since the formatted version could lose something
+pub fn format_source(source_str: &str, source_name: Option<&str>, config: &AssemblerConfig) -> Result<String, Vec<CompilerError>> {
+	let source: Vec<char> = source_str.chars().collect();
+	let (syntax_tree, parse_errors) = SyntaxTreeNode::build_tree_recovering(&source);
+	if !parse_errors.is_empty() {
+		return Err(parse_errors.into_iter().map(|error| CompilerError::from_source_string_index(&source, error.begin, error.end, source_name, None, CompilerErrorEnum::Parse(error))).collect());
+	}
+	let mut lines = Vec::<Line>::new();
+	// Comments and blank lines waiting for the indentation of the next code, (comment text or None for a blank line)
+	let mut pending = Vec::<Option<String>>::new();
+	let mut blocks = Vec::<Block>::new();
+	let mut base: usize = 0;
+	let mut in_anchor: bool = false;
+	// Index in `source` of the end of the last node
+	let mut last_end_opt: Option<usize> = None;
+	for node in &syntax_tree.children {
+		// Macro and comment nodes begin after the "@" or "#"
+		let begin: usize = match &node.type_ {
+			SyntaxTreeNodeType::Instruction => node.begin,
+			_ => node.begin - 1
+		};
+		let newlines: usize = match last_end_opt {
+			Some(last_end) => source[last_end..begin].iter().filter(|c| **c == '\n').count(),
+			None => 0
+		};
+		last_end_opt = Some(node.end);
+		if newlines >= 2 && !(lines.is_empty() && pending.is_empty()) {
+			pending.push(None);
+		}
+		let code: String = match &node.type_ {
+			SyntaxTreeNodeType::Comment => {
+				let text: String = source[node.begin..node.end].iter().collect::<String>().trim_end_matches('\r').to_owned();
+				match (newlines, pending.is_empty(), lines.last_mut()) {
+					// Trailing comment
+					(0, true, Some(line)) if !line.code.is_empty() && line.comment_opt.is_none() => line.comment_opt = Some(text),
+					_ => pending.push(Some(text))
+				}
+				continue;
+			},
+			SyntaxTreeNodeType::Instruction => format_instruction(node, config),
+			SyntaxTreeNodeType::Macro(macro_) => {
+				let text: String = source[begin..node.end].iter().collect();
+				let code: String = text.split('\n').map(|line| line.trim()).collect::<Vec<&str>>().join("\n");
+				// Indentation of this macro and of what comes after it
+				let indent: usize = match &macro_.type_ {
+					MacroEnum::Anchor => {
+						in_anchor = true;
+						base
+					},
+					MacroEnum::If | MacroEnum::While | MacroEnum::IfDef | MacroEnum::IfNDef | MacroEnum::Define(_) => {
+						let indent: usize = base + in_anchor as usize;
+						blocks.push(Block{indent, in_anchor});
+						base = indent + 1;
+						in_anchor = false;
+						indent
+					},
+					MacroEnum::Else => {
+						in_anchor = false;
+						blocks.last().map(|block| block.indent).unwrap_or(0)
+					},
+					MacroEnum::EndIf | MacroEnum::EndWhile | MacroEnum::EndMacro => match blocks.pop() {
+						Some(block) => {
+							base = block.indent - block.in_anchor as usize;
+							in_anchor = block.in_anchor;
+							block.indent
+						},
+						None => base + in_anchor as usize
+					},
+					_ => base + in_anchor as usize
+				};
+				flush_pending(&mut lines, &mut pending, indent);
+				lines.push(Line{indent, code: code + ";", comment_opt: None});
+				continue;
+			},
+			_ => continue
+		};
+		let indent: usize = base + in_anchor as usize;
+		flush_pending(&mut lines, &mut pending, indent);
+		lines.push(Line{indent, code, comment_opt: None});
+	}
+	flush_pending(&mut lines, &mut pending, base + in_anchor as usize);
+	// Done
+	Ok(write_lines(&lines))
+}
+
+/// Tokens separated by one space, with assembly words spelled like they are in the config
+fn format_instruction(node: &SyntaxTreeNode, config: &AssemblerConfig) -> String {
+	let tokens: Vec<&Token> = node.children.iter().filter_map(|child| match &child.type_ {
+		SyntaxTreeNodeType::InstructionToken(token) => Some(token),
+		_ => None
+	}).collect();
+	let opcode_opt: Option<String> = match tokens.first().map(|token| &token.enum_) {
+		Some(TokenEnum::AssemblyWord(raw)) => config.encode_word(AssemblerWordContext::Opcode, raw).map(|word| word.name),
+		_ => None
+	};
+	let mut out = Vec::<String>::new();
+	for (i, token) in tokens.iter().enumerate() {
+		let raw: &str = match &token.enum_ {
+			TokenEnum::AssemblyWord(raw) => raw,
+			_ => {
+				out.push(token.raw.clone());
+				continue;
+			}
+		};
+		// Where the word would be looked up when it is assembled, then anywhere else in case it is in the wrong place
+		let context_opt: Option<AssemblerWordContext> = match (i, opcode_opt.as_deref(), tokens.len()) {
+			(0, _, _) => Some(AssemblerWordContext::Opcode),
+			(1, Some("move"), 4) => Some(AssemblerWordContext::AluOpcode),
+			(_, Some("move"), len) if i == len - 2 => Some(AssemblerWordContext::ToBus),
+			(_, Some("move" | "write"), len) if i == len - 1 => Some(AssemblerWordContext::FromBus),
+			(_, Some("call"), _) => Some(AssemblerWordContext::AfterCall),
+			(_, Some("return"), _) => Some(AssemblerWordContext::AfterReturn),
+			(_, Some(_), _) => Some(AssemblerWordContext::GenericAfterOpcode),
+			_ => None
+		};
+		let contexts = [
+			AssemblerWordContext::Opcode,
+			AssemblerWordContext::AluOpcode,
+			AssemblerWordContext::ToBus,
+			AssemblerWordContext::FromBus,
+			AssemblerWordContext::AfterCall,
+			AssemblerWordContext::AfterReturn,
+			AssemblerWordContext::GenericAfterOpcode
+		];
+		let name_opt: Option<String> = context_opt.into_iter().chain(contexts).find_map(|context| config.encode_word(context, raw)).map(|word| word.name);
+		// Words that aren't in the config, like `@macro` parameters, stay as they are
+		out.push(name_opt.unwrap_or(raw.to_owned()));
+	}
+	// Done
+	out.join(" ") + ";"
+}
+
+/// Own-line comments and blank lines before some code, indented like the code
+fn flush_pending(lines: &mut Vec<Line>, pending: &mut Vec<Option<String>>, indent: usize) {
+	for item in pending.drain(..) {
+		lines.push(match item {
+			Some(text) => Line{indent, code: format!("#{}", text), comment_opt: None},
+			None => Line{indent: 0, code: String::new(), comment_opt: None}
+		});
+	}
+}
+
+/// Joins the lines, lining up trailing comments on lines next to each other one space after the longest code
+fn write_lines(lines: &[Line]) -> String {
+	let mut out = String::new();
+	let mut i: usize = 0;
+	while i < lines.len() {
+		let run_len: usize = lines[i..].iter().take_while(|line| line.comment_opt.is_some()).count().max(1);
+		let run: &[Line] = &lines[i..i + run_len];
+		let comment_column: usize = run.iter().map(|line| line.width()).max().unwrap() + 1;
+		for line in run {
+			let indent: String = "\t".repeat(line.indent);
+			// Lines after the first of `@data` values are one more tab in
+			out += &indent;
+			out += &line.code.replace('\n', &format!("\n{}\t", indent));
+			if let Some(comment) = &line.comment_opt {
+				out += &format!("{}#{}", " ".repeat(comment_column - line.width()), comment);
+			}
+			out.push('\n');
+		}
+		i += run_len;
+	}
+	// Done
+	out
+}
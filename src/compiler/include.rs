@@ -0,0 +1,232 @@
+//! `@include("path")`, splices other source files into the program
+//! Paths are relative to the directory of the file containing the `@include()`, every file shares the same anchor namespace
+//! Each file is only included once, later `@include()`s of the same file are ignored
+//! Conditional assembly is resolved here too so that an `@include()` in a branch that isn't taken doesn't read the file
+//! `@binfile()` files are read here as well, their paths work the same way
+
+use std::{collections::HashSet, fmt, fs, path::{Path, PathBuf}};
+
+use crate::prelude::*;
+use super::{CompilerError, CompilerErrorEnum, ErrorLocation, SourceFile, Span, conditional::select_conditional, macros::{MacroArgument, MacroEnum}, syntax_tree::{SyntaxTreeNode, SyntaxTreeNodeType}};
+
+#[derive(Debug)]
+pub enum IncludeErrorEnum {
+	/// Names of the files in the cycle, the first and last are the same
+	Cycle(Vec<String>),
+	/// (path, reason)
+	ReadFailed(String, String),
+	ArgumentWrongType(MacroArgument),
+	/// (path, reason), `.pbm` file given to `@binfile()` that can't be loaded
+	ImageInvalid(String, String),
+	/// (path, number of bytes), `@binfile()` that would take more instructions to load than a program can have
+	BinFileTooLarge(String, usize)
+}
+
+impl fmt::Display for IncludeErrorEnum {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Cycle(names) => write!(f, "Include cycle: {}", names.join(" -> ")),
+			Self::ReadFailed(path, reason) => write!(f, "Could not read included file \"{}\": {}", path, reason),
+			Self::ArgumentWrongType(arg) => write!(f, "Include path must be a string literal, not \"{}\"", arg.to_string()),
+			Self::ImageInvalid(path, reason) => write!(f, "Invalid image \"{}\": {}", path, reason),
+			Self::BinFileTooLarge(path, len) => write!(f, "\"{}\" is {} bytes, which is too large to load in a program", path, len)
+		}
+	}
+}
+
+impl std::error::Error for IncludeErrorEnum {}
+
+/// Everything needed while resolving includes, so it doesn't have to be passed through every recursive call
+struct IncludeResolver<'a> {
+	config: &'a AssemblerConfig,
+	files: &'a mut Vec<SourceFile>,
+	sources: &'a mut Vec<Vec<char>>,
+	errors: &'a mut Vec<CompilerError>,
+	/// Canonical paths and file indices of files currently being included, for cycle detection
+	stack: Vec<(PathBuf, usize)>,
+	/// Canonical paths of every file included so far, so that a file included from 2 places only appears once
+	included: HashSet<PathBuf>
+}
+
+/// Replaces every `@include()` in `root` (the syntax tree of `files[0]`, located at `main_path`) with the top level nodes of the included file, recursively
+/// Included files are added to `files` and `sources`, parse errors in them and include errors are added to `errors`
+/// Returns the top level nodes of the whole program in order, each with the index of the file it is from, leaving out `@ifdef` and `@ifndef` branches that aren't taken
+pub fn resolve_includes(root: SyntaxTreeNode, main_path: &Path, config: &AssemblerConfig, files: &mut Vec<SourceFile>, sources: &mut Vec<Vec<char>>, errors: &mut Vec<CompilerError>) -> Vec<(SyntaxTreeNode, usize)> {
+	let mut resolver = IncludeResolver {
+		config,
+		files,
+		sources,
+		errors,
+		stack: vec![(canonical(main_path), 0)],
+		included: HashSet::from([canonical(main_path)])
+	};
+	let mut out = Vec::<(SyntaxTreeNode, usize)>::new();
+	resolver.splice(root, 0, main_path, &mut out);
+	// Done
+	out
+}
+
+impl IncludeResolver<'_> {
+	fn splice(&mut self, root: SyntaxTreeNode, file: usize, path: &Path, out: &mut Vec<(SyntaxTreeNode, usize)>) {
+		for node in select_conditional(root.children, self.config) {
+			let macro_ = match &node.type_ {
+				SyntaxTreeNodeType::Macro(macro_) if macro_.type_ == MacroEnum::Include => macro_,
+				SyntaxTreeNodeType::Macro(macro_) if matches!(macro_.type_, MacroEnum::BinFile(_)) => {
+					let binfile_node = self.load_binfile(node, file, path);
+					out.push((binfile_node, file));
+					continue;
+				},
+				_ => {
+					out.push((node, file));
+					continue;
+				}
+			};
+			// Macro nodes begin after the "@"
+			let span = Span::from_char_indices(&self.sources[file], node.begin - 1, node.end);
+			let include_arg: &str = match &macro_.args[0] {
+				MacroArgument::StringLiteral(include_arg) => include_arg,
+				invalid => {
+					self.add_error(file, span, IncludeErrorEnum::ArgumentWrongType(invalid.clone()));
+					continue;
+				}
+			};
+			let include_path: PathBuf = relative_path(path, include_arg);
+			let include_name: String = match &self.files[file].name {
+				Some(name) => match Path::new(name).parent() {
+					Some(dir) => dir.join(include_arg).to_string_lossy().into_owned(),
+					None => include_arg.to_owned()
+				},
+				None => include_arg.to_owned()
+			};
+			// Cycle detection
+			let include_canonical: PathBuf = canonical(&include_path);
+			if let Some(cycle_start) = self.stack.iter().position(|(stack_path, _)| *stack_path == include_canonical) {
+				let mut names: Vec<String> = self.stack[cycle_start..].iter().map(|(_, stack_file)| self.display_name(*stack_file)).collect();
+				names.push(include_name);
+				self.add_error(file, span, IncludeErrorEnum::Cycle(names));
+				continue;
+			}
+			// Include once
+			if !self.included.insert(include_canonical.clone()) {
+				continue;
+			}
+			let text: String = match fs::read_to_string(&include_path) {
+				Ok(text) => text,
+				Err(e) => {
+					self.add_error(file, span, IncludeErrorEnum::ReadFailed(include_path.to_string_lossy().into_owned(), e.to_string()));
+					continue;
+				}
+			};
+			// Parse included file
+			let source: Vec<char> = text.chars().collect();
+			let (tree, parse_errors) = SyntaxTreeNode::build_tree_recovering(&source);
+			let include_file: usize = self.files.len();
+			self.files.push(SourceFile {
+				name: Some(include_name),
+				text
+			});
+			for parse_error in parse_errors {
+				self.errors.push(CompilerError::from_source_string_index(&source, parse_error.begin, parse_error.end, self.files[include_file].name.as_deref(), None, CompilerErrorEnum::Parse(parse_error)));
+			}
+			self.sources.push(source);
+			// Recurse
+			self.stack.push((include_canonical, include_file));
+			self.splice(tree, include_file, &include_path, out);
+			self.stack.pop();
+		}
+	}
+	/// Reads the file into the `MacroEnum::BinFile` of `node`, only the pixels of `.pbm` images are kept
+	fn load_binfile(&mut self, mut node: SyntaxTreeNode, file: usize, path: &Path) -> SyntaxTreeNode {
+		let span = Span::from_char_indices(&self.sources[file], node.begin - 1, node.end);
+		if let SyntaxTreeNodeType::Macro(macro_) = &mut node.type_ {
+			let binfile_arg: String = match (macro_.type_.args_correct_type(&macro_.args), macro_.args.last()) {
+				(Ok(()), Some(MacroArgument::StringLiteral(binfile_arg))) => binfile_arg.clone(),
+				(_, last_arg_opt) => {
+					if let Some(last_arg) = last_arg_opt {
+						self.add_error(file, span, IncludeErrorEnum::ArgumentWrongType(last_arg.clone()));
+					}
+					return node;
+				}
+			};
+			let binfile_path: PathBuf = relative_path(path, &binfile_arg);
+			let binfile_path_string: String = binfile_path.to_string_lossy().into_owned();
+			let mut bytes: Vec<u8> = match fs::read(&binfile_path) {
+				Ok(bytes) => bytes,
+				Err(e) => {
+					self.add_error(file, span, IncludeErrorEnum::ReadFailed(binfile_path_string, e.to_string()));
+					return node;
+				}
+			};
+			if binfile_path.extension().is_some_and(|extension| extension == "pbm") {
+				bytes = match pbm_pixels(&bytes) {
+					Ok(pixels) => pixels,
+					Err(reason) => {
+						self.add_error(file, span, IncludeErrorEnum::ImageInvalid(binfile_path_string, reason));
+						return node;
+					}
+				};
+			}
+			// 1 instruction per byte and 2 to set the address
+			if bytes.len() + 2 > u16::MAX as usize {
+				self.add_error(file, span, IncludeErrorEnum::BinFileTooLarge(binfile_path_string, bytes.len()));
+				return node;
+			}
+			macro_.type_ = MacroEnum::BinFile(bytes);
+		}
+		// Done
+		node
+	}
+	fn add_error(&mut self, file: usize, span: Span, enum_: IncludeErrorEnum) {
+		let location = ErrorLocation::new(&self.files[file].text, span, self.files[file].name.as_deref());
+		self.errors.push(CompilerError::new(Some(location), None, CompilerErrorEnum::Include(enum_)));
+	}
+	fn display_name(&self, file: usize) -> String {
+		self.files[file].name.clone().unwrap_or("<input>".to_owned())
+	}
+}
+
+/// `arg` relative to the directory of the file at `path`
+fn relative_path(path: &Path, arg: &str) -> PathBuf {
+	match path.parent() {
+		Some(dir) => dir.join(arg),
+		None => PathBuf::from(arg)
+	}
+}
+
+/// Pixels of a binary (P4) PBM image, 8 pixels per byte with the leftmost in the MSB and 1 for black, each row starting on a new byte
+fn pbm_pixels(file: &[u8]) -> Result<Vec<u8>, String> {
+	if !file.starts_with(b"P4") {
+		return Err("Only binary PBM images (starting with \"P4\") are supported".to_owned());
+	}
+	// Width and height separated by whitespace and "#" comments, then a single whitespace character before the pixels
+	let mut numbers = Vec::<usize>::new();
+	let mut i: usize = 2;
+	while numbers.len() < 2 {
+		match file.get(i) {
+			Some(b'#') => while file.get(i).is_some_and(|byte| *byte != b'\n') {
+				i += 1;
+			},
+			Some(byte) if byte.is_ascii_whitespace() => i += 1,
+			Some(byte) if byte.is_ascii_digit() => {
+				let digits_len: usize = file[i..].iter().take_while(|byte| byte.is_ascii_digit()).count();
+				numbers.push(to_string_err(String::from_utf8_lossy(&file[i..i + digits_len]).parse::<usize>())?);
+				i += digits_len;
+			},
+			_ => {return Err("Invalid header".to_owned());}
+		}
+	}
+	let (width, height) = (numbers[0], numbers[1]);
+	let pixels_len: usize = match width.div_ceil(8).checked_mul(height) {
+		Some(pixels_len) => pixels_len,
+		None => {return Err(format!("{} x {} image is too large", width, height));}
+	};
+	match (i + 1).checked_add(pixels_len).and_then(|pixels_end| file.get(i + 1..pixels_end)) {
+		Some(pixels) => Ok(pixels.to_vec()),
+		None => Err(format!("{} x {} image should have {} bytes of pixels", width, height, pixels_len))
+	}
+}
+
+/// So that different ways of writing the same path are detected as the same file, the path is left as-is if it doesn't exist
+fn canonical(path: &Path) -> PathBuf {
+	fs::canonicalize(path).unwrap_or(path.to_owned())
+}
@@ -0,0 +1,159 @@
+//! Warnings for instructions that assemble but don't do what they look like they do on the hardware
+//! Each lint has a code that doesn't change, so a line can allow it with a comment like `# allow(implicit-add)`
+//! `--deny-warnings` turns every warning into an error (see `AssemblerConfig::deny_warnings`)
+
+use std::fmt;
+
+use crate::prelude::*;
+use super::{CompilerWarning, ErrorLocation, SourceFile, Span, assembly_encode::{AssemblerWordContext, Token, TokenEnum}, program_skeleton::SourceLocation};
+
+/// Every lint code, for checking `# allow()`
+pub const LINT_CODES: [&str; 5] = ["invalid-device", "ext-alu-op", "implicit-add", "unused-alu-op", "unknown-lint"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LintEnum {
+	/// TX 3 (the control unit) or a TX/RX that isn't connected to anything, the emulator stops with an error (name)
+	InvalidDevice(String),
+	/// `ext-10` - `ext-15` aren't implemented by the ALU, the emulator stops with an error (name)
+	ExtAluOpcode(String),
+	/// `move alu <destination>`, the ALU opcode bits are 0 which is `add`
+	ImplicitAdd,
+	/// ALU opcode on a `move` that isn't from `alu` (name)
+	UnusedAluOpcode(String),
+	/// Code in `# allow()` that isn't a lint
+	UnknownLint(String)
+}
+
+impl LintEnum {
+	/// Stable code used by `# allow()`, one of `LINT_CODES`
+	pub fn code(&self) -> &'static str {
+		match self {
+			Self::InvalidDevice(_) => LINT_CODES[0],
+			Self::ExtAluOpcode(_) => LINT_CODES[1],
+			Self::ImplicitAdd => LINT_CODES[2],
+			Self::UnusedAluOpcode(_) => LINT_CODES[3],
+			Self::UnknownLint(_) => LINT_CODES[4]
+		}
+	}
+}
+
+impl fmt::Display for LintEnum {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::InvalidDevice(name) => write!(f, "`{}` isn't a device on the bus, the emulator stops with an error when it is used", name),
+			Self::ExtAluOpcode(name) => write!(f, "ALU opcode `{}` isn't implemented, the emulator stops with an error when it is used", name),
+			Self::ImplicitAdd => write!(f, "`move` from `alu` without an ALU opcode uses `add`"),
+			Self::UnusedAluOpcode(name) => write!(f, "ALU opcode `{}` does nothing because the source isn't `alu`", name),
+			Self::UnknownLint(code) => write!(f, "`{}` isn't a lint code, it can be one of {}", code, LINT_CODES.join(", "))
+		}
+	}
+}
+
+/// Lints every instruction in `lines` and every `# allow()` in `files`, leaving out allowed ones
+pub fn lint<'a>(lines: impl Iterator<Item = &'a (Vec<Token>, SourceLocation)>, files: &[SourceFile], config: &AssemblerConfig) -> Vec<CompilerWarning> {
+	let mut out = Vec::<CompilerWarning>::new();
+	let line_text = |location: &SourceLocation| -> &str {files[location.file].text.split('\n').nth(location.line - 1).unwrap_or("")};
+	for (tokens, location) in lines {
+		let allowed: Vec<(String, usize)> = allowed_codes(line_text(location));
+		for lint in lint_instruction(tokens, config) {
+			if !allowed.iter().any(|(code, _)| code == lint.code()) {
+				out.push(CompilerWarning {
+					location: ErrorLocation::new(&files[location.file].text, location.span, files[location.file].name.as_deref()),
+					lint
+				});
+			}
+		}
+	}
+	// Misspelled codes would otherwise silently allow nothing
+	for file in files {
+		let mut line_start: usize = 0;
+		for line in file.text.split('\n') {
+			for (code, offset) in allowed_codes(line) {
+				if !LINT_CODES.contains(&code.as_str()) {
+					let begin: usize = line_start + offset;
+					out.push(CompilerWarning {
+						location: ErrorLocation::new(&file.text, Span::new(begin, begin + code.len()), file.name.as_deref()),
+						lint: LintEnum::UnknownLint(code)
+					});
+				}
+			}
+			line_start += line.len() + 1;
+		}
+	}
+	// Done
+	out
+}
+
+/// Lints for one instruction, words that don't exist are left to the assembler to report
+fn lint_instruction(tokens: &[Token], config: &AssemblerConfig) -> Vec<LintEnum> {
+	let mut out = Vec::<LintEnum>::new();
+	let word = |i: usize, context: AssemblerWordContext| -> Option<(String, u8)> {
+		match &tokens.get(i)?.enum_ {
+			TokenEnum::AssemblyWord(raw) => config.encode_word(context, raw).map(|word| (word.name, word.id_)),
+			_ => None
+		}
+	};
+	// Control unit and unconnected IDs, which are only named for version 2
+	let invalid_tx = |id_: u8| -> bool {id_ == 3 || (config.version == HardwareVersion::V2 && id_ >= 17)};
+	let invalid_rx = |id_: u8| -> bool {config.version == HardwareVersion::V2 && id_ >= 26};
+	match word(0, AssemblerWordContext::Opcode).map(|(name, _)| name).as_deref() {
+		Some("move") if tokens.len() == 3 || tokens.len() == 4 => {
+			let alu_opcode_opt: Option<(String, u8)> = match tokens.len() {
+				4 => word(1, AssemblerWordContext::AluOpcode),
+				_ => None
+			};
+			let source_opt: Option<(String, u8)> = word(tokens.len() - 2, AssemblerWordContext::ToBus);
+			if let Some((name, id_)) = &source_opt {
+				if invalid_tx(*id_) {
+					out.push(LintEnum::InvalidDevice(name.clone()));
+				}
+			}
+			if let Some((name, id_)) = word(tokens.len() - 1, AssemblerWordContext::FromBus) {
+				if invalid_rx(id_) {
+					out.push(LintEnum::InvalidDevice(name));
+				}
+			}
+			let from_alu: bool = source_opt.map(|(name, _)| name.to_lowercase()) == Some("alu".to_owned());
+			match (alu_opcode_opt, from_alu) {
+				(Some((name, id_)), true) if id_ >= 10 => out.push(LintEnum::ExtAluOpcode(name)),
+				(Some((name, _)), false) => out.push(LintEnum::UnusedAluOpcode(name)),
+				(None, true) if tokens.len() == 3 => out.push(LintEnum::ImplicitAdd),
+				_ => {}
+			}
+		},
+		Some("write") => if let Some((name, id_)) = word(2, AssemblerWordContext::FromBus) {
+			if invalid_rx(id_) {
+				out.push(LintEnum::InvalidDevice(name));
+			}
+		},
+		_ => {}
+	}
+	// Done
+	out
+}
+
+/// Codes in `# allow()`s on a line, with the byte offset of each in the line
+fn allowed_codes(line: &str) -> Vec<(String, usize)> {
+	let mut out = Vec::<(String, usize)>::new();
+	for (hash_i, _) in line.match_indices('#') {
+		let after_hash: &str = &line[hash_i + 1..];
+		let list_start: usize = match after_hash.trim_start().strip_prefix("allow(") {
+			Some(rest) => line.len() - rest.len(),
+			None => continue
+		};
+		let list_end: usize = match line[list_start..].find(')') {
+			Some(end) => list_start + end,
+			None => continue
+		};
+		let mut code_start: usize = list_start;
+		for code in line[list_start..list_end].split(',') {
+			let trimmed: &str = code.trim();
+			if !trimmed.is_empty() {
+				out.push((trimmed.to_owned(), code_start + code.find(trimmed).unwrap()));
+			}
+			code_start += code.len() + 1;
+		}
+	}
+	// Done
+	out
+}
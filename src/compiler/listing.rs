@@ -0,0 +1,120 @@
+//! Assembler listing, every program address with its encoding, decoded fields and where it came from in the source
+//! Written to `out/<name>.lst` by `-assemble-file <name> -listing`
+
+use std::fmt::Write;
+
+use crate::prelude::*;
+use super::{Program, assembly_encode::{AssemblerWordContext, InstructionFields}, program_skeleton::{Symbol, SourceLocation, gpram_byte_address}};
+
+/// Creates the listing as a string
+pub fn listing(program: &Program, config: &AssemblerConfig) -> String {
+	let file_lines: Vec<Vec<&str>> = program.files.iter().map(|file| file.text.split('\n').collect()).collect();
+	let mut out = String::new();
+	writeln!(out, "# Listing of {}, hardware version {}", program.source_name().unwrap_or("<input>"), match config.version {
+		HardwareVersion::V1 => 1,
+		HardwareVersion::V2 => 2
+	}).unwrap();
+	writeln!(out, "{:<8}{:<8}{:<18}{:<12}{:<8}{:<18}{:<38}{:<8}{:<6}SOURCE", "ADDR", "HEX", "BINARY", "OPCODE", "ALU", "TX", "RX", "LIT", "LINE").unwrap();
+	write_instructions(&mut out, program, config, &file_lines, 0, &program.words, &program.locations);
+	// Code run from GPRAM, which is loaded by the first instructions above
+	for section in &program.gpram_sections {
+		writeln!(out, "\n# GPRAM code at {:#06X}, GPRAM address {:#06X}", section.address, gpram_byte_address(section.address)).unwrap();
+		write_instructions(&mut out, program, config, &file_lines, section.address, &section.words, &section.locations);
+	}
+	// Cost of loading data into GPRAM
+	let data_blocks = program.data_blocks();
+	if !data_blocks.is_empty() {
+		writeln!(out, "\n# Data").unwrap();
+		for (address, len, location) in data_blocks {
+			writeln!(out, "{:#06X}  {:<6}{}", address, len, location.macro_opt.as_ref().unwrap().format()).unwrap();
+		}
+	}
+	// `@const`s then `@var`s, before the anchors because `disassemble::parse_symbol_table()` reads everything after "# Anchors"
+	if !program.symbols.is_empty() {
+		writeln!(out, "\n# Symbols").unwrap();
+		let mut symbols: Vec<(&String, &Symbol)> = program.symbols.iter().collect();
+		symbols.sort_by_key(|(name, symbol)| (matches!(symbol, Symbol::Var{..}), symbol.value(), (*name).clone()));
+		for (name, symbol) in symbols {
+			match symbol {
+				Symbol::Const(value) => writeln!(out, "{:#06X}  {}", value, name).unwrap(),
+				Symbol::Var{address, size} => writeln!(out, "{:#06X}  {}[{}]", address, name, size).unwrap()
+			}
+		}
+	}
+	// Symbol table
+	writeln!(out, "\n# Anchors").unwrap();
+	let mut anchors: Vec<(&String, &usize)> = program.anchors.iter().collect();
+	anchors.sort_by_key(|(name, address)| (**address, (*name).clone()));
+	for (name, address) in anchors {
+		writeln!(out, "{:#06X}  {}", address, name).unwrap();
+	}
+	// Done
+	out
+}
+
+/// Rows for `words`, which start at program address `start`
+fn write_instructions(out: &mut String, program: &Program, config: &AssemblerConfig, file_lines: &[Vec<&str>], start: usize, words: &[u16], locations: &[SourceLocation]) {
+	let alu_id_opt: Option<u8> = config.encode_word(AssemblerWordContext::ToBus, "alu").map(|word| word.id_);
+	for (i, word) in words.iter().enumerate() {
+		let address: usize = start + i;
+		let fields = InstructionFields::decode(*word, config.version);
+		let location = &locations[i];
+		// Only show the source line once for a group of instructions from the same place
+		let new_location: bool = i == 0 || locations[i - 1] != *location;
+		let source_text: &str = match new_location {
+			true => file_lines[location.file].get(location.line - 1).map(|line| line.trim()).unwrap_or(""),
+			false => ""
+		};
+		let macro_marker: String = match &location.macro_opt {
+			Some(macro_) => format!("  [from {}]", macro_.format()),
+			None => String::new()
+		};
+		writeln!(
+			out,
+			"{:<8}{:<8}{:<18}{:<12}{:<8}{:<18}{:<38}{:<8}{:<6}{}{}",
+			format!("{:#06X}", address),
+			format!("{:#06X}", word),
+			format!("{:016b}", word),
+			match fields.opcode {
+				Some(opcode) => word_name(config, AssemblerWordContext::Opcode, opcode),
+				None => format!("?{}", fields.raw_opcode)
+			},
+			match (fields.alu_opcode, fields.to_bus) {
+				// ALU opcode only matters when the ALU is writing to the bus
+				(Some(alu_opcode), Some(to_bus)) if Some(to_bus) == alu_id_opt => word_name(config, AssemblerWordContext::AluOpcode, alu_opcode),
+				_ => "-".to_owned()
+			},
+			optional_word_name(config, AssemblerWordContext::ToBus, fields.to_bus),
+			optional_word_name(config, AssemblerWordContext::FromBus, fields.from_bus),
+			match (fields.literal, fields.flag, fields.opcode) {
+				(Some(literal), _, _) => format!("{:#04X}", literal),
+				(None, Some(flag), Some(5)) => word_name(config, AssemblerWordContext::AfterCall, flag),
+				(None, Some(flag), Some(6)) => word_name(config, AssemblerWordContext::AfterReturn, flag),
+				(None, Some(flag), _) => word_name(config, AssemblerWordContext::GenericAfterOpcode, flag),
+				_ => "-".to_owned()
+			},
+			match (new_location, &program.files[location.file].name) {
+				(false, _) => String::new(),
+				// Included files show which file the line is in
+				(true, Some(name)) if location.file != 0 => format!("{}:{}", name, location.line),
+				(true, _) => location.line.to_string()
+			},
+			source_text,
+			macro_marker
+		).unwrap();
+	}
+}
+
+fn word_name(config: &AssemblerConfig, context: AssemblerWordContext, id_: u8) -> String {
+	match config.decode_word(context, id_) {
+		Some(word) => word.name,
+		None => format!("?{}", id_)
+	}
+}
+
+fn optional_word_name(config: &AssemblerConfig, context: AssemblerWordContext, id_opt: Option<u8>) -> String {
+	match id_opt {
+		Some(id_) => word_name(config, context, id_),
+		None => "-".to_owned()
+	}
+}
@@ -1,7 +1,12 @@
 //! For assembling macros
 
+use std::fmt;
+
 use crate::prelude::*;
-use super::{program_skeleton::ProgramSkeletonBuildError, ParseError, ParseErrorType};
+use super::{anchor::ANCHOR_REFERENCE_CHARS, expression::Expression, program_skeleton::ProgramSkeletonBuildError, ParseError, ParseErrorType};
+
+/// Macros that can be used without parenthesis when they don't have any arguments
+const NO_PARENTHESIS_MACROS: [&str; 6] = ["endmacro", "if", "else", "endif", "while", "endwhile"];
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Macro {
@@ -15,9 +20,36 @@ impl Macro {
 	pub fn parse(source: &Vec<char>, start: usize) -> Result<(Self, usize), ParseError> {
 		let mut i: usize = start;
 		// First, read macro name
-		let (name, new_i) = parse_identifier(source, i, Some(ParseContext::Macro))?;
+		let (name, new_i) = parse_identifier(source, i, None)?;// EOF is checked after `@endmacro`
+		// `@endmacro` and block macros like `@else` don't need parenthesis
+		let after_name: usize = skip_whitespace(source, new_i, None)?;
+		if NO_PARENTHESIS_MACROS.contains(&name.as_str()) && (after_name == source.len() || source[after_name] != '(') {
+			return Ok((
+				Self {
+					type_: MacroEnum::match_identifier(&name, start, new_i)?,
+					args: Vec::new()
+				},
+				new_i
+			));
+		}
+		// Declarations without parenthesis
+		match name.as_str() {
+			"const" => {return Self::parse_const(source, after_name);},
+			"var" => {return Self::parse_var(source, after_name);},
+			_ => {}
+		}
 		// Skip whitespace after macro name
 		i = skip_whitespace(source, new_i, Some(ParseContext::Macro))?;
+		// `@macro <name>(<params>)` has the name of the macro being defined before the "("
+		let mut definition_name_opt: Option<String> = None;
+		if name == "macro" && source[i] != '(' {
+			let (definition_name, new_i) = parse_identifier(source, i, Some(ParseContext::Macro))?;
+			if definition_name.is_empty() {
+				return Err(ParseError::new(i, i+1, ParseErrorType::MissingMacroIdentifier, Some("Expected name of macro being defined".to_string())));
+			}
+			definition_name_opt = Some(definition_name);
+			i = skip_whitespace(source, new_i, Some(ParseContext::Macro))?;
+		}
 		// Check that next character is "("
 		if source[i] != '(' {
 			return Err(ParseError::new(i, i+1, ParseErrorType::InvalidCharacterInContext(source[i], ParseContext::Macro), Some("Expected \"(\"".to_string())));
@@ -35,9 +67,9 @@ impl Macro {
 				args.push(MacroArgument::StringLiteral(string_arg));
 				i = end;
 			}
-			// Check if identifier
-			if IDENTIFIER_CHARS.contains(&char_) {
-				let (arg, new_i) = parse_identifier(source, i, Some(ParseContext::Macro))?;
+			// Check if identifier, or an anchor reference like `.loop`, `:+` or `loop+3`
+			if IDENTIFIER_CHARS.contains(&char_) || ANCHOR_REFERENCE_CHARS.contains(&char_) {
+				let (arg, new_i) = parse_until_false(source, i, |c: &char| -> bool {IDENTIFIER_CHARS.contains(c) || ANCHOR_REFERENCE_CHARS.contains(c)}, Some(ParseContext::Macro), true)?;
 				i = new_i;
 				args.push(MacroArgument::Identifier(arg));
 			}
@@ -53,13 +85,32 @@ impl Macro {
 			if char_ != ',' {
 				return Err(ParseError::new(i, i+1, ParseErrorType::InvalidCharacterInContext(char_, ParseContext::Macro), Some("Expected \",\"".to_string())));
 			}
+			i += 1;// get past the ","
 		}
 		// Match name with varients of this enum
-		let enum_ = MacroEnum::match_identifier(&name, start, i)?;
-		let correct_n_args = enum_.num_args();
-		if correct_n_args != args.len() {
-			return Err(ParseError::new(start, i, ParseErrorType::MacroWrongNumArgs{actual: args.len(), correct: correct_n_args}, None));
+		let enum_ = match definition_name_opt {
+			Some(definition_name) => MacroEnum::Define(definition_name),
+			None => MacroEnum::match_identifier(&name, start, i)?
+		};
+		// User macros are checked when they are expanded since the definition could be anywhere
+		if let Some(correct_n_args) = enum_.num_args() {
+			if correct_n_args != args.len() {
+				return Err(ParseError::new(start, i, ParseErrorType::MacroWrongNumArgs{actual: args.len(), correct: correct_n_args}, None));
+			}
+		}
+		// `@binfile` has an optional address
+		if matches!(enum_, MacroEnum::BinFile(_)) && !(1..=2).contains(&args.len()) {
+			return Err(ParseError::new(start, i, ParseErrorType::MacroWrongNumArgs{actual: args.len(), correct: 2}, None));
 		}
+		// `@data` is followed by the values
+		let enum_ = match enum_ {
+			MacroEnum::Data(_) => {
+				let (values, end) = Self::parse_data_values(source, i)?;
+				i = end;
+				MacroEnum::Data(values)
+			},
+			enum_ => enum_
+		};
 		// Done
 		Ok((
 			Self {
@@ -69,12 +120,113 @@ impl Macro {
 			i
 		))
 	}
+	/// `@const NAME = <value>`, `start` is at NAME
+	fn parse_const(source: &Vec<char>, start: usize) -> Result<(Self, usize), ParseError> {
+		let (name, mut i) = Self::parse_declaration_name(source, start)?;
+		i = skip_whitespace(source, i, Some(ParseContext::Macro))?;
+		if source[i] != '=' {
+			return Err(ParseError::new(i, i+1, ParseErrorType::InvalidCharacterInContext(source[i], ParseContext::Macro), Some("Expected \"=\"".to_string())));
+		}
+		i = skip_whitespace(source, i + 1, Some(ParseContext::Macro))?;
+		let (value, end) = parse_number_u16(source, i)?;
+		Ok((
+			Self {
+				type_: MacroEnum::Const{name, value},
+				args: Vec::new()
+			},
+			end
+		))
+	}
+	/// `@var name[size]` or `@var name` for 1 byte, `start` is at name
+	fn parse_var(source: &Vec<char>, start: usize) -> Result<(Self, usize), ParseError> {
+		let (name, mut i) = Self::parse_declaration_name(source, start)?;
+		let mut size: u16 = 1;
+		if source.get(i) == Some(&'[') {
+			let size_start: usize = skip_whitespace(source, i + 1, Some(ParseContext::Macro))?;
+			let (size_parsed, size_end) = parse_number_u16(source, size_start)?;
+			if size_parsed == 0 {
+				return Err(ParseError::new(size_start, size_end, ParseErrorType::VarSizeZero, None));
+			}
+			i = skip_whitespace(source, size_end, Some(ParseContext::Macro))?;
+			if source[i] != ']' {
+				return Err(ParseError::new(i, i+1, ParseErrorType::InvalidCharacterInContext(source[i], ParseContext::Macro), Some("Expected \"]\"".to_string())));
+			}
+			size = size_parsed;
+			i += 1;
+		}
+		Ok((
+			Self {
+				type_: MacroEnum::Var{name, size},
+				args: Vec::new()
+			},
+			i
+		))
+	}
+	/// `0x01, 'A', "text", lo(table)` after `@data(<address>)`, ends at the end of the line unless the line ends with ","
+	fn parse_data_values(source: &Vec<char>, start: usize) -> Result<(Vec<DataValue>, usize), ParseError> {
+		let skip_spaces = |i: usize| -> usize {
+			match source[i.min(source.len())..].iter().position(|c| *c != ' ' && *c != '\t') {
+				Some(offset) => i + offset,
+				None => source.len()
+			}
+		};
+		let mut values = Vec::<DataValue>::new();
+		let mut i: usize = skip_spaces(start);
+		loop {
+			match source.get(i) {
+				Some('\"') => {
+					let (string, end) = parse_string_literal(source, i + 1)?;
+					// 1 byte per character
+					if let Some(char_i) = (i + 1..end).find(|char_i| !source[*char_i].is_ascii()) {
+						return Err(ParseError::new(char_i, char_i + 1, ParseErrorType::NonAsciiCharacter(source[char_i]), None));
+					}
+					values.push(DataValue::String(string));
+					i = end;
+				},
+				Some(_) => {
+					let (expression, end) = Expression::parse(source, i)?;
+					values.push(DataValue::Expression(expression));
+					i = end;
+				},
+				None => {return Err(ParseError::new(start, i, ParseErrorType::UnfinishedNode(ParseContext::Macro), Some("Expected data values".to_string())));}
+			}
+			i = skip_spaces(i);
+			if source.get(i) != Some(&',') {
+				break;
+			}
+			// Values can continue on the next line after a ","
+			i = skip_whitespace(source, i + 1, Some(ParseContext::Macro))?;
+		}
+		Ok((values, i))
+	}
+	fn parse_declaration_name(source: &Vec<char>, start: usize) -> Result<(String, usize), ParseError> {
+		let (name, end) = parse_identifier(source, start, None)?;
+		if name.is_empty() {
+			return Err(ParseError::new(start, start+1, ParseErrorType::MissingMacroIdentifier, Some("Expected name being declared".to_string())));
+		}
+		Ok((name, end))
+	}
 	pub fn instructions_represented(&self) -> u16 {
 		self.type_.instructions_represented(&self.args)
 	}
+	/// How it would be written in the source, for example `@call(add-two)`
+	pub fn format(&self) -> String {
+		let args: Vec<String> = self.args.iter().map(|arg| match arg {
+			MacroArgument::Identifier(s) => s.clone(),
+			MacroArgument::StringLiteral(s) => format!("{:?}", s)
+		}).collect();
+		match &self.type_ {
+			MacroEnum::Define(name) => format!("{}macro {}({})", MACRO_BEGIN, name, args.join(", ")),
+			type_ if args.is_empty() && NO_PARENTHESIS_MACROS.contains(&type_.name()) => format!("{}{}", MACRO_BEGIN, type_.name()),
+			MacroEnum::Const{name, value} => format!("{}const {} = {:#06X}", MACRO_BEGIN, name, value),
+			MacroEnum::Var{name, size} => format!("{}var {}[{}]", MACRO_BEGIN, name, size),
+			MacroEnum::Data(values) => format!("{}data({}) {}", MACRO_BEGIN, args.join(", "), values.iter().map(|value| value.to_string()).collect::<Vec<String>>().join(", ")),
+			type_ => format!("{}{}({})", MACRO_BEGIN, type_.name(), args.join(", "))
+		}
+	}
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum MacroEnum {
 	Anchor,
 	Call,
@@ -82,22 +234,62 @@ pub enum MacroEnum {
 	GotoIf,
 	WriteString,
 	PushAnchorAddress,
-	#[cfg(feature = "version_2")]
-	SetIntGoto
+	SetIntGoto,
+	/// Resolved before the program skeleton is built, see `include::resolve_includes()`
+	Include,
+	/// `@macro <name>(<params>)`, beginning of a user macro definition, the args are the parameter names
+	Define(String),
+	/// `@endmacro`
+	EndMacro,
+	/// Use of a user macro defined with `@macro`
+	User(String),
+	/// `@const NAME = <value>`
+	Const{name: String, value: u16},
+	/// `@var name[size]`, allocated an address in GPRAM
+	Var{name: String, size: u16},
+	/// `@if(<alu opcode>, <source>)`, `@if(<source>)` or `@if`, runs the block if the goto decider is 1, after loading it from the bus with the arguments if given
+	If,
+	Else,
+	EndIf,
+	/// Same arguments as `@if`, checks the goto decider before every iteration
+	While,
+	EndWhile,
+	/// `@ifdef(NAME)`, conditional assembly, resolved with `conditional::select_conditional()` and uses the same `@else` and `@endif` as `@if`
+	IfDef,
+	/// `@ifndef(NAME)`
+	IfNDef,
+	/// `@data(<address>) <values>`, writes bytes into GPRAM starting at the address
+	Data(Vec<DataValue>),
+	/// `@binfile(<address>, "path")` or `@binfile("path")` to start wherever the GPRAM address already is, the file contents are loaded by `include::resolve_includes()`
+	BinFile(Vec<u8>),
+	/// `@org(<address>)`, the code after it starts at the address, see `program_skeleton::GPRAM_PROGRAM_START`
+	Org
 }
 
 impl MacroEnum {
-	pub fn num_args(&self) -> usize {
-		match self {
+	/// None if it depends on the user macro definition
+	pub fn num_args(&self) -> Option<usize> {
+		Some(match self {
 			Self::Anchor => 1,
 			Self::Call => 1,
 			Self::Goto => 1,
 			Self::GotoIf => 1,
 			Self::WriteString => 1,
 			Self::PushAnchorAddress => 1,
-			#[cfg(feature = "version_2")]
-			Self::SetIntGoto => 1
-		}
+			Self::SetIntGoto => 1,
+			Self::Include => 1,
+			Self::EndMacro => 0,
+			Self::Const{..} | Self::Var{..} => 0,
+			Self::Else | Self::EndIf | Self::EndWhile => 0,
+			Self::IfDef | Self::IfNDef => 1,
+			Self::Data(_) => 1,
+			Self::Org => 1,
+			// 1 or 2, checked by `Macro::parse()`
+			Self::BinFile(_) => {return None;},
+			// 0 to 2, checked by `program_skeleton::expand_blocks()`
+			Self::If | Self::While => {return None;},
+			Self::Define(_) | Self::User(_) => {return None;}
+		})
 	}
 	/// Assumes that the number of arguments (`Self::num_args()`) has already been checked
 	pub fn args_correct_type(&self, args: &Vec<MacroArgument>) -> Result<(), ProgramSkeletonBuildError> {
@@ -126,11 +318,62 @@ impl MacroEnum {
 				MacroArgument::Identifier(_) => Ok(()),
 				invalid => Err(ProgramSkeletonBuildError::MacroArgumentWrongType(invalid.clone()))
 			},
-			#[cfg(feature = "version_2")]
 			Self::SetIntGoto => match &args[0] {
 				MacroArgument::Identifier(_) => Ok(()),
 				invalid => Err(ProgramSkeletonBuildError::MacroArgumentWrongType(invalid.clone()))
-			}
+			},
+			Self::Include => match &args[0] {
+				MacroArgument::StringLiteral(_) => Ok(()),
+				invalid => Err(ProgramSkeletonBuildError::MacroArgumentWrongType(invalid.clone()))
+			},
+			Self::IfDef | Self::IfNDef => match &args[0] {
+				MacroArgument::Identifier(_) => Ok(()),
+				invalid => Err(ProgramSkeletonBuildError::MacroArgumentWrongType(invalid.clone()))
+			},
+			Self::Data(_) | Self::Org => match &args[0] {
+				MacroArgument::Identifier(_) => Ok(()),
+				invalid => Err(ProgramSkeletonBuildError::MacroArgumentWrongType(invalid.clone()))
+			},
+			Self::BinFile(_) => match args.as_slice() {
+				[MacroArgument::StringLiteral(_)] | [MacroArgument::Identifier(_), MacroArgument::StringLiteral(_)] => Ok(()),
+				_ => Err(ProgramSkeletonBuildError::MacroArgumentWrongType(args[0].clone()))
+			},
+			Self::Define(_) | Self::If | Self::While => match args.iter().find(|arg| !matches!(arg, MacroArgument::Identifier(_))) {
+				Some(invalid) => Err(ProgramSkeletonBuildError::MacroArgumentWrongType(invalid.clone())),
+				None => Ok(())
+			},
+			Self::EndMacro => Ok(()),
+			Self::User(_) => Ok(()),
+			Self::Const{..} | Self::Var{..} => Ok(()),
+			Self::Else | Self::EndIf | Self::EndWhile => Ok(())
+		}
+	}
+	/// Inverse of `Self::match_identifier()`
+	pub fn name(&self) -> &str {
+		match self {
+			Self::Anchor => "anchor",
+			Self::Call => "call",
+			Self::Goto => "goto",
+			Self::GotoIf => "goto_if",
+			Self::WriteString => "write_string",
+			Self::PushAnchorAddress => "push_anchor_address",
+			Self::SetIntGoto => "set_interrupt",
+			Self::Include => "include",
+			Self::Define(_) => "macro",
+			Self::EndMacro => "endmacro",
+			Self::User(name) => name,
+			Self::Const{..} => "const",
+			Self::Var{..} => "var",
+			Self::If => "if",
+			Self::Else => "else",
+			Self::EndIf => "endif",
+			Self::While => "while",
+			Self::EndWhile => "endwhile",
+			Self::IfDef => "ifdef",
+			Self::IfNDef => "ifndef",
+			Self::Data(_) => "data",
+			Self::BinFile(_) => "binfile",
+			Self::Org => "org"
 		}
 	}
 	pub fn match_identifier(id: &str, source_start: usize, source_end: usize) -> Result<Self, ParseError> {
@@ -142,7 +385,21 @@ impl MacroEnum {
 			"write_string" => Self::WriteString,
 			"push_anchor_address" => Self::PushAnchorAddress,
 			"set_interrupt" => Self::SetIntGoto,
-			id => {return Err(ParseError::new(source_start, source_end, ParseErrorType::InvalidMacroIdentifier(id.to_owned()), None));}
+			"include" => Self::Include,
+			"endmacro" => Self::EndMacro,
+			"if" => Self::If,
+			"else" => Self::Else,
+			"endif" => Self::EndIf,
+			"while" => Self::While,
+			"endwhile" => Self::EndWhile,
+			"ifdef" => Self::IfDef,
+			"ifndef" => Self::IfNDef,
+			"data" => Self::Data(Vec::new()),
+			"binfile" => Self::BinFile(Vec::new()),
+			"org" => Self::Org,
+			"macro" => {return Err(ParseError::new(source_start, source_end, ParseErrorType::MissingMacroIdentifier, Some("Expected name of macro being defined".to_string())));},
+			"" => {return Err(ParseError::new(source_start, source_end, ParseErrorType::MissingMacroIdentifier, None));},
+			id => Self::User(id.to_owned())
 		})
 	}
 	/// Number of instructions represented by this macro
@@ -154,8 +411,64 @@ impl MacroEnum {
 			Self::GotoIf => 3,
 			Self::WriteString => args[0].to_string().len() as u16 + 2,
 			Self::PushAnchorAddress => 2,
-			#[cfg(feature = "version_2")]
-			Self::SetIntGoto => 2
+			Self::SetIntGoto => 2,
+			Self::Include => 0,
+			// Expanded before instructions are counted
+			Self::Define(_) | Self::EndMacro | Self::User(_) => 0,
+			Self::If | Self::Else | Self::EndIf | Self::While | Self::EndWhile => 0,
+			Self::IfDef | Self::IfNDef => 0,
+			// Setting the GPRAM address, then 1 per byte, saturating since a program can't be that large anyway (`include::resolve_includes()` reports `@binfile()`s that are)
+			Self::Data(values) => u16::try_from(2 + values.iter().map(|value| value.len()).sum::<usize>()).unwrap_or(u16::MAX),
+			Self::BinFile(bytes) => u16::try_from(2 * (args.len() - 1) + bytes.len()).unwrap_or(u16::MAX),
+			// Padding in flash is added by `program_skeleton::macro_expansion()`
+			Self::Org => 0,
+			Self::Const{..} | Self::Var{..} => 0
+		}
+	}
+}
+
+/// Hex (`0x1234`), binary (`0b...`) or decimal (`0d255` or `4660`), up to 16 bits
+fn parse_number_u16(source: &Vec<char>, start: usize) -> Result<(u16, usize), ParseError> {
+	let (raw, end) = parse_identifier(source, start, None)?;
+	let (digits, radix, error_type): (&str, u32, ParseErrorType) = match raw.get(0..2) {
+		Some("0x") => (&raw[2..], 16, ParseErrorType::HexParseError),
+		Some("0b") => (&raw[2..], 2, ParseErrorType::BinaryParseError),
+		Some("0d") => (&raw[2..], 10, ParseErrorType::DecimalParseError),
+		_ => (&raw, 10, ParseErrorType::DecimalParseError)
+	};
+	match u16::from_str_radix(digits, radix) {
+		Ok(n) => Ok((n, end)),
+		Err(e) => Err(ParseError::new(start, end.max(start + 1), error_type, Some(e.to_string())))
+	}
+}
+
+/// A value in `@data()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DataValue {
+	/// 1 byte
+	Expression(Expression),
+	/// 1 byte per character, only ASCII
+	String(String)
+}
+
+impl DataValue {
+	/// Number of bytes
+	pub fn len(&self) -> usize {
+		match self {
+			Self::Expression(_) => 1,
+			Self::String(string) => string.chars().count()
+		}
+	}
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+impl fmt::Display for DataValue {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Expression(expression) => write!(f, "{}", expression),
+			Self::String(string) => write!(f, "{:?}", string)
 		}
 	}
 }
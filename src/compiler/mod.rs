@@ -5,7 +5,7 @@
 //! * Create program skeleton from syntax tree, a lot of validation will happen here
 //! * Perform macro expansion on program skeleton
 //! * Program skeleton will now consist entierly of assembly words, compile to machine code, done
-use std::fs;
+use std::{fmt, fs, path::{Path, PathBuf}};
 
 use crate::prelude::*;
 
@@ -13,23 +13,107 @@ pub mod macros;
 pub mod syntax_tree;
 pub mod program_skeleton;
 pub mod assembly_encode;
+pub mod listing;
+pub mod disassemble;
+pub mod include;
+pub mod expression;
+pub mod anchor;
+pub mod conditional;
+pub mod optimizer;
+pub mod stack_analysis;
+pub mod lint;
+pub mod format;
 
 use syntax_tree::{SyntaxTreeNode, ParseError, ParseErrorType};
-use program_skeleton::program_skeleton_build;
+use program_skeleton::{program_skeleton_build, gpram_byte_address, load_gpram_instructions, AnchorTable, ProgramSkeletonBuildError, SymbolTable, SourceLocation, TokenLines};
 use assembly_encode::{AssemblyEncodeErrorEnum, Token, TokenEnum};
+use macros::MacroEnum;
+use optimizer::OptimizationReport;
+use lint::LintEnum;
 
 #[derive(Debug)]
 pub enum CompilerErrorEnum {
 	Assembly(AssemblyEncodeErrorEnum),
 	Parse(syntax_tree::ParseError),
-	ProgramSkeleton(program_skeleton::ProgramSkeletonBuildError)
+	ProgramSkeleton(program_skeleton::ProgramSkeletonBuildError),
+	Include(include::IncludeErrorEnum),
+	/// Warning with `--deny-warnings`
+	Lint(LintEnum)
+}
+
+impl fmt::Display for CompilerErrorEnum {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Assembly(e) => write!(f, "{}", e),
+			Self::Parse(e) => write!(f, "{}", e),
+			Self::ProgramSkeleton(e) => write!(f, "{}", e),
+			Self::Include(e) => write!(f, "{}", e),
+			Self::Lint(e) => write!(f, "{} (`{}`, denied by --deny-warnings)", e, e.code())
+		}
+	}
+}
+
+/// Byte offsets into the source text, `end` is exclusive
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+	pub begin: usize,
+	pub end: usize
+}
+
+impl Span {
+	pub fn new(begin: usize, end: usize) -> Self {
+		Self {
+			begin,
+			end
+		}
+	}
+	/// The parser works with `Vec<char>` indices, this converts them to byte offsets
+	pub fn from_char_indices(source: &[char], begin: usize, end: usize) -> Self {
+		let byte_offset = |i: usize| -> usize {source[0..i.min(source.len())].iter().map(|c| c.len_utf8()).sum()};
+		Self::new(byte_offset(begin), byte_offset(end))
+	}
+}
+
+/// Where an error is in the source, everything needed to print it without the source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorLocation {
+	/// File name, None if the source is not from a file
+	pub source_name: Option<String>,
+	pub span: Span,
+	/// 1-indexed
+	pub line: usize,
+	/// 1-indexed, in characters
+	pub column: usize,
+	/// Whole line that the span begins on
+	pub line_text: String
+}
+
+impl ErrorLocation {
+	pub fn new(source: &str, span: Span, source_name: Option<&str>) -> Self {
+		let begin: usize = span.begin.min(source.len());
+		let line_start: usize = match source[0..begin].rfind('\n') {
+			Some(newline_i) => newline_i + 1,
+			None => 0
+		};
+		let line_end: usize = match source[begin..].find('\n') {
+			Some(newline_i) => begin + newline_i,
+			None => source.len()
+		};
+		Self {
+			source_name: source_name.map(|name| name.to_owned()),
+			span,
+			line: source[0..begin].matches('\n').count() + 1,
+			column: source[line_start..begin].chars().count() + 1,
+			line_text: source[line_start..line_end].trim_end_matches('\r').to_owned()
+		}
+	}
 }
 
 /// Assembler error
 #[derive(Debug)]
 pub struct CompilerError {
-	/// Option for line number (which will start at 1 :( ) and line raw text
-	pub location_opt: Option<(usize, String)>,
+	/// None if it isn't caused by a specific part of the source, for example the program being too large
+	pub location_opt: Option<ErrorLocation>,
 	/// Specific message
 	pub message_opt: Option<String>,
 	/// Specific error
@@ -38,7 +122,7 @@ pub struct CompilerError {
 
 impl CompilerError {
 	pub fn new(
-		location_opt: Option<(usize, String)>,
+		location_opt: Option<ErrorLocation>,
 		message_opt: Option<String>,
 		enum_: CompilerErrorEnum
 	) -> Self {
@@ -48,80 +132,359 @@ impl CompilerError {
 			enum_
 		}
 	}
+	/// Same as `Display`, kept because it is used everywhere
 	pub fn format(&self) -> String {
-		match &self.location_opt {
-			Some((line_n, line)) => format!(
-				"Error on line {}: {:?}\n\t{}{}\n",
-				line_n,
-				self.enum_,
-				line,
-				match &self.message_opt {
-					Some(msg) => format!("\n\n\t{}", msg.clone()),
-					None => "".to_owned()
-				}
-			),
-			None => format!(
-				"Error: {:?}\n\t{}\n",
-				self.enum_,
-				match &self.message_opt {
-					Some(msg) => format!("\n\n\t{}", msg.clone()),
-					None => "".to_owned()
-				}
-			)
+		format!("{}\n", self)
+	}
+	/// For parse errors, which use indices into the `Vec<char>` source
+	pub fn from_source_string_index(source: &[char], begin: usize, end: usize, source_name: Option<&str>, message_opt: Option<String>, enum_: CompilerErrorEnum) -> Self {
+		let source_string: String = source.iter().collect();
+		Self::new(Some(ErrorLocation::new(&source_string, Span::from_char_indices(source, begin, end), source_name)), message_opt, enum_)
+	}
+}
+
+/// Rustc style
+/// ```text
+/// error: Invalid character ' ' in instruction
+///  --> tetris:12:5
+///    |
+/// 12 |     mve stack-pop alu-a;
+///    |     ^^^
+/// ```
+impl fmt::Display for CompilerError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(f, "error: {}", self.enum_)?;
+		if let Some(location) = &self.location_opt {
+			fmt_source_excerpt(f, location)?;
 		}
+		if let Some(message) = &self.message_opt {
+			write!(f, "\n  = note: {}", message)?;
+		}
+		Ok(())
 	}
-	pub fn from_source_string_index(_source: &Vec<char>, _i: usize, message_opt: Option<String>, enum_: CompilerErrorEnum) -> Self {
-		/*let (_, begin_i) = parse_until_false(source, i, |c: &char| -> bool {*c != '\n'}, None, false).expect("This fucntion should not error with the provided arguments");
-		let (_, end_i) = parse_until_false(source, i, |c: &char| -> bool {*c != '\n'}, None, true).expect("This fucntion should not error with the provided arguments");
-		let line_n: usize = source[0..begin_i].iter().filter(|c: &&char| -> bool {**c == '\n'}).count() + 2;
-		let line: String = source[begin_i..end_i].iter().collect();*/
-		// Done
-		Self {
-			location_opt: None,//Some((line_n, line)),
-			message_opt,
-			enum_
+}
+
+/// Source line with the span underlined, used by errors and warnings
+fn fmt_source_excerpt(f: &mut fmt::Formatter<'_>, location: &ErrorLocation) -> fmt::Result {
+	let line_n_string: String = location.line.to_string();
+	let gutter: String = " ".repeat(line_n_string.len());
+	writeln!(f, "{}--> {}:{}:{}", gutter, location.source_name.as_deref().unwrap_or("<input>"), location.line, location.column)?;
+	writeln!(f, "{} |", gutter)?;
+	writeln!(f, "{} | {}", line_n_string, location.line_text)?;
+	// Underline to the end of the span or the end of the line, whichever is first, tabs are kept so it lines up
+	let before: String = location.line_text.chars().take(location.column - 1).map(|c| if c == '\t' {'\t'} else {' '}).collect();
+	let span_chars: usize = location.line_text.chars().skip(location.column - 1).scan(location.span.begin, |byte_i, c| {
+		let in_span: bool = *byte_i < location.span.end;
+		*byte_i += c.len_utf8();
+		Some(in_span)
+	}).take_while(|in_span| *in_span).count();
+	write!(f, "{} | {}{}", gutter, before, "^".repeat(span_chars.max(1)))
+}
+
+impl std::error::Error for CompilerError {}
+
+/// Lint that wasn't allowed, see `lint`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompilerWarning {
+	pub location: ErrorLocation,
+	pub lint: LintEnum
+}
+
+/// Same as `CompilerError`
+/// ```text
+/// warning[implicit-add]: `move` from `alu` without an ALU opcode uses `add`
+///  --> tetris:12:1
+/// ...
+/// ```
+impl fmt::Display for CompilerWarning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(f, "warning[{}]: {}", self.lint.code(), self.lint)?;
+		fmt_source_excerpt(f, &self.location)?;
+		write!(f, "\n  = note: `# allow({})` at the end of the line hides this", self.lint.code())
+	}
+}
+
+/// A file that is part of a program, the main source or an `@include()`d one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceFile {
+	/// Shown before line numbers, for example "tetris" in `tetris:142`, None if the source is not from a file
+	pub name: Option<String>,
+	pub text: String
+}
+
+/// Assembles a file in `assembly_sources` and prints its stack analysis, see `stack_analysis`
+pub fn analyze_stack_file(name: &str, assembler_config: &AssemblerConfig) -> Result<(), String> {
+	let path: String = resources::ASSEMBLY_SOURCES_DIR.to_owned() + name;
+	let file_raw = to_string_err(fs::read_to_string(&path))?;
+	match compiler_pipeline(&file_raw, Some(name), assembler_config) {
+		Ok(program) => print!("{}", stack_analysis::analyze(&program, assembler_config).format(&program)),
+		Err(errors) => println!("{}", format_errors(&errors))
+	}
+	Ok(())
+}
+
+/// Formats a file in `resources::ASSEMBLY_SOURCES_DIR` (`-fmt`), with `check` it is only checked and not written
+/// Returns false if the file wasn't formatted already or doesn't parse
+pub fn format_file(name: &str, assembler_config: &AssemblerConfig, check: bool) -> Result<bool, String> {
+	let path: String = resources::ASSEMBLY_SOURCES_DIR.to_owned() + name;
+	let file_raw = to_string_err(fs::read_to_string(&path))?;
+	match format::format_source(&file_raw, Some(name), assembler_config) {
+		Ok(formatted) if formatted == file_raw => Ok(true),
+		Ok(formatted) => {
+			match check {
+				true => println!("{} is not formatted", name),
+				false => to_string_err(fs::write(&path, formatted))?
+			}
+			Ok(false)
+		},
+		Err(errors) => {
+			println!("{}", format_errors(&errors));
+			Ok(false)
 		}
 	}
 }
 
+/// Everything the assembler knows about a program, not just the machine code
+#[derive(Clone, Debug)]
+pub struct Program {
+	/// Machine code
+	pub words: Vec<u16>,
+	/// Where each word came from, same length as `words`
+	pub locations: Vec<SourceLocation>,
+	/// Anchor name -> program address
+	pub anchors: AnchorTable,
+	/// `@const` values and `@var` GPRAM addresses
+	pub symbols: SymbolTable,
+	/// Main source first, then every `@include()`d file, indexed by `SourceLocation::file`
+	pub files: Vec<SourceFile>,
+	/// Code placed at `program_skeleton::GPRAM_PROGRAM_START` or above with `@org()`, loaded into GPRAM by the bootstrap at the start of `words`
+	pub gpram_sections: Vec<GpramSection>,
+	/// What the optimizer did, if `AssemblerConfig::optimize` is set
+	pub optimization_opt: Option<OptimizationReport>,
+	/// Lints that weren't allowed, in source order
+	pub warnings: Vec<CompilerWarning>
+}
+
+/// Instructions starting at program address `address`, which are run from GPRAM
+#[derive(Clone, Debug)]
+pub struct GpramSection {
+	pub address: usize,
+	pub words: Vec<u16>,
+	/// Same length as `words`
+	pub locations: Vec<SourceLocation>
+}
+
+impl Program {
+	/// Name of the main source file
+	pub fn source_name(&self) -> Option<&str> {
+		self.files[0].name.as_deref()
+	}
+	/// Closest anchor at or before `address` and how far `address` is past it
+	pub fn anchor_before(&self, address: u16) -> Option<(&str, usize)> {
+		let mut closest: Option<(&str, usize)> = None;
+		for (name, anchor_address) in &self.anchors {
+			if *anchor_address > address as usize {
+				continue;
+			}
+			let replace: bool = match closest {
+				Some((closest_name, closest_address)) => *anchor_address > closest_address || (*anchor_address == closest_address && name.as_str() < closest_name),
+				None => true
+			};
+			if replace {
+				closest = Some((name, *anchor_address));
+			}
+		}
+		closest.map(|(name, anchor_address)| (name, address as usize - anchor_address))
+	}
+	/// Where the instruction at `address` came from, in flash or GPRAM
+	pub fn location(&self, address: u16) -> Option<&SourceLocation> {
+		match self.locations.get(address as usize) {
+			Some(location) => Some(location),
+			None => self.gpram_sections.iter().find_map(|section| section.locations.get((address as usize).checked_sub(section.address)?))
+		}
+	}
+	/// Instruction at `address`, in flash or GPRAM
+	pub fn word(&self, address: u16) -> Option<u16> {
+		match self.words.get(address as usize) {
+			Some(word) => Some(*word),
+			None => self.gpram_sections.iter().find_map(|section| section.words.get((address as usize).checked_sub(section.address)?).copied())
+		}
+	}
+	/// Source line and closest anchor, for example `tetris:142 (@anchor(drop-piece)+3)`
+	pub fn describe_address(&self, address: u16) -> String {
+		let line: Option<String> = self.location(address).map(|location| match &self.files[location.file].name {
+			Some(name) => format!("{}:{}", name, location.line),
+			None => format!("line {}", location.line)
+		});
+		let anchor: Option<String> = self.anchor_before(address).map(|(name, offset)| match offset {
+			0 => format!("(@anchor({}))", name),
+			offset => format!("(@anchor({})+{})", name, offset)
+		});
+		match (line, anchor) {
+			(Some(line), Some(anchor)) => format!("{} {}", line, anchor),
+			(Some(line), None) => line,
+			(None, Some(anchor)) => anchor,
+			(None, None) => "outside of program".to_owned()
+		}
+	}
+	/// Every `@data()` and `@binfile()` in flash or GPRAM as (first program address, number of instructions, location), so that their cost can be reported
+	pub fn data_blocks(&self) -> Vec<(usize, usize, &SourceLocation)> {
+		let mut out = Vec::<(usize, usize, &SourceLocation)>::new();
+		let gpram_locations = self.gpram_sections.iter().flat_map(|section| section.locations.iter().enumerate().map(|(i, location)| (section.address + i, location)));
+		for (address, location) in self.locations.iter().enumerate().chain(gpram_locations) {
+			if !matches!(location.macro_opt.as_ref().map(|macro_| &macro_.type_), Some(MacroEnum::Data(_) | MacroEnum::BinFile(_))) {
+				continue;
+			}
+			match out.last_mut() {
+				Some((start, len, block_location)) if *block_location == location && *start + *len == address => *len += 1,
+				_ => out.push((address, 1, location))
+			}
+		}
+		// Done
+		out
+	}
+}
+
 /// Main compile function
-pub fn compiler_pipeline(in_: &str, config: &AssemblerConfig) -> Result<Vec<u16>, Vec<CompilerError>> {
+/// `source_name` is used in error messages and source locations, for example "tetris" in `tetris:142`
+/// The source is treated as if it were the file `source_name` in `resources::ASSEMBLY_SOURCES_DIR` for resolving `@include()`s
+pub fn compiler_pipeline(in_: &str, source_name: Option<&str>, config: &AssemblerConfig) -> Result<Program, Vec<CompilerError>> {
+	let source_path: PathBuf = Path::new(resources::ASSEMBLY_SOURCES_DIR).join(source_name.unwrap_or("<input>"));
+	compiler_pipeline_at(in_, source_name, &source_path, config)
+}
+
+/// Same as `compiler_pipeline()`, but `@include()` paths are relative to the directory of `source_path`, which doesn't have to exist
+pub fn compiler_pipeline_at(in_: &str, source_name: Option<&str>, source_path: &Path, config: &AssemblerConfig) -> Result<Program, Vec<CompilerError>> {
 	// First, make vector of chars
 	let source: Vec<char> = in_.chars().collect();
-	// Parse into syntax tree
-	let syntax_tree: SyntaxTreeNode = match SyntaxTreeNode::build_tree(&source) {
-		Ok(tree) => tree,
-		Err(parse_error) => {return Err(vec![CompilerError::from_source_string_index(&source, parse_error.begin, None, CompilerErrorEnum::Parse(parse_error))]);}
-	};
-	// Compile program instructions
-	let token_lines: Vec<(Vec<Token>, usize)> = match program_skeleton_build(&syntax_tree, &source) {
-		Ok(skelet) => skelet,
-		Err(skelet_error) => {return Err(vec![CompilerError::new(None, None, CompilerErrorEnum::ProgramSkeleton(skelet_error))]);}
+	// Parse into syntax tree, keeps going after errors so that everything can be reported at once
+	let mut errors = Vec::<CompilerError>::new();
+	let (syntax_tree, parse_errors) = SyntaxTreeNode::build_tree_recovering(&source);
+	for parse_error in parse_errors {
+		errors.push(CompilerError::from_source_string_index(&source, parse_error.begin, parse_error.end, source_name, None, CompilerErrorEnum::Parse(parse_error)));
+	}
+	// Include other files
+	let mut files: Vec<SourceFile> = vec![SourceFile {
+		name: source_name.map(|name| name.to_owned()),
+		text: in_.to_owned()
+	}];
+	let mut sources: Vec<Vec<char>> = vec![source];
+	let nodes = include::resolve_includes(syntax_tree, source_path, config, &mut files, &mut sources, &mut errors);
+	// Compile program instructions, only what did parse
+	let (skeleton, skelet_errors) = program_skeleton_build(&nodes, &sources, config.optimize);
+	let error_location = |location: &SourceLocation| -> ErrorLocation {
+		ErrorLocation::new(&files[location.file].text, location.span, files[location.file].name.as_deref())
 	};
+	for (skelet_error, location_opt) in skelet_errors {
+		errors.push(CompilerError::new(location_opt.as_ref().map(error_location), None, CompilerErrorEnum::ProgramSkeleton(skelet_error)));
+	}
 	// Assemble, a lot of this is copied from `compiler_pipeline_old()`
-	let mut errors = Vec::<CompilerError>::new();
-	// Split into lines
-	let raw_lines: Vec<&str> = in_.split("\n").collect();
-	// Convienience closure function
-	let mut add_error = |line_index: usize, message_opt: Option<String>, error_enum: CompilerErrorEnum| {
-		errors.push(CompilerError::new(Some((line_index+1, raw_lines[line_index].to_owned())), message_opt, error_enum));
+	let mut assemble = |token_lines: &TokenLines| -> (Vec<u16>, Vec<SourceLocation>) {
+		let mut out = Vec::<u16>::new();
+		let mut locations = Vec::<SourceLocation>::new();
+		for (line, location) in token_lines {
+			match assembly_encode::assemble_instruction(line, config) {
+				Ok(instruction) => {
+					out.push(instruction);
+					locations.push(location.clone());
+				},
+				Err((err_enum, msg, token_i_opt)) => {
+					// Version 1 has fewer words, so say why a word doesn't exist
+					let msg = msg.or_else(|| line.iter().find_map(|token| match &token.enum_ {
+						TokenEnum::AssemblyWord(word) if config.version_2_only_words.contains(&word.to_lowercase()) => Some(format!("`{}` only exists on hardware version 2", word)),
+						_ => None
+					}));
+					// Underline the token that couldn't be encoded if there is one
+					let token_location = SourceLocation {
+						span: match token_i_opt {
+							Some(token_i) => token_span(&files[location.file].text, location, line, token_i),
+							None => location.span
+						},
+						..location.clone()
+					};
+					errors.push(CompilerError::new(Some(error_location(&token_location)), msg, CompilerErrorEnum::Assembly(err_enum)))
+				}
+			}
+		}
+		(out, locations)
 	};
-	let mut out = Vec::<u16>::new();
-	for (line, line_n) in &token_lines {
-		match assembly_encode::assemble_instruction(line, config) {
-			Ok(instruction) => {out.push(instruction);},
-			Err((err_enum, msg)) => add_error(*line_n, msg, CompilerErrorEnum::Assembly(err_enum))
+	// GPRAM code first so that the bootstrap that loads it can be put at the start of flash, the skeleton has already left space for it
+	let mut gpram_sections = Vec::<GpramSection>::new();
+	let mut bootstrap = TokenLines::new();
+	for (address, org_location, token_lines) in &skeleton.gpram_sections {
+		let (words, locations) = assemble(token_lines);
+		let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+		bootstrap.append(&mut load_gpram_instructions(Some(gpram_byte_address(*address) as u16), &bytes, org_location));
+		gpram_sections.push(GpramSection {
+			address: *address,
+			words,
+			locations
+		});
+	}
+	let (mut out, mut locations) = assemble(&bootstrap);
+	let (mut flash_words, mut flash_locations) = assemble(&skeleton.token_lines);
+	out.append(&mut flash_words);
+	locations.append(&mut flash_locations);
+	if config.version == HardwareVersion::V1 {
+		for (_, org_location, _) in &skeleton.gpram_sections {
+			errors.push(CompilerError::new(Some(error_location(org_location)), None, CompilerErrorEnum::ProgramSkeleton(ProgramSkeletonBuildError::GpramCodeUnsupported)));
+		}
+	}
+	// Lints, the bootstrap is generated so it is left out
+	let mut warnings: Vec<CompilerWarning> = lint::lint(skeleton.gpram_sections.iter().flat_map(|(_, _, token_lines)| token_lines).chain(&skeleton.token_lines), &files, config);
+	warnings.sort_by_key(|warning| (files.iter().position(|file| file.name == warning.location.source_name).unwrap_or(0), warning.location.span.begin));
+	if config.deny_warnings {
+		for warning in warnings.drain(..) {
+			errors.push(CompilerError::new(Some(warning.location), None, CompilerErrorEnum::Lint(warning.lint)));
 		}
 	}
 	// Done
-	if errors.len() == 0 {
-		Ok(out)
+	if errors.is_empty() {
+		Ok(Program {
+			words: out,
+			locations,
+			anchors: skeleton.anchors,
+			symbols: skeleton.symbols,
+			files,
+			gpram_sections,
+			optimization_opt: skeleton.optimization_opt,
+			warnings
+		})
 	}
 	else {
+		// In source order, main file first, errors without a location before everything
+		errors.sort_by_key(|error| error.location_opt.as_ref().map(|location| (
+			files.iter().position(|file| file.name == location.source_name).unwrap_or(0),
+			location.span.begin
+		)));
 		Err(errors)
 	}
 }
 
+/// Span of `line[token_i]` in `source`, found by searching for the raw text of each token in order from the start of the instruction
+/// Instructions expanded from macros don't have their tokens in the source, so they get the span of the whole instruction or macro
+fn token_span(source: &str, location: &SourceLocation, line: &[Token], token_i: usize) -> Span {
+	if location.macro_opt.is_some() {
+		return location.span;
+	}
+	let instruction: &str = &source[location.span.begin..location.span.end];
+	let mut begin: usize = 0;
+	for (i, token) in line.iter().enumerate().take(token_i + 1) {
+		match instruction[begin..].find(&token.raw) {
+			Some(offset) => {
+				begin += offset;
+				if i == token_i {
+					return Span::new(location.span.begin + begin, location.span.begin + begin + token.raw.len());
+				}
+				begin += token.raw.len();
+			},
+			None => break
+		}
+	}
+	// Done
+	location.span
+}
+
 /// Turn raw string (probably from assembly source file) into machine code
 /*#[deprecated]
 pub fn compiler_pipeline_old(in_: &str, config: &AssemblerConfig) -> Result<Vec<u16>, Vec<CompilerError>> {
@@ -152,38 +515,58 @@ pub fn compiler_pipeline_old(in_: &str, config: &AssemblerConfig) -> Result<Vec<
 }*/
 
 pub fn compiler_pipeline_formated_errors(in_: &str, config: &AssemblerConfig) -> Result<Vec<u16>, String> {
-	match compiler_pipeline(in_, config) {
-		Ok(program) => Ok(program),
-		Err(errors) => {
-			let mut out = String::new();
-			for error in &errors {
-				out += &error.format();
-			}
-			out += &format!("\nCould not complete assembly due to {} error(s)", errors.len());
-			// Done
-			Err(out)
-		}
+	match compiler_pipeline(in_, None, config) {
+		Ok(program) => Ok(program.words),
+		Err(errors) => Err(format_errors(&errors))
+	}
+}
+
+/// Formats all errors into one message
+pub fn format_errors(errors: &Vec<CompilerError>) -> String {
+	let mut out = String::new();
+	for error in errors {
+		out += &error.format();
 	}
+	out += &format!("\nCould not complete assembly due to {} error(s)", errors.len());
+	// Done
+	out
 }
 
 /// Attempts to run assembler on given file in `assembly_sources`
-pub fn assemble_file(name: &str, assembler_config: &AssemblerConfig) -> Result<(), String> {
+/// Writes `out/<name>.dec_list` and optionally the listing to `out/<name>.lst`
+pub fn assemble_file(name: &str, assembler_config: &AssemblerConfig, write_listing: bool) -> Result<(), String> {
 	let path: String = resources::ASSEMBLY_SOURCES_DIR.to_owned() + name;
 	let file_raw = to_string_err(fs::read_to_string(&path))?;
-	match compiler_pipeline_formated_errors(&file_raw, assembler_config) {
+	match compiler_pipeline(&file_raw, Some(name), assembler_config) {
 		Ok(program) => {
 			// Format list of numbers in decimal
 			let mut num_list = String::new();
-			for (i, n) in program.iter().enumerate() {
+			for (i, n) in program.words.iter().enumerate() {
 				num_list += &format!("{}", n);
-				if i < program.len() - 1 {
+				if i < program.words.len() - 1 {
 					num_list += ",";
 				}
 			}
 			// Put in file
 			to_string_err(fs::write(resources::OUTPUT_DIR.to_owned() + name + ".dec_list", &num_list))?;
+			if write_listing {
+				to_string_err(fs::write(resources::OUTPUT_DIR.to_owned() + name + ".lst", listing::listing(&program, assembler_config)))?;
+			}
+			for warning in &program.warnings {
+				println!("{}\n", warning);
+			}
+			if let Some(report) = &program.optimization_opt {
+				match &report.skipped_opt {
+					Some(reason) => println!("Not optimized: {}", reason),
+					None => println!("Optimizer removed {} redundant write(s) and {} push/pop pair(s), saving {} instructions and {} clock cycles", report.writes_removed, report.push_pop_pairs, report.instructions_saved(), report.cycles_saved())
+				}
+			}
+			// Loading data into GPRAM costs an instruction per byte
+			for (address, len, location) in program.data_blocks() {
+				println!("{} at {:#06X} is {} instructions ({})", location.macro_opt.as_ref().unwrap().format(), address, len, program.describe_address(address as u16));
+			}
 		},
-		Err(s) => println!("{}", s)
+		Err(errors) => println!("{}", format_errors(&errors))
 	}
 	Ok(())
 }
\ No newline at end of file
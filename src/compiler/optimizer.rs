@@ -0,0 +1,189 @@
+//! Optional peephole optimizer (`-optimize`), removes instructions that macro expansion leaves in but that can't change anything
+//! Latches that are only set from the bus are tracked through straight-line code so that a `write` of the value a latch already has can be removed, for example the `goto-b` write of a `@goto` right after another one to the same 256 instructions
+//! A `stack-push` immediately followed by a `stack-pop` is replaced by a single instruction sending the value directly
+//! Every `@anchor` is assumed to be where straight-line code can be jumped into, along with the instruction after a `call`, so nothing is known there
+//! Interrupt handlers are assumed to leave the latches as they were, and jumps to calculated addresses that aren't anchors aren't allowed
+
+use std::collections::HashMap;
+
+use super::assembly_encode::{Token, TokenEnum};
+
+/// Latches that keep the last value written to them until they are written again
+const LATCHES: [&str; 11] = ["alu-a", "alu-b", "alu-c-in", "goto-a", "goto-b", "goto-decider", "gpram-addr-a", "gpram-addr-b", "set-stack-offset", "int-goto-a", "int-goto-b"];
+/// Base clock cycles of a MOVE or WRITE, see `Machine::execute_instruction()`
+const MOVE_WRITE_CLOCK_CYCLES: usize = (5 + 1) * 4;
+
+/// (index of the node in the program skeleton, index of the instruction in its expansion)
+pub type LineId = (usize, usize);
+/// Changes to make to the expanded program
+pub type LineEdits = HashMap<LineId, LineEdit>;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LineEdit {
+	/// `write` of the value the latch already has
+	RemoveWrite,
+	/// Push of a push/pop pair, replaced with an instruction sending the value straight to where the pop sent it
+	ReplacePush(Vec<Token>),
+	/// Pop of a push/pop pair
+	RemovePop
+}
+
+/// Instruction in the order of the source before any edits, for finding edits
+#[derive(Clone, Debug)]
+pub struct StreamLine {
+	pub id: LineId,
+	pub tokens: Vec<Token>,
+	/// Can be jumped to or follows a change of `@org()`, so nothing is known about the latches
+	pub jump_target: bool
+}
+
+/// What the optimizer did, with `-optimize`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OptimizationReport {
+	pub writes_removed: usize,
+	pub push_pop_pairs: usize,
+	/// Why the program wasn't optimized
+	pub skipped_opt: Option<String>
+}
+
+impl OptimizationReport {
+	pub fn new(edits: &LineEdits) -> Self {
+		Self {
+			writes_removed: edits.values().filter(|edit| **edit == LineEdit::RemoveWrite).count(),
+			push_pop_pairs: edits.values().filter(|edit| **edit == LineEdit::RemovePop).count(),
+			skipped_opt: None
+		}
+	}
+	pub fn skipped(reason: &str) -> Self {
+		Self {
+			skipped_opt: Some(reason.to_owned()),
+			..Default::default()
+		}
+	}
+	pub fn instructions_saved(&self) -> usize {
+		self.writes_removed + self.push_pop_pairs
+	}
+	/// Each time every optimized instruction is run once
+	pub fn cycles_saved(&self) -> usize {
+		self.instructions_saved() * MOVE_WRITE_CLOCK_CYCLES
+	}
+}
+
+/// Instruction as far as the optimizer cares
+enum Instruction<'a> {
+	/// (literal, destination)
+	Write(u8, String),
+	/// (source including ALU opcode, source, destination)
+	Move(&'a [Token], String, String),
+	/// `goto-if` and `config-int` don't change any latches or where execution goes next if they don't jump
+	NoEffect,
+	/// Anything else, after which nothing is known
+	Other
+}
+
+impl<'a> Instruction<'a> {
+	fn parse(tokens: &'a [Token]) -> Self {
+		let word = |i: usize| -> Option<String> {
+			match tokens.get(i).map(|token| &token.enum_) {
+				Some(TokenEnum::AssemblyWord(word)) => Some(word.to_lowercase()),
+				_ => None
+			}
+		};
+		match (word(0).as_deref(), tokens.len()) {
+			(Some("write"), 3) => match (&tokens[1].enum_, word(2)) {
+				(TokenEnum::Literal{n, ..}, Some(destination)) => Self::Write(*n, destination),
+				_ => Self::Other
+			},
+			(Some("move"), 3 | 4) => match (word(tokens.len() - 2), word(tokens.len() - 1)) {
+				(Some(source), Some(destination)) => Self::Move(&tokens[1..tokens.len() - 1], source, destination),
+				_ => Self::Other
+			},
+			(Some("goto-if" | "config-int"), _) => Self::NoEffect,
+			_ => Self::Other
+		}
+	}
+}
+
+/// Finds every edit that is valid for the addresses the program currently has
+pub fn find_edits(stream: &[StreamLine]) -> LineEdits {
+	let mut edits = LineEdits::new();
+	let mut known = HashMap::<String, u8>::new();
+	let mut i: usize = 0;
+	while i < stream.len() {
+		let line = &stream[i];
+		if line.jump_target {
+			known.clear();
+		}
+		let instruction = Instruction::parse(&line.tokens);
+		// Push/pop pair
+		let next_opt: Option<&StreamLine> = stream.get(i + 1).filter(|next| !next.jump_target);
+		if let Some(next) = next_opt {
+			let source_opt: Option<Vec<Token>> = match &instruction {
+				Instruction::Write(_, destination) if destination == "stack-push" => Some(line.tokens[0..2].to_vec()),
+				Instruction::Move(source_tokens, _, destination) if destination == "stack-push" => Some([&line.tokens[0..1], *source_tokens].concat()),
+				_ => None
+			};
+			if let (Some(source), Instruction::Move(_, next_source, next_destination)) = (source_opt, Instruction::parse(&next.tokens)) {
+				if next_source == "stack-pop" {
+					let destination_token: Token = next.tokens[next.tokens.len() - 1].clone();
+					edits.insert(line.id, LineEdit::ReplacePush([source, vec![destination_token]].concat()));
+					edits.insert(next.id, LineEdit::RemovePop);
+					if let Instruction::Move(_, source_word, _) = &instruction {
+						forget_destination(&mut known, source_word);
+					}
+					forget_destination(&mut known, &next_destination);
+					i += 2;
+					continue;
+				}
+			}
+		}
+		// Latches
+		match instruction {
+			Instruction::Write(n, destination) => {
+				if LATCHES.contains(&destination.as_str()) {
+					if known.get(&destination) == Some(&n) {
+						edits.insert(line.id, LineEdit::RemoveWrite);
+					}
+					known.insert(destination, n);
+				}
+				else {
+					forget_destination(&mut known, &destination);
+				}
+			},
+			Instruction::Move(_, source, destination) => {
+				forget_destination(&mut known, &source);
+				forget_destination(&mut known, &destination);
+			},
+			Instruction::NoEffect => {},
+			Instruction::Other => known.clear()
+		}
+		i += 1;
+	}
+	// Done
+	edits
+}
+
+/// Edits in both `old` and `new`, keeping push/pop pairs together
+pub fn intersect(old: &LineEdits, new: &LineEdits, stream: &[StreamLine]) -> LineEdits {
+	let mut out: LineEdits = old.iter().filter(|(id, edit)| new.get(id) == Some(edit)).map(|(id, edit)| (*id, edit.clone())).collect();
+	for pair in stream.windows(2) {
+		let (push, pop) = (&pair[0].id, &pair[1].id);
+		let push_kept: bool = matches!(out.get(push), Some(LineEdit::ReplacePush(_)));
+		let pop_kept: bool = out.get(pop) == Some(&LineEdit::RemovePop);
+		if push_kept != pop_kept {
+			out.remove(push);
+			out.remove(pop);
+		}
+	}
+	// Done
+	out
+}
+
+/// A latch written or the GPRAM address changed by something that isn't a known literal
+fn forget_destination(known: &mut HashMap<String, u8>, word: &str) {
+	if word == "gpram-inc-addr" {
+		known.remove("gpram-addr-a");
+		known.remove("gpram-addr-b");
+	}
+	known.remove(word);
+}
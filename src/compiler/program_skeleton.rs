@@ -1,107 +1,489 @@
 //! Program skeleton, created from syntax tree, enable macro expansion
 
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
 use crate::prelude::*;
 
-use super::{assembly_encode::{Token, TokenEnum}, macros::{Macro, MacroArgument,MacroEnum}, syntax_tree::*};
+use super::{Span, anchor::{self, AnchorScope, AnchorTarget}, assembly_encode::{Token, TokenEnum}, expression::Expression, macros::{DataValue, Macro, MacroArgument,MacroEnum}, optimizer::{self, LineEdit, LineEdits, OptimizationReport, StreamLine}, syntax_tree::*};
+
+/// Anchor name -> program address
+pub type AnchorTable = HashMap<String, usize>;
+/// `@const` and `@var` name -> symbol
+pub type SymbolTable = HashMap<String, Symbol>;
+/// Instructions as tokens with where they came from
+pub type TokenLines = Vec<(Vec<Token>, SourceLocation)>;
+
+/// Program addresses from here up fetch instructions from GPRAM on V2 hardware (see README), code is put here with `@org()`
+pub const GPRAM_PROGRAM_START: usize = 0x8000;
+
+/// Skeleton error with where it happened, if it's anywhere specific
+pub type ProgramSkeletonError = (ProgramSkeletonBuildError, Option<SourceLocation>);
+
+/// Everything `program_skeleton_build()` creates
+#[derive(Debug, Default)]
+pub struct ProgramSkeleton {
+	/// Flash, not including the bootstrap that loads `gpram_sections`
+	pub token_lines: TokenLines,
+	/// (program address, location of the `@org()`, instructions) for code at `GPRAM_PROGRAM_START` and up
+	pub gpram_sections: Vec<(usize, SourceLocation, TokenLines)>,
+	pub anchors: AnchorTable,
+	pub symbols: SymbolTable,
+	/// Every instruction in source order before the optimizer changes anything
+	pub stream: Vec<StreamLine>,
+	/// An anchor reference has a `+offset`, so code could be jumped into where there isn't an anchor
+	pub offset_references: bool,
+	/// Only if the optimizer was used
+	pub optimization_opt: Option<OptimizationReport>
+}
+
+/// Named value usable with `lo()` and `hi()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Symbol {
+	/// `@const NAME = <value>`
+	Const(u16),
+	/// `@var name[size]`, address assigned by the allocator
+	Var{address: u16, size: u16}
+}
+
+impl Symbol {
+	pub fn value(&self) -> u16 {
+		match self {
+			Self::Const(value) => *value,
+			Self::Var{address, ..} => *address
+		}
+	}
+}
+
+/// Where an instruction came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+	/// Index into `Program::files`, 0 is the main source and the rest are `@include()`d
+	pub file: usize,
+	/// Whole instruction or macro
+	pub span: Span,
+	/// 1-indexed
+	pub line: usize,
+	/// 1-indexed, in characters
+	pub column: usize,
+	/// Macro that the instruction was expanded from, if any
+	pub macro_opt: Option<Macro>
+}
+
+impl SourceLocation {
+	/// `begin` and `end` are indices of `source`, which is the text of `file`
+	pub fn from_char_indices(source: &Vec<char>, file: usize, i: usize, end: usize) -> Self {
+		let line_start: usize = match source[0..i].iter().rposition(|c: &char| -> bool {*c == '\n'}) {
+			Some(newline_i) => newline_i + 1,
+			None => 0
+		};
+		Self {
+			file,
+			span: Span::from_char_indices(source, i, end),
+			line: line_n_from_index(source, i),
+			column: i - line_start + 1,
+			macro_opt: None
+		}
+	}
+	/// Same location, marked as expanded from `macro_`
+	pub fn expanded_from(&self, macro_: &Macro) -> Self {
+		Self {
+			file: self.file,
+			span: self.span,
+			line: self.line,
+			column: self.column,
+			macro_opt: Some(macro_.clone())
+		}
+	}
+}
 
 /// Construct a more linear representation of the program as opposed to the syntax tree
 /// At this step the final length of the program is now known, which means that @anchor() macros can be assigned addresses and all other macros expanded
-/// `source` is required for finding line numbers
-pub fn program_skeleton_build(tree_root: &SyntaxTreeNode, source: &Vec<char>) -> Result<Vec<(Vec<Token>, usize)>, ProgramSkeletonBuildError> {
-	match &tree_root.type_ {
-		SyntaxTreeNodeType::Program => {
-			// Iterate over tree root's children
-			let mut nodes = Vec::<(ProgramSkeletonNode, usize)>::new();// (node, line number)
-			for tree_node in &tree_root.children {// Top level nodes, right under root
-				match &tree_node.type_ {
-					SyntaxTreeNodeType::Instruction => {
-						// All children of instruction node should be tokens
-						let mut tokens = Vec::<Token>::new();
-						for instruction_child in &tree_node.children {
-							match &instruction_child.type_ {
-								SyntaxTreeNodeType::InstructionToken(token) => tokens.push(token.clone()),
-								invalid => return Err(ProgramSkeletonBuildError::BadSyntaxNodeType(invalid.clone()))
-							}
-						}
-						// Done
-						nodes.push((ProgramSkeletonNode::Instruction(tokens), line_n_from_index(source, tree_node.begin)));
-					},
-					SyntaxTreeNodeType::Macro(macro_) => {nodes.push((ProgramSkeletonNode::Macro(macro_.clone()), line_n_from_index(source, tree_node.begin)));},
-					SyntaxTreeNodeType::Comment => {},
-					invalid => return Err(ProgramSkeletonBuildError::BadSyntaxNodeType(invalid.clone()))
+/// `nodes` are the top level syntax tree nodes of every file with `@include()`s already resolved (see `include::resolve_includes()`), each with the index of the file it came from
+/// `sources` are the texts of those files, required for finding line numbers
+/// Returns the instructions with their source locations, the anchor and symbol tables, and all errors
+/// Errors don't stop the build, anything that caused one is left out so that the rest can still be checked
+/// `optimize` runs the peephole optimizer (see `optimizer`) if there aren't any errors
+pub fn program_skeleton_build(nodes_in: &[(SyntaxTreeNode, usize)], sources: &[Vec<char>], optimize: bool) -> (ProgramSkeleton, Vec<ProgramSkeletonError>) {
+	let mut errors = Vec::<ProgramSkeletonError>::new();
+	// Iterate over tree roots' children
+	let mut nodes = Vec::<(ProgramSkeletonNode, SourceLocation)>::new();
+	for (tree_node, file) in nodes_in {// Top level nodes, right under root
+		let source: &Vec<char> = &sources[*file];
+		match &tree_node.type_ {
+			SyntaxTreeNodeType::Instruction => {
+				// All children of instruction node should be tokens
+				let mut tokens = Vec::<Token>::new();
+				for instruction_child in &tree_node.children {
+					match &instruction_child.type_ {
+						SyntaxTreeNodeType::InstructionToken(token) => tokens.push(token.clone()),
+						invalid => errors.push((ProgramSkeletonBuildError::BadSyntaxNodeType(invalid.clone()), Some(SourceLocation::from_char_indices(source, *file, instruction_child.begin, instruction_child.end))))
+					}
 				}
+				// Done
+				nodes.push((ProgramSkeletonNode::Instruction(tokens), SourceLocation::from_char_indices(source, *file, tree_node.begin, tree_node.end)));
+			},
+			SyntaxTreeNodeType::Macro(macro_) => {nodes.push((ProgramSkeletonNode::Macro(macro_.clone()), SourceLocation::from_char_indices(source, *file, tree_node.begin - 1, tree_node.end)));},// Macro nodes begin after the "@"
+			SyntaxTreeNodeType::Comment => {},
+			invalid => errors.push((ProgramSkeletonBuildError::BadSyntaxNodeType(invalid.clone()), Some(SourceLocation::from_char_indices(source, *file, tree_node.begin, tree_node.end))))
+		}
+	}
+	// User macros then blocks, before anything is counted so that anchor addresses include their expansions
+	let nodes = expand_user_macros(nodes, &mut errors);
+	let nodes = expand_blocks(nodes, &mut errors);
+	// Calculate size
+	let mut size_total: usize = 0;
+	for (node, _) in &nodes {
+		size_total += node.instructions_represented() as usize;
+	}
+	if size_total > POWER_16 {
+		errors.push((ProgramSkeletonBuildError::ProgramTooLarge(size_total), None));
+	}
+	// Macro expansion
+	let mut skeleton = macro_expansion(&nodes, &LineEdits::new(), &mut errors);
+	// Optimizer, removing instructions moves anchors so the program is expanded again with the edits until all of them are still valid with the addresses they cause
+	if optimize && errors.is_empty() {
+		if skeleton.offset_references {
+			skeleton.optimization_opt = Some(OptimizationReport::skipped("anchor references with a +offset can jump to code that isn't at an anchor"));
+		}
+		else {
+			let mut edits: LineEdits = optimizer::find_edits(&skeleton.stream);
+			loop {
+				let mut optimized_errors = Vec::<ProgramSkeletonError>::new();
+				let mut optimized = macro_expansion(&nodes, &edits, &mut optimized_errors);
+				let still_valid: LineEdits = optimizer::intersect(&edits, &optimizer::find_edits(&optimized.stream), &optimized.stream);
+				if still_valid == edits {
+					if optimized_errors.is_empty() {
+						optimized.optimization_opt = Some(OptimizationReport::new(&edits));
+						skeleton = optimized;
+					}
+					break;
+				}
+				edits = still_valid;
 			}
-			// Calculate size
-			let mut size_total: usize = 0;
-			for (node, _) in &nodes {
-				size_total += node.instructions_represented() as usize;
-			}
-			if size_total > POWER_16 {
-				return Err(ProgramSkeletonBuildError::ProgramTooLarge(size_total));
-			}
-			// Macro expansion, done
-			macro_expansion(&nodes)
-		},
-		other => Err(ProgramSkeletonBuildError::BadSyntaxNodeType(other.clone()))
+		}
 	}
+	// Done
+	(skeleton, errors)
 }
 
-fn macro_expansion(nodes: &Vec<(ProgramSkeletonNode, usize)>) -> Result<Vec<(Vec<Token>, usize)>, ProgramSkeletonBuildError> {
-	let mut nodes_2 = Vec::<(ProgramSkeletonNode, usize)>::new();
-	// Compile anchor addresses and delete anchor
-	let mut anchors = HashMap::<String, usize>::new();
-	let mut current_program_address: usize = 0;// Address of final instruction, NOT node list index
-	for (node, line_n) in nodes {
+/// Macros with errors are left out of the output, `edits` from the optimizer are applied to the expanded instructions
+fn macro_expansion(nodes: &[(ProgramSkeletonNode, SourceLocation)], edits: &LineEdits, errors: &mut Vec<ProgramSkeletonError>) -> ProgramSkeleton {
+	// Number of instructions the optimizer removes from each node
+	let mut removed = HashMap::<usize, usize>::new();
+	for ((node_index, _), edit) in edits {
+		if matches!(edit, LineEdit::RemoveWrite | LineEdit::RemovePop) {
+			*removed.entry(*node_index).or_default() += 1;
+		}
+	}
+	let size = |node_index: usize, node: &ProgramSkeletonNode| -> usize {node.instructions_represented() as usize - removed.get(&node_index).copied().unwrap_or(0)};
+	// `@org()` addresses, code in GPRAM is loaded by a bootstrap at the start of flash so its size is needed before any flash addresses are known
+	let consts: HashMap<&str, u16> = nodes.iter().filter_map(|(node, _)| match node {
+		ProgramSkeletonNode::Macro(Macro{type_: MacroEnum::Const{name, value}, ..}) => Some((name.as_str(), *value)),
+		_ => None
+	}).collect();
+	let mut org_addresses = Vec::<Option<usize>>::new();
+	let mut bootstrap_size: usize = 0;
+	let mut in_gpram: bool = false;
+	for (node_index, (node, location)) in nodes.iter().enumerate() {
+		let org_address_opt: Option<usize> = match node {
+			ProgramSkeletonNode::Macro(macro_) if macro_.type_ == MacroEnum::Org => match evaluate_org_address(&macro_.args[0], &consts) {
+				Ok(address) => Some(address),
+				Err(e) => {
+					errors.push((e, Some(location.clone())));
+					None
+				}
+			},
+			_ => None
+		};
+		match org_address_opt {
+			// Setting the GPRAM address
+			Some(address) => {
+				in_gpram = address >= GPRAM_PROGRAM_START;
+				if in_gpram {
+					bootstrap_size += 2;
+				}
+			},
+			// 2 bytes per instruction
+			None => if in_gpram {
+				bootstrap_size += 2 * size(node_index, node);
+			}
+		}
+		org_addresses.push(org_address_opt);
+	}
+	// Each node keeps the anchor scope it was in so that local and anonymous anchors can be resolved
+	let mut nodes_2 = Vec::<(usize, ProgramSkeletonNode, SourceLocation, AnchorScope)>::new();
+	// Indices of nodes that code can be jumped to after, `@anchor()`s and `@org()`s
+	let mut jump_target_nodes = Vec::<usize>::new();
+	let mut offset_references: bool = false;
+	// Compile anchor addresses and delete anchor, symbols are also collected here so they can be used before they are declared
+	let mut anchors = AnchorTable::new();
+	let mut anonymous_anchors = Vec::<usize>::new();
+	let mut scope = AnchorScope::default();
+	let mut symbols = SymbolTable::new();
+	let mut next_var_address: usize = 0;// GPRAM
+	let mut current_program_address: usize = bootstrap_size;// Address of final instruction, NOT node list index
+	// Where each space is filled up to when the other is being used, and the (start, end) of every GPRAM section
+	let (mut flash_end, mut gpram_end): (usize, usize) = (bootstrap_size, GPRAM_PROGRAM_START);
+	let mut gpram_ranges = Vec::<(usize, usize)>::new();
+	// For each valid `@org()` in order, the GPRAM section it starts or None for flash
+	let mut org_targets = Vec::<Option<usize>>::new();
+	in_gpram = false;
+	for (node_index, ((node, location), org_address_opt)) in nodes.iter().zip(&org_addresses).enumerate() {
+		if let (Some(address), ProgramSkeletonNode::Macro(macro_)) = (org_address_opt, node) {
+			match in_gpram {
+				true => {
+					gpram_end = current_program_address;
+					gpram_ranges.last_mut().unwrap().1 = gpram_end;
+				},
+				false => flash_end = current_program_address
+			}
+			in_gpram = *address >= GPRAM_PROGRAM_START;
+			let space_end: usize = match in_gpram {
+				true => gpram_end,
+				false => flash_end
+			};
+			// Sections in the same space can't go backwards, gaps in flash are filled so that the addresses are right
+			if *address < space_end {
+				errors.push((ProgramSkeletonBuildError::OrgOverlap{address: *address, end: space_end}, Some(location.clone())));
+			}
+			else if !in_gpram {
+				for _ in space_end..*address {
+					nodes_2.push((node_index, padding_instruction(), location.expanded_from(macro_), scope.clone()));
+				}
+			}
+			current_program_address = space_end.max(*address);
+			if in_gpram {
+				gpram_ranges.push((current_program_address, current_program_address));
+			}
+			org_targets.push(in_gpram.then_some(current_program_address));
+			jump_target_nodes.push(node_index);
+			nodes_2.push((node_index, node.clone(), location.clone(), scope.clone()));
+			continue;
+		}
 		match node {
 			ProgramSkeletonNode::Instruction(_) => {
-				nodes_2.push((node.clone(), *line_n));
+				nodes_2.push((node_index, node.clone(), location.clone(), scope.clone()));
 			},
 			ProgramSkeletonNode::Macro(macro_) => {
-				match macro_.type_ {
+				match &macro_.type_ {
 					MacroEnum::Anchor => {
+						jump_target_nodes.push(node_index);
 						// Verify that type of macro's first argument is MacroArgument::Identifier(String)
 						match &macro_.args[0] {
-							MacroArgument::Identifier(anchor_name) => {
-								// Check that the anchor name is not already defined
-								if let Some(_) = anchors.get(anchor_name) {
-									return Err(ProgramSkeletonBuildError::AnchorRedefinition(anchor_name.clone()));
-								}
-								// Create new anchor
-								anchors.insert(anchor_name.clone(), current_program_address);
+							MacroArgument::Identifier(anchor_name_raw) => match scope.define(anchor_name_raw) {
+								Ok(Some(anchor_name)) => {
+									// Check that the anchor name is not already defined
+									if let Some(_) = anchors.get(&anchor_name) {
+										errors.push((ProgramSkeletonBuildError::AnchorRedefinition(anchor_name), Some(location.clone())));
+									}
+									// Same namespace in expressions
+									else if symbols.contains_key(&anchor_name) {
+										errors.push((ProgramSkeletonBuildError::SymbolRedefinition(anchor_name), Some(location.clone())));
+									}
+									else {
+										// Create new anchor
+										anchors.insert(anchor_name, current_program_address);
+									}
+								},
+								Ok(None) => anonymous_anchors.push(current_program_address),
+								Err(e) => errors.push((e, Some(location.clone())))
 							},
-							invalid => {return Err(ProgramSkeletonBuildError::MacroArgumentWrongType(invalid.clone()));}
+							invalid => {errors.push((ProgramSkeletonBuildError::MacroArgumentWrongType(invalid.clone()), Some(location.clone())));}
 						}
 					},
-					_ => {nodes_2.push((node.clone(), *line_n));}// Leave all other macros for now
+					MacroEnum::Const{name, value} => {
+						if symbols.contains_key(name) || anchors.contains_key(name) {
+							errors.push((ProgramSkeletonBuildError::SymbolRedefinition(name.clone()), Some(location.clone())));
+						}
+						else {
+							symbols.insert(name.clone(), Symbol::Const(*value));
+						}
+					},
+					MacroEnum::Var{name, size} => {
+						// Allocated one after another from the start of GPRAM
+						if symbols.contains_key(name) || anchors.contains_key(name) {
+							errors.push((ProgramSkeletonBuildError::SymbolRedefinition(name.clone()), Some(location.clone())));
+						}
+						else if next_var_address + *size as usize > POWER_16 {
+							errors.push((ProgramSkeletonBuildError::VarOutOfMemory{name: name.clone(), size: *size, free: POWER_16.saturating_sub(next_var_address)}, Some(location.clone())));
+						}
+						else {
+							symbols.insert(name.clone(), Symbol::Var{address: next_var_address as u16, size: *size});
+							next_var_address += *size as usize;
+						}
+					},
+					// Invalid address, already reported
+					MacroEnum::Org => {},
+					MacroEnum::Call | MacroEnum::Goto | MacroEnum::GotoIf | MacroEnum::PushAnchorAddress | MacroEnum::SetIntGoto => {
+						offset_references |= anchor::has_offset(&macro_.args[0].to_string());
+						nodes_2.push((node_index, node.clone(), location.clone(), scope.clone()));
+					},
+					_ => {nodes_2.push((node_index, node.clone(), location.clone(), scope.clone()));}// Leave all other macros for now
 				}
 			}
 		}
-		current_program_address += node.instructions_represented() as usize;
+		current_program_address += size(node_index, node);
+	}
+	match in_gpram {
+		true => gpram_ranges.last_mut().unwrap().1 = current_program_address,
+		false => flash_end = current_program_address
+	}
+	// Flash and GPRAM code have to fit in their halves of the program address space, and GPRAM code can't be where `@var`s are
+	if let Some((_, last_gpram_end)) = gpram_ranges.last() {
+		if flash_end > GPRAM_PROGRAM_START {
+			errors.push((ProgramSkeletonBuildError::OrgOverlap{address: GPRAM_PROGRAM_START, end: flash_end}, None));
+		}
+		if *last_gpram_end > POWER_16 {
+			errors.push((ProgramSkeletonBuildError::ProgramTooLarge(*last_gpram_end), None));
+		}
+	}
+	for (name, symbol) in &symbols {
+		if let Symbol::Var{address, size} = symbol {
+			let var_range = (*address as usize)..(*address as usize + *size as usize);
+			if gpram_ranges.iter().any(|(start, end)| var_range.start < gpram_byte_address(*end) && gpram_byte_address(*start) < var_range.end) {
+				errors.push((ProgramSkeletonBuildError::GpramCodeOverlapsVar(name.clone()), None));
+			}
+		}
 	}
-	// Expand other macros
-	let mut out = Vec::<(Vec<Token>, usize)>::new();
-	for (node, line_n) in &nodes_2 {
+	// `@data()` and `@binfile()` can fill a `@var` but not go past it, or write over code in GPRAM
+	let data_overlap = |address: u16, len: usize| -> Option<ProgramSkeletonBuildError> {
+		let data_range = (address as usize)..(address as usize + len);
+		let var_opt: Option<&String> = symbols.iter().filter_map(|(name, symbol)| match symbol {
+			Symbol::Var{address, size} => {
+				let var_range = (*address as usize)..(*address as usize + *size as usize);
+				let overlaps: bool = data_range.start < var_range.end && var_range.start < data_range.end;
+				let inside: bool = var_range.start <= data_range.start && data_range.end <= var_range.end;
+				match overlaps && !inside {
+					true => Some(name),
+					false => None
+				}
+			},
+			_ => None
+		}).min();
+		if let Some(name) = var_opt {
+			return Some(ProgramSkeletonBuildError::DataOverlapsVar(name.clone()));
+		}
+		match gpram_ranges.iter().any(|(start, end)| data_range.start < gpram_byte_address(*end) && gpram_byte_address(*start) < data_range.end) {
+			true => Some(ProgramSkeletonBuildError::DataOverlapsGpramCode),
+			false => None
+		}
+	};
+	// Expand other macros, `out` is the current section
+	let mut out = TokenLines::new();
+	let mut flash = TokenLines::new();
+	let mut gpram_sections = Vec::<(usize, SourceLocation, TokenLines)>::new();
+	let mut org_targets = org_targets.into_iter();
+	in_gpram = false;
+	let mut stream = Vec::<StreamLine>::new();
+	let mut jump_target_nodes = jump_target_nodes.into_iter().peekable();
+	let mut jump_target: bool = false;
+	for (node_index, node, location, scope) in &nodes_2 {
+		while jump_target_nodes.next_if(|target_node_index| target_node_index <= node_index).is_some() {
+			jump_target = true;
+		}
+		let lines_before: usize = out.len();
 		match node {
 			ProgramSkeletonNode::Instruction(tokens) => {
-				out.push((tokens.clone(), *line_n));
+				match resolve_expression_tokens(tokens, scope, &symbols, &anchors) {
+					Ok(resolved_tokens) => out.push((resolved_tokens, location.clone())),
+					Err(e) => errors.push((e, Some(location.clone())))
+				}
 			},
 			ProgramSkeletonNode::Macro(macro_) => {
-				match macro_.type_ {
+				let macro_location: SourceLocation = location.expanded_from(macro_);
+				match &macro_.type_ {
 					MacroEnum::Anchor => {
 						panic!("Logic error: Anchor macro encountered during 2nd macro expansion loop")
 					},
+					MacroEnum::Include => {
+						panic!("Logic error: Include macro should have been resolved by `include::resolve_includes()`")
+					},
+					MacroEnum::Define(_) | MacroEnum::EndMacro | MacroEnum::User(_) => {
+						panic!("Logic error: User macros should have been expanded by `expand_user_macros()`")
+					},
+					MacroEnum::Const{..} | MacroEnum::Var{..} => {
+						panic!("Logic error: Declaration encountered during 2nd macro expansion loop")
+					},
+					MacroEnum::If | MacroEnum::Else | MacroEnum::EndIf | MacroEnum::While | MacroEnum::EndWhile => {
+						panic!("Logic error: Block macros should have been expanded by `expand_blocks()`")
+					},
+					MacroEnum::IfDef | MacroEnum::IfNDef => {
+						panic!("Logic error: Conditional assembly should have been resolved by `conditional::select_conditional()`")
+					},
+					MacroEnum::Org => {
+						match (in_gpram, gpram_sections.last_mut()) {
+							(true, Some((_, _, section_lines))) => section_lines.append(&mut out),
+							_ => flash.append(&mut out)
+						}
+						match org_targets.next().unwrap() {
+							Some(address) => {
+								gpram_sections.push((address, macro_location, TokenLines::new()));
+								in_gpram = true;
+							},
+							None => in_gpram = false
+						}
+					},
+					MacroEnum::Data(values) => {
+						let resolve = |name: &str| -> Option<i64> {resolve_symbol(name, scope, &symbols, &anchors)};
+						let address_result = evaluate_gpram_address(&macro_.args[0], &resolve);
+						let mut bytes = Vec::<u8>::new();
+						let mut failed: bool = false;
+						for value in values {
+							match value {
+								DataValue::Expression(expression) => match expression.evaluate_u8(&resolve) {
+									Ok(byte) => bytes.push(byte),
+									Err(e) => {
+										errors.push((e, Some(macro_location.clone())));
+										failed = true;
+									}
+								},
+								DataValue::String(string) => bytes.extend(string.chars().map(|char_| char_ as u8))
+							}
+						}
+						match address_result {
+							Ok(address) => match data_overlap(address, bytes.len()) {
+								Some(e) => errors.push((e, Some(macro_location.clone()))),
+								None => if !failed {
+									out.append(&mut load_gpram_instructions(Some(address), &bytes, &macro_location));
+								}
+							},
+							Err(e) => errors.push((e, Some(macro_location.clone())))
+						}
+					},
+					MacroEnum::BinFile(bytes) => {
+						// Address is optional
+						let address_opt: Option<u16> = match macro_.args.len() {
+							2 => match evaluate_gpram_address(&macro_.args[0], &|name: &str| -> Option<i64> {resolve_symbol(name, scope, &symbols, &anchors)}) {
+								Ok(address) => Some(address),
+								Err(e) => {
+									errors.push((e, Some(macro_location.clone())));
+									continue;
+								}
+							},
+							_ => None
+						};
+						if let Some(e) = address_opt.and_then(|address| data_overlap(address, bytes.len())) {
+							errors.push((e, Some(macro_location.clone())));
+							continue;
+						}
+						out.append(&mut load_gpram_instructions(address_opt, bytes, &macro_location));
+					},
 					MacroEnum::Call => {
-						let anchor_name = macro_.args[0].to_string();
-						out.append(&mut expand_address_set_macro(&anchors, &anchor_name, "call".to_owned(), *line_n)?);
+						match scope.resolve(&macro_.args[0].to_string(), &anchors, &anonymous_anchors) {
+							Ok(address) => out.append(&mut expand_address_set_macro(address as u16, "call".to_owned(), &macro_location)),
+							Err(e) => errors.push((e, Some(macro_location.clone())))
+						}
 					},
 					MacroEnum::Goto => {
-						let anchor_name = macro_.args[0].to_string();
-						out.append(&mut expand_address_set_macro(&anchors, &anchor_name, "goto".to_owned(), *line_n)?);
+						match scope.resolve(&macro_.args[0].to_string(), &anchors, &anonymous_anchors) {
+							Ok(address) => out.append(&mut expand_address_set_macro(address as u16, "goto".to_owned(), &macro_location)),
+							Err(e) => errors.push((e, Some(macro_location.clone())))
+						}
 					},
 					MacroEnum::GotoIf => {
-						let anchor_name = macro_.args[0].to_string();
-						out.append(&mut expand_address_set_macro(&anchors, &anchor_name, "goto-if".to_owned(), *line_n)?);
+						match scope.resolve(&macro_.args[0].to_string(), &anchors, &anonymous_anchors) {
+							Ok(address) => out.append(&mut expand_address_set_macro(address as u16, "goto-if".to_owned(), &macro_location)),
+							Err(e) => errors.push((e, Some(macro_location.clone())))
+						}
 					},
 					MacroEnum::WriteString => {
 						let string_to_write: String = macro_.args[0].to_string();
@@ -113,12 +495,13 @@ fn macro_expansion(nodes: &Vec<(ProgramSkeletonNode, usize)>) -> Result<Vec<(Vec
 									Token::new(TokenEnum::Literal{n: char_ as u8, bit_size: 8}, "<Expanded macro>".to_owned()),
 									Token::new(TokenEnum::AssemblyWord("gpram-inc-addr".to_string()), "<Expanded macro>".to_owned())
 								],
-								*line_n
+								macro_location.clone()
 							));
 						}
 						// Push size to stack
 						if string_to_write.len() >= POWER_16 {
-							return Err(ProgramSkeletonBuildError::MacroWriteStringArgumentTooLong(string_to_write.len()));
+							errors.push((ProgramSkeletonBuildError::MacroWriteStringArgumentTooLong(string_to_write.len()), Some(macro_location.clone())));
+							continue;
 						}
 						let string_size_u16 = string_to_write.len() as u16;
 						out.push((
@@ -127,7 +510,7 @@ fn macro_expansion(nodes: &Vec<(ProgramSkeletonNode, usize)>) -> Result<Vec<(Vec
 								Token::new(TokenEnum::Literal{n: (string_size_u16 & 0x00FF) as u8, bit_size: 8}, "<Expanded macro>".to_owned()),
 								Token::new(TokenEnum::AssemblyWord("stack-push".to_string()), "<Expanded macro>".to_owned())
 							],
-							*line_n
+							macro_location.clone()
 						));
 						out.push((
 							vec![
@@ -135,55 +518,438 @@ fn macro_expansion(nodes: &Vec<(ProgramSkeletonNode, usize)>) -> Result<Vec<(Vec
 								Token::new(TokenEnum::Literal{n: ((string_size_u16 >> 8) & 0x00FF) as u8, bit_size: 8}, "<Expanded macro>".to_owned()),
 								Token::new(TokenEnum::AssemblyWord("stack-push".to_string()), "<Expanded macro>".to_owned())
 							],
-							*line_n
+							macro_location.clone()
 						));
 					},
 					MacroEnum::PushAnchorAddress => {
-						let anchor_name = macro_.args[0].to_string();
-						let address: u16 = match anchors.get(&anchor_name) {
-							Some(prog_address) => (*prog_address as u16).wrapping_sub(1),// TODO
-							None => {return Err(ProgramSkeletonBuildError::MacroInvalidAnchor(anchor_name.to_owned()));}
+						let address: u16 = match scope.resolve(&macro_.args[0].to_string(), &anchors, &anonymous_anchors) {
+							Ok(prog_address) => (prog_address as u16).wrapping_sub(1),// TODO
+							Err(e) => {
+								errors.push((e, Some(macro_location.clone())));
+								continue;
+							}
 						};
 						// GOTO-A
 						out.push((vec![
 							Token::new(TokenEnum::AssemblyWord("write".to_string()), "<Expanded macro>".to_owned()),
 							Token::new(TokenEnum::Literal{n: (address & 0x00FF) as u8, bit_size: 8}, "<Expanded macro>".to_owned()),
 							Token::new(TokenEnum::AssemblyWord("stack-push".to_string()), "<Expanded macro>".to_owned())
-						], *line_n));
+						], macro_location.clone()));
 						// GOTO-B
 						out.push((vec![
 							Token::new(TokenEnum::AssemblyWord("write".to_string()), "<Expanded macro>".to_owned()),
 							Token::new(TokenEnum::Literal{n: ((address >> 8) & 0x00FF) as u8, bit_size: 8}, "<Expanded macro>".to_owned()),
 							Token::new(TokenEnum::AssemblyWord("stack-push".to_string()), "<Expanded macro>".to_owned())
-						], *line_n));
+						], macro_location.clone()));
 					},
-					#[cfg(feature = "version_2")]
 					MacroEnum::SetIntGoto => {
 						// Setup interrupt latches
-						let anchor_name = macro_.args[0].to_string();
-						let address: u16 = match anchors.get(&anchor_name) {
-							Some(prog_address) => (*prog_address as u16).wrapping_sub(1),// TODO
-							None => {return Err(ProgramSkeletonBuildError::MacroInvalidAnchor(anchor_name.to_owned()));}
+						let address: u16 = match scope.resolve(&macro_.args[0].to_string(), &anchors, &anonymous_anchors) {
+							Ok(prog_address) => (prog_address as u16).wrapping_sub(1),// TODO
+							Err(e) => {
+								errors.push((e, Some(macro_location.clone())));
+								continue;
+							}
 						};
 						// INT-GOTO-A
 						out.push((vec![
 							Token::new(TokenEnum::AssemblyWord("write".to_string()), "<Expanded macro>".to_owned()),
 							Token::new(TokenEnum::Literal{n: (address & 0x00FF) as u8, bit_size: 8}, "<Expanded macro>".to_owned()),
 							Token::new(TokenEnum::AssemblyWord("int-goto-a".to_string()), "<Expanded macro>".to_owned())
-						], *line_n));
+						], macro_location.clone()));
 						// INT-GOTO-B
 						out.push((vec![
 							Token::new(TokenEnum::AssemblyWord("write".to_string()), "<Expanded macro>".to_owned()),
 							Token::new(TokenEnum::Literal{n: ((address >> 8) & 0x00FF) as u8, bit_size: 8}, "<Expanded macro>".to_owned()),
 							Token::new(TokenEnum::AssemblyWord("int-goto-b".to_string()), "<Expanded macro>".to_owned())
-						], *line_n));
+						], macro_location.clone()));
+					}
+				}
+			}
+		}
+		// Instructions from this node, before and after the optimizer
+		if out.len() > lines_before {
+			for (line_index, (tokens, _)) in out[lines_before..].iter().enumerate() {
+				stream.push(StreamLine {
+					id: (*node_index, line_index),
+					tokens: tokens.clone(),
+					jump_target: jump_target && line_index == 0
+				});
+			}
+			jump_target = false;
+			for line_index in (0..out.len() - lines_before).rev() {
+				match edits.get(&(*node_index, line_index)) {
+					Some(LineEdit::ReplacePush(tokens)) => out[lines_before + line_index].0 = tokens.clone(),
+					Some(LineEdit::RemoveWrite | LineEdit::RemovePop) => {out.remove(lines_before + line_index);},
+					None => {}
+				}
+			}
+		}
+	}
+	match (in_gpram, gpram_sections.last_mut()) {
+		(true, Some((_, _, section_lines))) => section_lines.append(&mut out),
+		_ => flash.append(&mut out)
+	}
+	// Done
+	ProgramSkeleton {
+		token_lines: flash,
+		gpram_sections,
+		anchors,
+		symbols,
+		stream,
+		offset_references,
+		optimization_opt: None
+	}
+}
+
+/// Value of a name in an expression, `@const`s and `@var`s, then anchors, local anchors (`.loop`) are found the same way as in `@goto()` from `scope`
+fn resolve_symbol(name: &str, scope: &AnchorScope, symbols: &SymbolTable, anchors: &AnchorTable) -> Option<i64> {
+	match symbols.get(name) {
+		Some(symbol) => Some(symbol.value() as i64),
+		None => match scope.target(name) {
+			Ok((AnchorTarget::Named(anchor_name), 0)) => anchors.get(&anchor_name).map(|address| *address as i64),
+			_ => None
+		}
+	}
+}
+
+/// Evaluates expressions into 8-bit literals
+fn resolve_expression_tokens(tokens: &[Token], scope: &AnchorScope, symbols: &SymbolTable, anchors: &AnchorTable) -> Result<Vec<Token>, ProgramSkeletonBuildError> {
+	let resolve = |name: &str| -> Option<i64> {resolve_symbol(name, scope, symbols, anchors)};
+	tokens.iter().map(|token| match &token.enum_ {
+		TokenEnum::Expression(expression) => Ok(Token::new(TokenEnum::Literal{n: expression.evaluate_u8(&resolve)?, bit_size: 8}, token.raw.clone())),
+		_ => Ok(token.clone())
+	}).collect()
+}
+
+/// `@macro <name>(<params>) ... @endmacro`
+struct UserMacroDefinition {
+	params: Vec<String>,
+	body: Vec<ProgramSkeletonNode>
+}
+
+/// Takes `@macro` definitions out of `nodes` and replaces every use of one with its body, with the arguments substituted for the parameters
+/// Definitions can be anywhere in the program, including in other files, but can't be nested
+fn expand_user_macros(nodes: Vec<(ProgramSkeletonNode, SourceLocation)>, errors: &mut Vec<ProgramSkeletonError>) -> Vec<(ProgramSkeletonNode, SourceLocation)> {
+	// Collect definitions
+	let mut definitions = HashMap::<String, UserMacroDefinition>::new();
+	let mut outside_definitions = Vec::<(ProgramSkeletonNode, SourceLocation)>::new();
+	let mut current_definition_opt: Option<(String, UserMacroDefinition, SourceLocation)> = None;
+	for (node, location) in nodes {
+		let macro_type_opt: Option<&MacroEnum> = match &node {
+			ProgramSkeletonNode::Macro(macro_) => Some(&macro_.type_),
+			ProgramSkeletonNode::Instruction(_) => None
+		};
+		match macro_type_opt {
+			Some(MacroEnum::Define(name)) => {
+				if current_definition_opt.is_some() {
+					errors.push((ProgramSkeletonBuildError::MacroDefinitionNested(name.clone()), Some(location)));
+					continue;
+				}
+				let mut params = Vec::<String>::new();
+				if let ProgramSkeletonNode::Macro(macro_) = &node {
+					if let Err(e) = macro_.type_.args_correct_type(&macro_.args) {
+						errors.push((e, Some(location.clone())));
 					}
+					params = macro_.args.iter().map(|arg| arg.to_string()).collect();
 				}
+				current_definition_opt = Some((name.clone(), UserMacroDefinition{params, body: Vec::new()}, location));
+			},
+			Some(MacroEnum::EndMacro) => match current_definition_opt.take() {
+				Some((name, definition, definition_location)) => {
+					if MacroEnum::match_identifier(&name, 0, 0).map(|type_| !matches!(type_, MacroEnum::User(_))).unwrap_or(true) || definitions.contains_key(&name) {
+						errors.push((ProgramSkeletonBuildError::MacroRedefinition(name), Some(definition_location)));
+					}
+					else {
+						definitions.insert(name, definition);
+					}
+				},
+				None => errors.push((ProgramSkeletonBuildError::MacroEndWithoutDefinition, Some(location)))
+			},
+			_ => match &mut current_definition_opt {
+				Some((_, definition, _)) => definition.body.push(node),
+				None => outside_definitions.push((node, location))
 			}
 		}
 	}
+	if let Some((name, _, definition_location)) = current_definition_opt {
+		errors.push((ProgramSkeletonBuildError::MacroDefinitionUnterminated(name), Some(definition_location)));
+	}
+	// Expand
+	let mut out = Vec::<(ProgramSkeletonNode, SourceLocation)>::new();
+	let mut n_expansions: usize = 0;
+	for (node, location) in outside_definitions {
+		expand_user_macro_node(node, location, &definitions, &mut Vec::new(), &mut n_expansions, &mut out, errors);
+	}
 	// Done
-	Ok(out)
+	out
+}
+
+/// Adds `node` to `out`, or its expansion if it is a user macro, recursively
+/// `stack` is the names of the macros currently being expanded, so that recursion can't go on forever
+/// Anchors defined in the body are renamed to `%<macro name>-<n>.<anchor name>` for each use (`n` counts `n_expansions`), so that using the macro more than once doesn't define them again
+fn expand_user_macro_node(
+	node: ProgramSkeletonNode,
+	location: SourceLocation,
+	definitions: &HashMap<String, UserMacroDefinition>,
+	stack: &mut Vec<String>,
+	n_expansions: &mut usize,
+	out: &mut Vec<(ProgramSkeletonNode, SourceLocation)>,
+	errors: &mut Vec<ProgramSkeletonError>
+) {
+	let macro_: &Macro = match &node {
+		ProgramSkeletonNode::Macro(macro_) if matches!(macro_.type_, MacroEnum::User(_)) => macro_,
+		_ => {
+			out.push((node, location));
+			return;
+		}
+	};
+	let name: &str = macro_.type_.name();
+	let definition: &UserMacroDefinition = match definitions.get(name) {
+		Some(definition) => definition,
+		None => {
+			errors.push((ProgramSkeletonBuildError::MacroUndefined(name.to_owned()), Some(location)));
+			return;
+		}
+	};
+	if definition.params.len() != macro_.args.len() {
+		errors.push((ProgramSkeletonBuildError::MacroWrongNumArgs{name: name.to_owned(), actual: macro_.args.len(), correct: definition.params.len()}, Some(location)));
+		return;
+	}
+	if stack.iter().any(|stack_name| stack_name == name) {
+		errors.push((ProgramSkeletonBuildError::MacroRecursion(name.to_owned()), Some(location)));
+		return;
+	}
+	// Expanded instructions are from the outermost macro use
+	let body_location: SourceLocation = match location.macro_opt {
+		Some(_) => location.clone(),
+		None => location.expanded_from(macro_)
+	};
+	// Anchors to rename, anonymous and already qualified anchors are left as they are
+	let expansion_name: String = anchor::generated_anchor_name(name, *n_expansions);
+	*n_expansions += 1;
+	let anchor_renames: Vec<(String, String)> = definition.body.iter().filter_map(|body_node| match body_node {
+		ProgramSkeletonNode::Macro(Macro{type_: MacroEnum::Anchor, args}) => match args.first() {
+			Some(MacroArgument::Identifier(anchor_name)) => {
+				let label: &str = anchor_name.strip_prefix(anchor::LOCAL_ANCHOR_BEGIN).unwrap_or(anchor_name);
+				match !label.is_empty() && label.chars().all(|c| IDENTIFIER_CHARS.contains(&c)) {
+					true => Some((anchor_name.clone(), format!("{}{}{}", expansion_name, anchor::LOCAL_ANCHOR_BEGIN, label))),
+					false => None
+				}
+			},
+			_ => None
+		},
+		_ => None
+	}).collect();
+	stack.push(name.to_owned());
+	for body_node in &definition.body {
+		let body_node: ProgramSkeletonNode = rename_anchors(body_node, &anchor_renames);
+		expand_user_macro_node(substitute_macro_args(&body_node, &definition.params, &macro_.args), body_location.clone(), definitions, stack, n_expansions, out, errors);
+	}
+	stack.pop();
+}
+
+/// Replaces anchor names in macro arguments (including ones with a `+offset`) and expressions, `renames` is (old name, new name)
+fn rename_anchors(node: &ProgramSkeletonNode, renames: &[(String, String)]) -> ProgramSkeletonNode {
+	if renames.is_empty() {
+		return node.clone();
+	}
+	let new_name_for = |old_name: &str| -> Option<&String> {
+		renames.iter().find(|(rename_from, _)| rename_from == old_name).map(|(_, rename_to)| rename_to)
+	};
+	let rename_expression = |expression: &Expression| -> Expression {
+		expression.map_names(&|name: &str| -> Expression {
+			Expression::Name(new_name_for(name).cloned().unwrap_or_else(|| name.to_owned()))
+		})
+	};
+	match node {
+		ProgramSkeletonNode::Instruction(tokens) => ProgramSkeletonNode::Instruction(tokens.iter().map(|token| match &token.enum_ {
+			TokenEnum::Expression(expression) => {
+				let renamed = rename_expression(expression);
+				Token::new(TokenEnum::Expression(renamed.clone()), renamed.to_string())
+			},
+			_ => token.clone()
+		}).collect()),
+		ProgramSkeletonNode::Macro(macro_) => ProgramSkeletonNode::Macro(Macro {
+			type_: match &macro_.type_ {
+				MacroEnum::Data(values) => MacroEnum::Data(values.iter().map(|value| match value {
+					DataValue::Expression(expression) => DataValue::Expression(rename_expression(expression)),
+					DataValue::String(_) => value.clone()
+				}).collect()),
+				type_ => type_.clone()
+			},
+			args: macro_.args.iter().map(|arg| match arg {
+				MacroArgument::Identifier(word) if !word.starts_with(anchor::ANONYMOUS_ANCHOR) => {
+					let (target, offset) = word.split_at(word.find(anchor::ANCHOR_OFFSET_BEGIN).unwrap_or(word.len()));
+					match new_name_for(target) {
+						Some(new_name) => MacroArgument::Identifier(format!("{}{}", new_name, offset)),
+						None => arg.clone()
+					}
+				},
+				_ => arg.clone()
+			}).collect()
+		})
+	}
+}
+
+/// Replaces instruction tokens and macro arguments that are the name of a parameter with the corresponding argument
+fn substitute_macro_args(node: &ProgramSkeletonNode, params: &[String], args: &[MacroArgument]) -> ProgramSkeletonNode {
+	let arg_for = |word: &str| -> Option<&MacroArgument> {
+		params.iter().position(|param| param == word).map(|i| &args[i])
+	};
+	let substitute_expression = |expression: &Expression| -> Expression {
+		expression.map_names(&|name: &str| -> Expression {
+			match arg_for(name) {
+				Some(arg) => Expression::parse_str(&arg.to_string()).unwrap_or(Expression::Name(arg.to_string())),
+				None => Expression::Name(name.to_owned())
+			}
+		})
+	};
+	match node {
+		ProgramSkeletonNode::Instruction(tokens) => ProgramSkeletonNode::Instruction(tokens.iter().map(|token| match &token.enum_ {
+			TokenEnum::AssemblyWord(word) => match arg_for(word) {
+				Some(arg) => macro_argument_to_token(arg),
+				None => token.clone()
+			},
+			TokenEnum::Expression(expression) => {
+				let substituted = substitute_expression(expression);
+				Token::new(TokenEnum::Expression(substituted.clone()), substituted.to_string())
+			},
+			TokenEnum::Literal{..} => token.clone()
+		}).collect()),
+		ProgramSkeletonNode::Macro(macro_) => ProgramSkeletonNode::Macro(Macro {
+			type_: match &macro_.type_ {
+				MacroEnum::Data(values) => MacroEnum::Data(values.iter().map(|value| match value {
+					DataValue::Expression(expression) => DataValue::Expression(substitute_expression(expression)),
+					DataValue::String(_) => value.clone()
+				}).collect()),
+				type_ => type_.clone()
+			},
+			args: macro_.args.iter().map(|arg| match arg {
+				MacroArgument::Identifier(word) => arg_for(word).unwrap_or(arg).clone(),
+				MacroArgument::StringLiteral(_) => arg.clone()
+			}).collect()
+		})
+	}
+}
+
+/// Replaces `@if`/`@else`/`@endif` and `@while`/`@endwhile` with GOTOs to generated anchors named `%if-<n>.<label>` and `%while-<n>.<label>`, which are local anchors so they don't change the scope of the program's own local anchors
+/// Nesting has already been checked by the parser (see `syntax_tree::check_block_nesting()`)
+/// ```text
+/// @if(<args>)                 @while(<args>)
+///     move <args> goto-decider    @anchor(%while-n.start)
+///     @goto_if(%if-n.then)        move <args> goto-decider
+///     @goto(%if-n.else)           @goto_if(%while-n.body)
+///     @anchor(%if-n.then)         @goto(%while-n.end)
+/// ...                             @anchor(%while-n.body)
+/// @else                       ...
+///     @goto(%if-n.end)        @endwhile
+///     @anchor(%if-n.else)         @goto(%while-n.start)
+/// ...                             @anchor(%while-n.end)
+/// @endif
+///     @anchor(%if-n.end)
+/// ```
+fn expand_blocks(nodes: Vec<(ProgramSkeletonNode, SourceLocation)>, errors: &mut Vec<ProgramSkeletonError>) -> Vec<(ProgramSkeletonNode, SourceLocation)> {
+	let mut out = Vec::<(ProgramSkeletonNode, SourceLocation)>::new();
+	// Blocks that are open, (generated anchor name, is `@if`, has an `@else` yet)
+	let mut open = Vec::<(String, bool, bool)>::new();
+	let mut n_blocks: usize = 0;
+	for (node, location) in nodes {
+		let macro_: &Macro = match &node {
+			ProgramSkeletonNode::Macro(macro_) => macro_,
+			ProgramSkeletonNode::Instruction(_) => {
+				out.push((node, location));
+				continue;
+			}
+		};
+		let mut push = |new_node: ProgramSkeletonNode| out.push((new_node, location.clone()));
+		match &macro_.type_ {
+			MacroEnum::If | MacroEnum::While => {
+				let args_result = match macro_.args.len() {
+					0..=2 => macro_.type_.args_correct_type(&macro_.args),
+					n_args => Err(ProgramSkeletonBuildError::MacroWrongNumArgs{name: macro_.type_.name().to_owned(), actual: n_args, correct: 2})
+				};
+				let is_if: bool = macro_.type_ == MacroEnum::If;
+				let name: String = anchor::generated_anchor_name(macro_.type_.name(), n_blocks);
+				n_blocks += 1;
+				if !is_if {
+					push(block_anchor_macro(MacroEnum::Anchor, &name, "start"));
+				}
+				// Load goto decider, arguments are the ALU opcode and/or bus source
+				if let Err(e) = args_result {
+					errors.push((e, Some(location.clone())));
+				}
+				else if !macro_.args.is_empty() {
+					let mut tokens: Vec<Token> = vec![Token::new(TokenEnum::AssemblyWord("move".to_owned()), "<Expanded macro>".to_owned())];
+					tokens.extend(macro_.args.iter().map(|arg| Token::new(TokenEnum::AssemblyWord(arg.to_string()), arg.to_string())));
+					tokens.push(Token::new(TokenEnum::AssemblyWord("goto-decider".to_owned()), "<Expanded macro>".to_owned()));
+					push(ProgramSkeletonNode::Instruction(tokens));
+				}
+				let (then_label, else_label): (&str, &str) = match is_if {
+					true => ("then", "else"),
+					false => ("body", "end")
+				};
+				push(block_anchor_macro(MacroEnum::GotoIf, &name, then_label));
+				push(block_anchor_macro(MacroEnum::Goto, &name, else_label));
+				push(block_anchor_macro(MacroEnum::Anchor, &name, then_label));
+				open.push((name, is_if, false));
+			},
+			MacroEnum::Else => {
+				// A second `@else` has already been reported by the parser
+				if let Some((name, true, has_else @ false)) = open.last_mut() {
+					push(block_anchor_macro(MacroEnum::Goto, name, "end"));
+					push(block_anchor_macro(MacroEnum::Anchor, name, "else"));
+					*has_else = true;
+				}
+			},
+			MacroEnum::EndIf | MacroEnum::EndWhile => {
+				// Blocks opened after the one being ended have already been reported by the parser, they are closed here anyway so that their anchors don't cause more errors
+				let is_if: bool = macro_.type_ == MacroEnum::EndIf;
+				if let Some(block_i) = open.iter().rposition(|(_, open_is_if, _)| *open_is_if == is_if) {
+					for block in open.drain(block_i..).rev() {
+						close_block(&block).into_iter().for_each(&mut push);
+					}
+				}
+			},
+			_ => out.push((node, location))
+		}
+	}
+	// Unterminated, also already reported
+	if let Some((_, location)) = out.last().cloned() {
+		for block in open.iter().rev() {
+			out.extend(close_block(block).into_iter().map(|node| (node, location.clone())));
+		}
+	}
+	// Done
+	out
+}
+
+/// Nodes for the end of a block in `expand_blocks()`
+fn close_block((name, is_if, has_else): &(String, bool, bool)) -> Vec<ProgramSkeletonNode> {
+	match (is_if, has_else) {
+		(true, true) => vec![block_anchor_macro(MacroEnum::Anchor, name, "end")],
+		(true, false) => vec![block_anchor_macro(MacroEnum::Anchor, name, "else"), block_anchor_macro(MacroEnum::Anchor, name, "end")],
+		(false, _) => vec![block_anchor_macro(MacroEnum::Goto, name, "start"), block_anchor_macro(MacroEnum::Anchor, name, "end")]
+	}
+}
+
+/// `@<type_>(<block_name>.<label>)`, for `expand_blocks()`
+fn block_anchor_macro(type_: MacroEnum, block_name: &str, label: &str) -> ProgramSkeletonNode {
+	ProgramSkeletonNode::Macro(Macro {
+		type_,
+		args: vec![MacroArgument::Identifier(format!("{}.{}", block_name, label))]
+	})
+}
+
+/// Parses a macro argument the same way as an instruction token, so that literals like `0x42` work
+fn macro_argument_to_token(arg: &MacroArgument) -> Token {
+	let raw: String = arg.to_string();
+	let mut source: Vec<char> = raw.chars().collect();
+	source.push(';');
+	match ParseContext::InstructionToken.parse(&source, 0) {
+		Ok(SyntaxTreeNode{type_: SyntaxTreeNodeType::InstructionToken(token), end, ..}) if end == raw.chars().count() => token,
+		_ => Token::new(TokenEnum::AssemblyWord(raw.clone()), raw)// Encoding will report it as invalid
+	}
 }
 
 #[derive(Clone)]
@@ -210,12 +976,82 @@ pub enum ProgramSkeletonBuildError {
 	MacroArgumentWrongType(MacroArgument),
 	MacroInvalidAnchor(String),
 	MacroWriteStringArgumentTooLong(usize),
-	AnchorRedefinition(String)
+	AnchorRedefinition(String),
+	AnchorNameInvalid(String),
+	AnchorReferenceInvalid(String),
+	AnonymousAnchorMissing(String),
+	MacroUndefined(String),
+	MacroRedefinition(String),
+	MacroWrongNumArgs{name: String, actual: usize, correct: usize},
+	MacroRecursion(String),
+	MacroDefinitionNested(String),
+	MacroDefinitionUnterminated(String),
+	MacroEndWithoutDefinition,
+	/// `@const`, `@var` or anchor
+	SymbolUndefined(String),
+	ExpressionOverflow(String),
+	LiteralOutOfRange{expression: String, value: i64},
+	SymbolRedefinition(String),
+	VarOutOfMemory{name: String, size: u16, free: usize},
+	GpramAddressOutOfRange{expression: String, value: i64},
+	ProgramAddressOutOfRange{expression: String, value: i64},
+	/// `@org()` to before the end of the code already in that part of the program address space
+	OrgOverlap{address: usize, end: usize},
+	GpramCodeOverlapsVar(String),
+	/// `@data()` or `@binfile()` that writes some of a `@var` and some of what's around it
+	DataOverlapsVar(String),
+	DataOverlapsGpramCode,
+	/// Code placed in GPRAM when assembling for V1 hardware, which can't run it
+	GpramCodeUnsupported
 }
 
+impl fmt::Display for ProgramSkeletonBuildError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::ProgramTooLarge(size) => write!(f, "Program is {} instructions, the maximum is {}", size, PROG_MAX_INSTRUCTIONS),
+			Self::BadSyntaxNodeType(type_) => write!(f, "Unexpected syntax tree node {:?}", type_),
+			Self::MacroArgumentWrongType(arg) => write!(f, "Macro argument \"{}\" is the wrong type", arg.to_string()),
+			Self::MacroInvalidAnchor(name) => write!(f, "Anchor \"{}\" is not defined", name),
+			Self::MacroWriteStringArgumentTooLong(len_) => write!(f, "String is {} characters, too long to write", len_),
+			Self::AnchorRedefinition(name) => write!(f, "Anchor \"{}\" is already defined", name),
+			Self::AnchorNameInvalid(name) => write!(f, "Invalid anchor name \"{}\", must be a name, .local-name or :", name),
+			Self::AnchorReferenceInvalid(reference) => write!(f, "Invalid anchor reference \"{}\", must be a name, .local-name, :+ or :- with an optional +offset", reference),
+			Self::AnonymousAnchorMissing(reference) => write!(f, "There is no anonymous anchor for \"{}\"", reference),
+			Self::MacroUndefined(name) => write!(f, "Unknown macro \"{}{}\"", MACRO_BEGIN, name),
+			Self::MacroRedefinition(name) => write!(f, "Macro \"{}{}\" is already defined", MACRO_BEGIN, name),
+			Self::MacroWrongNumArgs{name, actual, correct} => write!(f, "Macro \"{}{}\" takes {} argument(s) but was given {}", MACRO_BEGIN, name, correct, actual),
+			Self::MacroRecursion(name) => write!(f, "Macro \"{}{}\" uses itself", MACRO_BEGIN, name),
+			Self::MacroDefinitionNested(name) => write!(f, "Macro \"{}{}\" is defined inside of another macro definition", MACRO_BEGIN, name),
+			Self::MacroDefinitionUnterminated(name) => write!(f, "Macro definition \"{}{}\" has no {}endmacro", MACRO_BEGIN, name, MACRO_BEGIN),
+			Self::MacroEndWithoutDefinition => write!(f, "{}endmacro without {}macro", MACRO_BEGIN, MACRO_BEGIN),
+			Self::SymbolUndefined(name) => write!(f, "\"{}\" is not a {}const, {}var or anchor", name, MACRO_BEGIN, MACRO_BEGIN),
+			Self::ExpressionOverflow(expression) => write!(f, "Overflow in {}", expression),
+			Self::LiteralOutOfRange{expression, value} => write_out_of_range(f, expression, *value, "doesn't fit in 8 bits"),
+			Self::SymbolRedefinition(name) => write!(f, "\"{}\" is already declared", name),
+			Self::VarOutOfMemory{name, size, free} => write!(f, "Not enough GPRAM for \"{}\", it is {} byte(s) and only {} are left", name, size, free),
+			Self::GpramAddressOutOfRange{expression, value} => write_out_of_range(f, expression, *value, "is not a GPRAM address"),
+			Self::ProgramAddressOutOfRange{expression, value} => write_out_of_range(f, expression, *value, "is not a program address"),
+			Self::OrgOverlap{address, end} => write!(f, "Code at {:#06X} overlaps code before it, which ends at {:#06X}", address, end),
+			Self::GpramCodeOverlapsVar(name) => write!(f, "Code in GPRAM overlaps {}var \"{}\"", MACRO_BEGIN, name),
+			Self::DataOverlapsVar(name) => write!(f, "Data goes past the edge of {}var \"{}\"", MACRO_BEGIN, name),
+			Self::DataOverlapsGpramCode => write!(f, "Data overlaps code in GPRAM"),
+			Self::GpramCodeUnsupported => write!(f, "Hardware version 1 can't run code from GPRAM")
+		}
+	}
+}
+
+impl std::error::Error for ProgramSkeletonBuildError {}
+
+/// "<value> <problem>", or "<expression> is <value>, which <problem>" if the expression isn't just the number
+fn write_out_of_range(f: &mut fmt::Formatter<'_>, expression: &str, value: i64, problem: &str) -> fmt::Result {
+	match expression == value.to_string() {
+		true => write!(f, "{} {}", value, problem),
+		false => write!(f, "{} is {}, which {}", expression, value, problem)
+	}
+}
 
 /// Creates 2 instructions to load `address` into the goto latches, takes care of subtracting 1 from it to compensate for the computer hardware incrementing it
-fn load_goto_instructions(address_og: u16, line_n: usize, for_interrupt: bool) -> Vec<(Vec<Token>, usize)> {
+fn load_goto_instructions(address_og: u16, location: &SourceLocation, for_interrupt: bool) -> TokenLines {
 	let registers: (&str, &str) = match for_interrupt {
 		true => ("int-goto-a", "int-goto-b"),
 		false => ("goto-a", "goto-b")
@@ -234,22 +1070,77 @@ fn load_goto_instructions(address_og: u16, line_n: usize, for_interrupt: bool) -
 		Token::new(TokenEnum::AssemblyWord(registers.1.to_string()), "<Expanded macro>".to_owned())
 	];
 	// Done
-	vec![(goto_a, line_n), (goto_b, line_n)]
+	vec![(goto_a, location.clone()), (goto_b, location.clone())]
 }
 
-/// For expanding call, goto, and goto-f
-fn expand_address_set_macro(anchors: &HashMap<String, usize>, anchor_name: &str, assembly_word: String, line_n: usize) -> Result<Vec<(Vec<Token>, usize)>, ProgramSkeletonBuildError> {
-	match anchors.get(anchor_name) {
-		Some(prog_address) => {
-			// Expand macro into vec of instructions
-			let mut out: Vec<(Vec<Token>, usize)> = load_goto_instructions(*prog_address as u16, line_n, false);
-			// Call instruction
-			out.push((vec![
-				Token::new(TokenEnum::AssemblyWord(assembly_word), "<Expanded macro>".to_owned())
-			], line_n));
-			// Done
-			Ok(out)
+/// GPRAM address of the lower byte of the instruction at `prog_address`, which must be at least `GPRAM_PROGRAM_START`
+pub fn gpram_byte_address(prog_address: usize) -> usize {
+	(prog_address - GPRAM_PROGRAM_START) * 2
+}
+
+/// Address argument of `@org()`, a number or `@const`
+fn evaluate_org_address(arg: &MacroArgument, consts: &HashMap<&str, u16>) -> Result<usize, ProgramSkeletonBuildError> {
+	let expression: Expression = match Expression::parse_str(&arg.to_string()) {
+		Some(expression) => expression,
+		None => {return Err(ProgramSkeletonBuildError::MacroArgumentWrongType(arg.clone()));}
+	};
+	match expression.evaluate(&|name: &str| -> Option<i64> {consts.get(name).map(|value| *value as i64)})? {
+		value @ 0..=0xFFFF => Ok(value as usize),
+		value => Err(ProgramSkeletonBuildError::ProgramAddressOutOfRange{expression: expression.to_string(), value})
+	}
+}
+
+/// Fills gaps left in flash by `@org()`
+fn padding_instruction() -> ProgramSkeletonNode {
+	ProgramSkeletonNode::Instruction(vec![
+		Token::new(TokenEnum::AssemblyWord("write".to_string()), "<Expanded macro>".to_owned()),
+		Token::new(TokenEnum::Literal{n: 0, bit_size: 8}, "<Expanded macro>".to_owned()),
+		Token::new(TokenEnum::AssemblyWord("none".to_string()), "<Expanded macro>".to_owned())
+	])
+}
+
+/// Address argument of `@data()` or `@binfile()`, a number, `@var` or expression like `buffer+4`
+fn evaluate_gpram_address(arg: &MacroArgument, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<u16, ProgramSkeletonBuildError> {
+	let expression: Expression = match arg {
+		MacroArgument::Identifier(raw) => match Expression::parse_str(raw) {
+			Some(expression) => expression,
+			None => {return Err(ProgramSkeletonBuildError::MacroArgumentWrongType(arg.clone()));}
 		},
-		None => Err(ProgramSkeletonBuildError::MacroInvalidAnchor(anchor_name.to_owned()))
+		MacroArgument::StringLiteral(_) => {return Err(ProgramSkeletonBuildError::MacroArgumentWrongType(arg.clone()));}
+	};
+	match expression.evaluate(resolve)? {
+		value @ 0..=0xFFFF => Ok(value as u16),
+		value => Err(ProgramSkeletonBuildError::GpramAddressOutOfRange{expression: expression.to_string(), value})
 	}
+}
+
+/// Writes `bytes` to GPRAM with `gpram-inc-addr`, starting at `address_opt` or wherever the GPRAM address is if it's None
+pub fn load_gpram_instructions(address_opt: Option<u16>, bytes: &[u8], location: &SourceLocation) -> TokenLines {
+	let write = |n: u8, to: &str| -> (Vec<Token>, SourceLocation) {
+		(vec![
+			Token::new(TokenEnum::AssemblyWord("write".to_string()), "<Expanded macro>".to_owned()),
+			Token::new(TokenEnum::Literal{n, bit_size: 8}, "<Expanded macro>".to_owned()),
+			Token::new(TokenEnum::AssemblyWord(to.to_string()), "<Expanded macro>".to_owned())
+		], location.clone())
+	};
+	let mut out = TokenLines::new();
+	if let Some(address) = address_opt {
+		out.push(write((address & 0x00FF) as u8, "gpram-addr-a"));
+		out.push(write((address >> 8) as u8, "gpram-addr-b"));
+	}
+	out.extend(bytes.iter().map(|byte| write(*byte, "gpram-inc-addr")));
+	// Done
+	out
+}
+
+/// For expanding call, goto, and goto-f
+fn expand_address_set_macro(prog_address: u16, assembly_word: String, location: &SourceLocation) -> TokenLines {
+	// Expand macro into vec of instructions
+	let mut out: TokenLines = load_goto_instructions(prog_address, location, false);
+	// Call instruction
+	out.push((vec![
+		Token::new(TokenEnum::AssemblyWord(assembly_word), "<Expanded macro>".to_owned())
+	], location.clone()));
+	// Done
+	out
 }
\ No newline at end of file
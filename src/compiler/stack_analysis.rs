@@ -0,0 +1,484 @@
+//! Static analysis of the data stack and the call stack of an assembled program (`-analyze-stack`)
+//! Both stacks wrap around silently on the hardware, so this follows every path from the first instruction to find where they could
+//! Jumps are followed when `goto-a` and `goto-b` have values from `write`s, which is always the case after `@goto()`, `@goto_if()` and `@call()`
+//! Each `call` target is a function, analyzed once with stack depths relative to its start, which is combined with the depth at each call to check stack reads
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::prelude::*;
+use super::{Program, assembly_encode::{AssemblerWordContext, InstructionFields}, macros::MacroEnum};
+
+/// Number of calls that can be nested before the call stack wraps, entry 0 is never used (see `Machine::execute_instruction()`)
+pub const CALL_STACK_SIZE: usize = 255;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StackIssueEnum {
+	/// Stack depths relative to the start of the function, (first path found, another path)
+	UnbalancedPaths(i32, i32),
+	/// Stack depths relative to the start of the function, (first `return` found, this `return`)
+	UnbalancedReturns(i32, i32),
+	/// Change in stack depth
+	UnbalancedInterruptHandler(i32),
+	/// Names of the functions in the cycle, starting and ending with the one called here
+	Recursion(Vec<String>),
+	/// Fewest bytes that can be on the stack
+	PopUnderflow(i32),
+	/// Position below the top of the stack (0 is the top) and fewest bytes that can be on the stack
+	OffsetBeyondStack{position: i32, known: i32},
+	/// How deep calls can be nested, including an interrupt
+	CallStackOverflow(usize),
+	/// `goto`, `goto-if` or `call` with `goto-a` and `goto-b` (or `int-goto-a` and `int-goto-b`) set from something other than `write`
+	UnknownTarget,
+	/// Number of instructions in a row that nothing jumps or continues to
+	Unreachable(usize)
+}
+
+impl fmt::Display for StackIssueEnum {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::UnbalancedPaths(first, other) => write!(f, "Paths get here with different stack depths, {:+} and {:+} bytes from the start of the function", first, other),
+			Self::UnbalancedReturns(first, other) => write!(f, "Returns with a different stack depth than another return in the same function, {:+} instead of {:+} bytes", other, first),
+			Self::UnbalancedInterruptHandler(delta) => write!(f, "Interrupt handler changes the stack depth by {:+} bytes", delta),
+			Self::Recursion(names) => write!(f, "Recursive call, {}", names.join(" -> ")),
+			Self::PopUnderflow(known) => write!(f, "Pops from the stack when it can have {} byte(s) on it", known),
+			Self::OffsetBeyondStack{position, known} => write!(f, "Stack offset {} is past the bottom of the stack, which can have {} byte(s) on it", position, known),
+			Self::CallStackOverflow(depth) => write!(f, "Calls can be nested {} deep, which is more than the {} the call stack has room for", depth, CALL_STACK_SIZE),
+			Self::UnknownTarget => write!(f, "Jumps to a calculated address, which the analysis doesn't follow"),
+			Self::Unreachable(len) => write!(f, "{} instruction(s) can't be reached", len)
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackIssue {
+	/// Program address
+	pub address: usize,
+	pub enum_: StackIssueEnum
+}
+
+/// Everything found out about one function
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionSummary {
+	/// Program address of the first instruction
+	pub entry: usize,
+	/// Anchor at `entry`, or the address if there isn't one
+	pub name: String,
+	/// Bytes pushed (negative if popped) by the time it returns, None if it never returns
+	pub net_delta_opt: Option<i32>,
+	/// Most bytes it has pushed at any point, including in functions it calls
+	pub max_depth: i32,
+	/// How deep the calls it makes are nested (0 if it doesn't call anything), None if there is recursion
+	pub call_depth_opt: Option<usize>,
+	/// Every path through it and every function it calls has one stack depth at each instruction, otherwise depths in it aren't checked
+	pub depths_known: bool,
+	/// Fewest bytes on the stack when it starts, None if that isn't known for every call
+	pub entry_depth_opt: Option<i32>
+}
+
+/// Result of `analyze()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StackAnalysis {
+	/// Program start first, then in order of address
+	pub functions: Vec<FunctionSummary>,
+	/// Entries of interrupt handlers set by `@set_interrupt()` before interrupts are enabled
+	pub interrupt_handlers: Vec<usize>,
+	/// Deepest nesting of calls, including an interrupt happening in the deepest function, None if there is recursion
+	pub max_call_depth_opt: Option<usize>,
+	/// In order of address
+	pub issues: Vec<StackIssue>
+}
+
+impl StackAnalysis {
+	pub fn function(&self, name: &str) -> Option<&FunctionSummary> {
+		self.functions.iter().find(|function| function.name == name)
+	}
+	/// Report printed by `-analyze-stack`
+	pub fn format(&self, program: &Program) -> String {
+		let mut out = format!("{:<8}{:<32}{:<8}{:<8}{:<8}ENTRY\n", "ADDR", "FUNCTION", "NET", "MAX", "CALLS");
+		for function in &self.functions {
+			out += &format!(
+				"{:<8}{:<32}{:<8}{:<8}{:<8}{}\n",
+				format!("{:#06X}", function.entry),
+				function.name,
+				match (function.net_delta_opt, function.depths_known) {
+					(Some(_), false) => "?".to_owned(),
+					(Some(delta), true) => format!("{:+}", delta),
+					(None, _) => "-".to_owned()
+				},
+				function.max_depth,
+				match function.call_depth_opt {
+					Some(depth) => depth.to_string(),
+					None => "rec".to_owned()
+				},
+				match function.entry_depth_opt {
+					Some(depth) => depth.to_string(),
+					None => "?".to_owned()
+				}
+			);
+		}
+		out += &match self.max_call_depth_opt {
+			Some(depth) => format!("Max call depth: {} of {}\n", depth, CALL_STACK_SIZE),
+			None => "Max call depth: unbounded because of recursion\n".to_owned()
+		};
+		for issue in &self.issues {
+			out += &format!("Warning at {}: {}\n", program.describe_address(issue.address as u16), issue.enum_);
+		}
+		// Done
+		out
+	}
+}
+
+/// Analyzes every path from the first instruction and from interrupt handlers
+pub fn analyze(program: &Program, config: &AssemblerConfig) -> StackAnalysis {
+	let mut analyzer = Analyzer {
+		program,
+		config,
+		functions: HashMap::new(),
+		in_progress: Vec::new(),
+		issues: Vec::new(),
+		reached: HashSet::new(),
+		interrupt_handlers: Vec::new()
+	};
+	analyzer.analyze_function(0);
+	// Handlers can enable other handlers
+	let mut i: usize = 0;
+	while i < analyzer.interrupt_handlers.len() {
+		let handler: usize = analyzer.interrupt_handlers[i];
+		analyzer.analyze_function(handler);
+		match analyzer.functions[&handler].summary.net_delta_opt {
+			Some(0) | None => {},
+			Some(delta) => analyzer.issue(handler, StackIssueEnum::UnbalancedInterruptHandler(delta))
+		}
+		i += 1;
+	}
+	// Fewest bytes on the stack at the start of each function, interrupts can happen with anything on the stack so handlers only know about their own
+	// Calls without recursion can't loop, so a depth is only set once every caller has one
+	for entry in [0].iter().chain(&analyzer.interrupt_handlers) {
+		analyzer.functions.get_mut(entry).unwrap().summary.entry_depth_opt = Some(0);
+	}
+	let mut callers = HashMap::<usize, Vec<(usize, i32)>>::new();
+	for function in analyzer.functions.values() {
+		for (_, callee, depth) in &function.calls {
+			callers.entry(*callee).or_default().push((function.summary.entry, *depth));
+		}
+	}
+	let mut changed: bool = true;
+	while changed {
+		changed = false;
+		for (callee, calls) in &callers {
+			if analyzer.functions[callee].summary.entry_depth_opt.is_some() {
+				continue;
+			}
+			let depth_opt: Option<i32> = calls.iter().map(|(caller, depth)| {
+				let caller_summary: &FunctionSummary = &analyzer.functions[caller].summary;
+				match caller_summary.depths_known {
+					true => caller_summary.entry_depth_opt.map(|entry_depth| entry_depth + depth),
+					false => None
+				}
+			}).collect::<Option<Vec<i32>>>().and_then(|depths| depths.into_iter().min());
+			if depth_opt.is_some() {
+				analyzer.functions.get_mut(callee).unwrap().summary.entry_depth_opt = depth_opt;
+				changed = true;
+			}
+		}
+	}
+	// Stack reads, only the first underflow in a function because the rest are usually caused by it
+	let mut underflows = Vec::<StackIssue>::new();
+	for function in analyzer.functions.values() {
+		if let (Some(entry_depth), true) = (function.summary.entry_depth_opt, function.summary.depths_known) {
+			let mut accesses: Vec<&(usize, StackAccess)> = function.accesses.iter().collect();
+			accesses.sort_by_key(|(address, _)| *address);
+			let underflow_opt = accesses.into_iter().find(|(_, access)| entry_depth + access.depth < access.needed);
+			if let Some((address, access)) = underflow_opt {
+				let known: i32 = entry_depth + access.depth;
+				underflows.push(StackIssue {
+					address: *address,
+					enum_: match access.offset {
+						true => StackIssueEnum::OffsetBeyondStack{position: access.needed - 1, known},
+						false => StackIssueEnum::PopUnderflow(known)
+					}
+				});
+			}
+		}
+	}
+	for issue in underflows {
+		analyzer.issue(issue.address, issue.enum_);
+	}
+	// Call stack, an interrupt can happen in the deepest function
+	let max_call_depth_opt: Option<usize> = analyzer.functions[&0].summary.call_depth_opt.and_then(|main_depth| analyzer.interrupt_handlers.iter().try_fold(main_depth, |max, handler| Some(max.max(main_depth + 1 + analyzer.functions[handler].summary.call_depth_opt?))));
+	if let Some(depth) = max_call_depth_opt {
+		if depth > CALL_STACK_SIZE {
+			analyzer.issue(0, StackIssueEnum::CallStackOverflow(depth));
+		}
+	}
+	// Unreachable code, only known if every jump could be followed, padding from `@org()` is never meant to be run
+	if !analyzer.issues.iter().any(|issue| issue.enum_ == StackIssueEnum::UnknownTarget) {
+		let mut ranges: Vec<(usize, usize)> = vec![(0, program.words.len())];
+		ranges.extend(program.gpram_sections.iter().map(|section| (section.address, section.address + section.words.len())));
+		for (start, end) in ranges {
+			let mut run_start_opt: Option<usize> = None;
+			for address in start..=end {
+				let unreachable: bool = address < end && !analyzer.reached.contains(&address) && !matches!(program.location(address as u16).and_then(|location| location.macro_opt.as_ref()).map(|macro_| &macro_.type_), Some(MacroEnum::Org));
+				match (unreachable, run_start_opt) {
+					(true, None) => run_start_opt = Some(address),
+					(false, Some(run_start)) => {
+						analyzer.issue(run_start, StackIssueEnum::Unreachable(address - run_start));
+						run_start_opt = None;
+					},
+					_ => {}
+				}
+			}
+		}
+	}
+	// Done
+	let mut functions: Vec<FunctionSummary> = analyzer.functions.into_values().map(|function| function.summary).collect();
+	functions.sort_by_key(|function| function.entry);
+	let mut issues = analyzer.issues;
+	issues.sort_by_key(|issue| issue.address);
+	StackAnalysis {
+		functions,
+		interrupt_handlers: analyzer.interrupt_handlers,
+		max_call_depth_opt,
+		issues
+	}
+}
+
+/// What is known before an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct State {
+	/// Bytes pushed since the start of the function
+	depth: i32,
+	/// `goto-a` and `goto-b`
+	goto: [Option<u8>; 2],
+	/// `int-goto-a` and `int-goto-b`
+	int_goto: [Option<u8>; 2],
+	/// Value of `set-stack-offset` as encoded, see `StackController::compute_offset()`
+	offset: Option<u8>
+}
+
+impl State {
+	/// Latches keep values that both have, the depth of `self` is kept
+	fn merge(&self, other: &Self) -> Self {
+		let same = |a: Option<u8>, b: Option<u8>| -> Option<u8> {
+			if a == b {a} else {None}
+		};
+		Self {
+			depth: self.depth,
+			goto: [same(self.goto[0], other.goto[0]), same(self.goto[1], other.goto[1])],
+			int_goto: [same(self.int_goto[0], other.int_goto[0]), same(self.int_goto[1], other.int_goto[1])],
+			offset: same(self.offset, other.offset)
+		}
+	}
+	/// Address that a jump goes to, the execution pointer is incremented after it is set from the latches
+	fn target(latches: [Option<u8>; 2]) -> Option<usize> {
+		Some((latches[0]? as usize | (latches[1]? as usize) << 8) + 1)
+	}
+}
+
+/// Pop, offset read or offset write
+struct StackAccess {
+	/// Depth relative to the start of the function
+	depth: i32,
+	/// Bytes that have to be on the stack
+	needed: i32,
+	offset: bool
+}
+
+struct FunctionAnalysis {
+	summary: FunctionSummary,
+	/// (program address, function called, depth relative to the start of this function), not including recursive calls
+	calls: Vec<(usize, usize, i32)>,
+	accesses: Vec<(usize, StackAccess)>
+}
+
+struct Analyzer<'a> {
+	program: &'a Program,
+	config: &'a AssemblerConfig,
+	functions: HashMap<usize, FunctionAnalysis>,
+	/// Functions being analyzed, outermost first
+	in_progress: Vec<usize>,
+	issues: Vec<StackIssue>,
+	/// Every instruction on any path
+	reached: HashSet<usize>,
+	interrupt_handlers: Vec<usize>
+}
+
+impl<'a> Analyzer<'a> {
+	fn issue(&mut self, address: usize, enum_: StackIssueEnum) {
+		let issue = StackIssue{address, enum_};
+		if !self.issues.contains(&issue) {
+			self.issues.push(issue);
+		}
+	}
+	fn name(&self, entry: usize) -> String {
+		let mut names: Vec<&String> = self.program.anchors.iter().filter(|(_, address)| **address == entry).map(|(name, _)| name).collect();
+		names.sort();
+		match names.first() {
+			Some(name) => (*name).clone(),
+			None => format!("{:#06X}", entry)
+		}
+	}
+	fn word_name(&self, context: AssemblerWordContext, id_opt: Option<u8>) -> String {
+		match id_opt.and_then(|id_| self.config.decode_word(context, id_)) {
+			Some(word) => word.name.to_lowercase(),
+			None => String::new()
+		}
+	}
+	/// Follows every path through a function, functions it calls are analyzed first
+	fn analyze_function(&mut self, entry: usize) {
+		if self.functions.contains_key(&entry) {
+			return;
+		}
+		self.in_progress.push(entry);
+		let mut summary = FunctionSummary {
+			entry,
+			name: self.name(entry),
+			net_delta_opt: None,
+			max_depth: 0,
+			call_depth_opt: Some(0),
+			depths_known: true,
+			entry_depth_opt: None
+		};
+		let mut calls = Vec::<(usize, usize, i32)>::new();
+		let mut accesses = Vec::<(usize, StackAccess)>::new();
+		let mut states = HashMap::<usize, State>::from([(entry, State{depth: 0, goto: [None; 2], int_goto: [None; 2], offset: None})]);
+		let mut worklist: Vec<usize> = vec![entry];
+		while let Some(address) = worklist.pop() {
+			let state: State = states[&address];
+			self.reached.insert(address);
+			// Running past the end of the program is an emulation error, so there is nothing to follow
+			let word: u16 = match self.program.word(address as u16) {
+				Some(word) => word,
+				None => continue
+			};
+			let fields = InstructionFields::decode(word, self.config.version);
+			let mut next: State = state;
+			let mut successors = Vec::<(usize, State)>::new();
+			let opcode: String = self.word_name(AssemblerWordContext::Opcode, fields.opcode);
+			match opcode.as_str() {
+				"move" | "write" => {
+					match self.word_name(AssemblerWordContext::ToBus, fields.to_bus).as_str() {
+						"stack-pop" => {
+							accesses.push((address, StackAccess{depth: next.depth, needed: 1, offset: false}));
+							next.depth -= 1;
+						},
+						"offset-read" => if let Some(offset) = next.offset {
+							accesses.push((address, StackAccess{depth: next.depth, needed: 0x100 - offset as i32, offset: true}));
+						},
+						_ => {}
+					}
+					// Latches set by a `move` aren't known
+					match self.word_name(AssemblerWordContext::FromBus, fields.from_bus).as_str() {
+						"stack-push" => next.depth += 1,
+						"offset-write" => if let Some(offset) = next.offset {
+							accesses.push((address, StackAccess{depth: next.depth, needed: 0x100 - offset as i32, offset: true}));
+						},
+						"goto-a" => next.goto[0] = fields.literal,
+						"goto-b" => next.goto[1] = fields.literal,
+						"int-goto-a" => next.int_goto[0] = fields.literal,
+						"int-goto-b" => next.int_goto[1] = fields.literal,
+						"set-stack-offset" => next.offset = fields.literal,
+						_ => {}
+					}
+					successors.push((address + 1, next));
+				},
+				"goto" | "goto-if" => {
+					match State::target(state.goto) {
+						Some(target) => successors.push((target, next)),
+						None => self.issue(address, StackIssueEnum::UnknownTarget)
+					}
+					if opcode == "goto-if" {
+						successors.push((address + 1, next));
+					}
+				},
+				"call" => {
+					let target_opt: Option<usize> = match fields.flag {
+						Some(1) => State::target(state.int_goto),
+						_ => State::target(state.goto)
+					};
+					// Called functions can change anything, stack depth is assumed to be unchanged if it isn't known
+					next.goto = [None; 2];
+					next.int_goto = [None; 2];
+					next.offset = None;
+					match target_opt {
+						Some(target) => match self.in_progress.iter().position(|entry| *entry == target) {
+							Some(cycle_start) => {
+								let mut names: Vec<String> = self.in_progress[cycle_start..].iter().map(|entry| self.name(*entry)).collect();
+								names.push(self.name(target));
+								self.issue(address, StackIssueEnum::Recursion(names));
+								summary.call_depth_opt = None;
+								summary.depths_known = false;
+								successors.push((address + 1, next));
+							},
+							None => {
+								self.analyze_function(target);
+								let callee: &FunctionSummary = &self.functions[&target].summary;
+								calls.push((address, target, state.depth));
+								summary.max_depth = summary.max_depth.max(state.depth + callee.max_depth);
+								summary.call_depth_opt = summary.call_depth_opt.zip(callee.call_depth_opt).map(|(depth, callee_depth)| depth.max(callee_depth + 1));
+								summary.depths_known &= callee.depths_known;
+								// Code after a call to a function that never returns can't be reached from here
+								if let Some(delta) = callee.net_delta_opt {
+									next.depth += delta;
+									successors.push((address + 1, next));
+								}
+							}
+						},
+						None => {
+							self.issue(address, StackIssueEnum::UnknownTarget);
+							summary.depths_known = false;
+							successors.push((address + 1, next));
+						}
+					}
+				},
+				"return" => match summary.net_delta_opt {
+					Some(first) if first != state.depth => {
+						self.issue(address, StackIssueEnum::UnbalancedReturns(first, state.depth));
+						summary.depths_known = false;
+					},
+					Some(_) => {},
+					None => summary.net_delta_opt = Some(state.depth)
+				},
+				"config-int" => {
+					// Enabling interrupts, the handler is wherever `int-goto-a` and `int-goto-b` point
+					if fields.flag == Some(1) {
+						match State::target(state.int_goto) {
+							Some(handler) => if !self.interrupt_handlers.contains(&handler) {
+								self.interrupt_handlers.push(handler);
+							},
+							None => self.issue(address, StackIssueEnum::UnknownTarget)
+						}
+					}
+					successors.push((address + 1, next));
+				},
+				// `halt` and invalid opcodes
+				_ => {}
+			}
+			summary.max_depth = summary.max_depth.max(next.depth);
+			for (successor, successor_state) in successors {
+				let merged: State = match states.get(&successor) {
+					Some(old) => {
+						if old.depth != successor_state.depth {
+							summary.depths_known = false;
+							let first: i32 = old.depth;
+							if !self.issues.iter().any(|issue| issue.address == successor && matches!(issue.enum_, StackIssueEnum::UnbalancedPaths(..))) {
+								self.issue(successor, StackIssueEnum::UnbalancedPaths(first, successor_state.depth));
+							}
+						}
+						let merged = old.merge(&successor_state);
+						if merged == *old {
+							continue;
+						}
+						merged
+					},
+					None => successor_state
+				};
+				states.insert(successor, merged);
+				worklist.push(successor);
+			}
+		}
+		self.in_progress.pop();
+		self.functions.insert(entry, FunctionAnalysis{summary, calls, accesses});
+	}
+}
@@ -1,7 +1,9 @@
 //! For creating and validating syntax tree
 
+use std::fmt;
+
 use crate::prelude::*;
-use super::{Token, TokenEnum};
+use super::{Token, TokenEnum, expression::Expression, macros::MacroEnum};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum SyntaxTreeNodeType {
@@ -24,6 +26,19 @@ pub enum ParseContext {
     StringLiteral
 }
 
+impl fmt::Display for ParseContext {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", match self {
+			Self::Program => "program",
+			Self::Macro => "macro",
+			Self::Instruction => "instruction",
+			Self::InstructionToken => "instruction token",
+			Self::Comment => "comment",
+			Self::StringLiteral => "string literal"
+		})
+	}
+}
+
 impl ParseContext {
 	pub fn parse(&self, source: &Vec<char>, start: usize) -> Result<SyntaxTreeNode, ParseError> {
 		//let source_substring: &str = &source[start..source.len()];
@@ -31,57 +46,12 @@ impl ParseContext {
 		let type_opt: Option<SyntaxTreeNodeType>;
 		let mut i: usize = start;
 		match self {
-			Self::Program => {
-				while i < source.len() {// Top level node type, so loop through everything
-					// Skip whitespace
-					i = skip_whitespace(source, i, None)?;
-					if i == source.len() {// allow whitespace at end of program
-						break;
-					}
-					let char_: char = source[i];
-					// Check if identifier (instruction)
-					if IDENTIFIER_CHARS.contains(&char_) {// Create new child of type Instruction
-						match ParseContext::Instruction.parse(source, i) {
-							Ok(instruction_node) => {
-								i = instruction_node.end;
-								children.push(instruction_node);
-								continue;
-							},
-							Err(errs) => {return Err(errs)}
-						}
-					}
-					// Check if macro
-					if char_ == MACRO_BEGIN {
-						match ParseContext::Macro.parse(source, i+1) {
-							Ok(macro_child) => {
-								i = macro_child.end;
-								children.push(macro_child);
-								continue;
-							},
-							Err(errs) => {return Err(errs)}
-						}
-					}
-					// Check if comment
-					if char_ == COMMENT_BEGIN {
-						match ParseContext::Comment.parse(source, i+1) {
-							Ok(comment_child) => {
-								i = comment_child.end;
-								children.push(comment_child);
-								continue;
-							},
-							Err(errs) => {return Err(errs)}
-						}
-					}
-					// Skip whitespace
-					i = skip_whitespace(source, i, Some(*self))?;
-					// Check if next next character is semicolon ";"
-					if source[i] != ';' {
-						return Err(ParseError::new(i, i+1, ParseErrorType::InvalidCharacterInContext(source[i], *self), Some("Expected \";\"'".to_string())));
-					}
-					// Skip semicolon
-					i += 1;
+			Self::Program => {// Recovers from errors, only the first one is returned here, use `SyntaxTreeNode::build_tree_recovering()` to get all of them
+				let (program_node, mut errors) = parse_program(source, start);
+				if errors.is_empty() {
+					return Ok(program_node);
 				}
-				type_opt = Some(SyntaxTreeNodeType::Program);
+				return Err(errors.remove(0));
 			},
 			Self::Macro => {
 				// Set node type
@@ -93,8 +63,8 @@ impl ParseContext {
 				while i < source.len() {
 					// Skip whitespace
 					i = skip_whitespace(source, i, Some(*self))?;
-					// Check if identifier
-					if IDENTIFIER_CHARS.contains(&source[i]) {
+					// Check if identifier or expression
+					if IDENTIFIER_CHARS.contains(&source[i]) || source[i] == '(' || source[i] == '\'' {
 						let token_child = ParseContext::InstructionToken.parse(source, i)?;
 						i = token_child.end;
 						children.push(token_child);
@@ -114,19 +84,32 @@ impl ParseContext {
 				type_opt = Some(SyntaxTreeNodeType::Instruction);
 			},
 			Self::InstructionToken => {
-				let (assembly_word, new_i) = parse_identifier(source, i, Some(*self))?;
+				let (assembly_word, mut new_i) = parse_identifier(source, i, Some(*self))?;
 				let token: Token = match check_for_and_parse_bit_string(source, i, Some(*self)) {
-					Some(result_) => {
+					// Literal followed by an operator is an expression, "-" is an identifier character so `0x10-1` is all one word
+					Some(result_) if !is_expression_after(source, new_i) && !assembly_word.contains('-') => {
 						let (bytes_vec, bit_size, _) = result_?;
 						Token {
 							enum_: TokenEnum::Literal{n: bytes_vec[0], bit_size},
 							raw: assembly_word
 						}
 					},
-					None => {
-						Token {
-							enum_: TokenEnum::AssemblyWord(assembly_word.clone()),
-							raw: assembly_word
+					_ => {
+						// Expression if it begins like one, is a (negative) decimal number or is followed by "(" or an operator
+						let starts_expression: bool = assembly_word.is_empty() || assembly_word.trim_start_matches('-').starts_with(|c: char| c.is_ascii_digit()) || is_expression_after(source, new_i);
+						match starts_expression {
+							true => {
+								let (expression, end) = Expression::parse(source, i)?;
+								new_i = end;
+								Token {
+									enum_: TokenEnum::Expression(expression),
+									raw: source[i..new_i].iter().collect()
+								}
+							},
+							false => Token {
+								enum_: TokenEnum::AssemblyWord(assembly_word.clone()),
+								raw: assembly_word
+							}
 						}
 					}
 				};
@@ -146,7 +129,7 @@ impl ParseContext {
                 let (parsed_string, new_i) = parse_string_literal(source, i)?;
                 type_opt = Some(SyntaxTreeNodeType::StringLiteral(parsed_string));
                 i = new_i;
-            }
+            },
 		}
 		// Done
 		match type_opt {
@@ -161,6 +144,149 @@ impl ParseContext {
 	}
 }
 
+/// If the character after an instruction token is "(" or an operator, meaning that the token is the beginning of an expression
+fn is_expression_after(source: &[char], i: usize) -> bool {
+	matches!(source.get(i), Some('(' | '+' | '*' | '&' | '|' | '<' | '>'))
+}
+
+/// Parses the top level of the program, on an error it skips to the next ";" or newline and keeps going so that every error can be reported in one pass
+/// Returns the root node with everything that did parse and all the errors
+fn parse_program(source: &Vec<char>, start: usize) -> (SyntaxTreeNode, Vec<ParseError>) {
+	let mut children = Vec::<SyntaxTreeNode>::new();
+	let mut errors = Vec::<ParseError>::new();
+	let mut i: usize = start;
+	while i < source.len() {// Top level node type, so loop through everything
+		// Skip whitespace
+		i = skip_whitespace(source, i, None).unwrap();// Can't fail without a parse context
+		if i == source.len() {// allow whitespace at end of program
+			break;
+		}
+		let char_: char = source[i];
+		let child_result: Option<Result<SyntaxTreeNode, ParseError>> = if IDENTIFIER_CHARS.contains(&char_) {// Create new child of type Instruction
+			Some(ParseContext::Instruction.parse(source, i))
+		}
+		else if char_ == MACRO_BEGIN {// Check if macro
+			Some(ParseContext::Macro.parse(source, i+1))
+		}
+		else if char_ == COMMENT_BEGIN {// Check if comment
+			Some(ParseContext::Comment.parse(source, i+1))
+		}
+		else {
+			None
+		};
+		match child_result {
+			Some(Ok(child)) => {
+				i = child.end;
+				children.push(child);
+				continue;
+			},
+			Some(Err(error)) => {
+				i = resync(source, i.max(error.begin));
+				errors.push(error);
+				continue;
+			},
+			None => {}
+		}
+		// Check if next next character is semicolon ";"
+		if source[i] != ';' {
+			errors.push(ParseError::new(i, i+1, ParseErrorType::InvalidCharacterInContext(source[i], ParseContext::Program), Some("Expected \";\"".to_string())));
+			i = resync(source, i);
+			continue;
+		}
+		// Skip semicolon
+		i += 1;
+	}
+	// Blocks must be closed in the same file they are opened in
+	errors.append(&mut check_block_nesting(&children));
+	errors.sort_by_key(|error| error.begin);
+	// Done
+	(SyntaxTreeNode::new(SyntaxTreeNodeType::Program, start, i, children), errors)
+}
+
+/// Checks that every `@if`, `@ifdef` and `@ifndef` has an `@endif` and every `@while` has an `@endwhile`, with at most 1 `@else` per `@if`, and that they are nested properly
+/// Blocks can't go into or out of a `@macro` definition, the definitions themselves are checked when they are expanded (see `program_skeleton::expand_user_macros()`)
+fn check_block_nesting(nodes: &[SyntaxTreeNode]) -> Vec<ParseError> {
+	let mut errors = Vec::<ParseError>::new();
+	// Open blocks and macro definitions, (macro type, node, has `@else`)
+	let mut open = Vec::<(&MacroEnum, &SyntaxTreeNode, bool)>::new();
+	// Macro nodes begin after the "@"
+	let error_at = |node: &SyntaxTreeNode, type_: ParseErrorType| -> ParseError {ParseError::new(node.begin - 1, node.end, type_, None)};
+	for node in nodes {
+		let macro_type: &MacroEnum = match &node.type_ {
+			SyntaxTreeNodeType::Macro(macro_) => &macro_.type_,
+			_ => continue
+		};
+		let begin_type: MacroEnum = match macro_type {
+			MacroEnum::If | MacroEnum::IfDef | MacroEnum::IfNDef | MacroEnum::While | MacroEnum::Define(_) => {
+				open.push((macro_type, node, false));
+				continue;
+			},
+			MacroEnum::Else => {
+				match open.last_mut() {
+					Some((MacroEnum::If | MacroEnum::IfDef | MacroEnum::IfNDef, _, has_else)) if !*has_else => *has_else = true,
+					Some((MacroEnum::If | MacroEnum::IfDef | MacroEnum::IfNDef, _, _)) => errors.push(error_at(node, ParseErrorType::BlockElseDuplicate)),
+					_ => errors.push(error_at(node, ParseErrorType::BlockEndWithoutBegin("else".to_owned(), "if".to_owned())))
+				}
+				continue;
+			},
+			MacroEnum::EndIf => MacroEnum::If,
+			MacroEnum::EndWhile => MacroEnum::While,
+			MacroEnum::EndMacro => MacroEnum::Define(String::new()),
+			_ => continue
+		};
+		// Find what this ends, anything opened after that is unterminated
+		let is_begin = |type_: &MacroEnum| -> bool {
+			match begin_type {
+				MacroEnum::If => matches!(type_, MacroEnum::If | MacroEnum::IfDef | MacroEnum::IfNDef),
+				_ => std::mem::discriminant(type_) == std::mem::discriminant(&begin_type)
+			}
+		};
+		let search_start: usize = match begin_type {
+			MacroEnum::Define(_) => 0,
+			// Not past the start of a macro definition
+			_ => open.iter().rposition(|(type_, _, _)| matches!(type_, MacroEnum::Define(_))).map(|i| i + 1).unwrap_or(0)
+		};
+		match open[search_start..].iter().rposition(|(type_, _, _)| is_begin(type_)) {
+			Some(offset) => {
+				// First one is the block being ended
+				for (unterminated_type, unterminated_node, _) in open.drain(search_start + offset..).skip(1) {
+					errors.push(block_unterminated(unterminated_type, unterminated_node));
+				}
+			},
+			None => {
+				// `@endmacro` without `@macro` is reported when macros are expanded
+				if !matches!(begin_type, MacroEnum::Define(_)) {
+					errors.push(error_at(node, ParseErrorType::BlockEndWithoutBegin(macro_type.name().to_owned(), begin_type.name().to_owned())));
+				}
+			}
+		}
+	}
+	for (unterminated_type, unterminated_node, _) in open {
+		if !matches!(unterminated_type, MacroEnum::Define(_)) {
+			errors.push(block_unterminated(unterminated_type, unterminated_node));
+		}
+	}
+	// Done
+	errors
+}
+
+fn block_unterminated(type_: &MacroEnum, node: &SyntaxTreeNode) -> ParseError {
+	let end_name: &str = match type_ {
+		MacroEnum::If | MacroEnum::IfDef | MacroEnum::IfNDef => "endif",
+		MacroEnum::While => "endwhile",
+		_ => "endmacro"
+	};
+	ParseError::new(node.begin - 1, node.end, ParseErrorType::BlockUnterminated(type_.name().to_owned(), end_name.to_owned()), None)
+}
+
+/// Index right after the next ";" or newline at or after `start`, where parsing can pick up again after an error
+fn resync(source: &[char], start: usize) -> usize {
+	match source[start.min(source.len())..].iter().position(|c: &char| -> bool {*c == ';' || *c == '\n'}) {
+		Some(offset) => start + offset + 1,
+		None => source.len()
+	}
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct SyntaxTreeNode {
 	pub type_: SyntaxTreeNodeType,
@@ -172,13 +298,17 @@ pub struct SyntaxTreeNode {
 }
 
 impl SyntaxTreeNode {
-	/// Basically a wrapper for `ParseContext::parse()`
+	/// Basically a wrapper for `ParseContext::parse()`, returns the first error
 	pub fn build_tree(source: &Vec<char>) -> Result<Self, ParseError> {
 		ParseContext::Program.parse(source, 0)/* {
 			Ok((root_node, _)) => Ok(root_node),
 			Err(errs) => Err(errs)
 		}*/
 	}
+	/// Keeps going after errors, returns the tree of everything that parsed and all errors in the order they appear in the source
+	pub fn build_tree_recovering(source: &Vec<char>) -> (Self, Vec<ParseError>) {
+		parse_program(source, 0)
+	}
 	/// New
 	pub fn new(type_: SyntaxTreeNodeType, begin: usize, end: usize, children: Vec<Self>) -> Self {
 		Self {
@@ -194,6 +324,9 @@ impl SyntaxTreeNode {
 pub enum ParseErrorType {
 	InvalidCharacterInContext(char, ParseContext),
 	UnfinishedNode(ParseContext),
+	/// Expressions aren't parsed with a `ParseContext`, so they have their own errors
+	InvalidCharacterInExpression(char),
+	UnfinishedExpression,
 	MissingMacroIdentifier,
 	StringInvalidEscapeSequence(char),
 	StringEscapeEOF,
@@ -201,7 +334,18 @@ pub enum ParseErrorType {
 	BinaryParseError,
 	DecimalParseError,
     InvalidMacroIdentifier(String),
-    MacroWrongNumArgs{actual: usize, correct: usize}
+    MacroWrongNumArgs{actual: usize, correct: usize},
+	InvalidCharacterLiteral,
+	InvalidExpressionFunction(String),
+	/// (begin, end) macro names, for example ("if", "endif")
+	BlockUnterminated(String, String),
+	/// (end, begin) macro names
+	BlockEndWithoutBegin(String, String),
+	BlockElseDuplicate,
+	/// `@var name[0]`
+	VarSizeZero,
+	/// In a `@data` string, which is 1 byte per character
+	NonAsciiCharacter(char)
 }
 
 #[derive(Debug)]
@@ -212,6 +356,43 @@ pub struct ParseError {
 	pub message: Option<String>
 }
 
+impl fmt::Display for ParseErrorType {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::InvalidCharacterInContext(char_, context) => write!(f, "Invalid character {:?} in {}", char_, context),
+			Self::UnfinishedNode(context) => write!(f, "Unfinished {}", context),
+			Self::InvalidCharacterInExpression(char_) => write!(f, "Invalid character {:?} in expression", char_),
+			Self::UnfinishedExpression => write!(f, "Unfinished expression"),
+			Self::MissingMacroIdentifier => write!(f, "Missing macro name"),
+			Self::StringInvalidEscapeSequence(char_) => write!(f, "Invalid escape sequence \"\\{}\"", char_),
+			Self::StringEscapeEOF => write!(f, "Escape sequence at end of file"),
+			Self::HexParseError => write!(f, "Invalid hex literal"),
+			Self::BinaryParseError => write!(f, "Invalid binary literal"),
+			Self::DecimalParseError => write!(f, "Invalid decimal literal"),
+			Self::InvalidMacroIdentifier(id) => write!(f, "Unknown macro \"{}{}\"", MACRO_BEGIN, id),
+			Self::MacroWrongNumArgs{actual, correct} => write!(f, "Macro takes {} argument(s) but was given {}", correct, actual),
+			Self::InvalidCharacterLiteral => write!(f, "Invalid character literal, must be 1 character or escape sequence in single quotes"),
+			Self::InvalidExpressionFunction(name) => write!(f, "Unknown function \"{}\", must be lo or hi", name),
+			Self::BlockUnterminated(begin, end) => write!(f, "{}{} has no matching {}{}", MACRO_BEGIN, begin, MACRO_BEGIN, end),
+			Self::BlockEndWithoutBegin(end, begin) => write!(f, "{}{} without {}{}", MACRO_BEGIN, end, MACRO_BEGIN, begin),
+			Self::BlockElseDuplicate => write!(f, "{}if already has an {}else", MACRO_BEGIN, MACRO_BEGIN),
+			Self::VarSizeZero => write!(f, "{}var size must be at least 1 byte", MACRO_BEGIN),
+			Self::NonAsciiCharacter(char_) => write!(f, "{:?} is not an ASCII character, {}data strings are 1 byte per character", char_, MACRO_BEGIN)
+		}
+	}
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.message {
+			Some(message) => write!(f, "{}, {}", self.type_, message),
+			None => write!(f, "{}", self.type_)
+		}
+	}
+}
+
+impl std::error::Error for ParseError {}
+
 impl ParseError {
 	pub fn new(
 		begin: usize,
@@ -0,0 +1,247 @@
+//! Interactive step debugger for the emulator, run with `-debug <file>`
+//! Commands are parsed and executed one line at a time by `Debugger::execute_command()` so that it can be scripted
+
+use std::{collections::BTreeSet, io::{self, BufRead, Write}};
+
+use crate::prelude::*;
+use crate::compiler::Program;
+
+pub const HELP: &str = "Commands:
+  break <address|anchor>    (b) Add breakpoint
+  delete <address|anchor>   (d) Remove breakpoint
+  breakpoints               (bl) List breakpoints
+  step [n]                  (s) Execute 1 or n instructions
+  next                      (n) Execute 1 instruction, stepping over `call`
+  continue                  (c) Run until breakpoint or halt
+  regs                      (r) Print ALU/GOTO latches, stack, GPRAM pointer and interrupt state
+  callstack                 (bt) Print the call stack
+  mem <gpram|stack|prog> <start> [len]    (m) Print memory range, len defaults to 16
+  anchors                   List anchors
+  help                      (h) This
+  quit                      (q) Exit
+Addresses are decimal or hex (0x..), anchors can have an offset (`loop+3`)";
+
+/// Max number of instructions run by `continue` or `next` before giving up, so a program that never halts can't freeze the debugger
+pub const MAX_RUN_INSTRUCTIONS: usize = 100_000_000;
+
+pub struct Debugger<T: GpioInterface> {
+	pub machine: Machine,
+	pub gpio_interface: T,
+	/// For anchors and source locations
+	program: Program,
+	breakpoints: BTreeSet<u16>,
+	/// Set once the program halts or causes an emulation error, nothing else can be executed after that
+	stopped: bool
+}
+
+impl<T: GpioInterface> Debugger<T> {
+	pub fn new(program: Program, version: HardwareVersion, gpio_interface: T) -> Self {
+		Self {
+			machine: Machine::new(program.words.clone(), version),
+			gpio_interface,
+			program,
+			breakpoints: BTreeSet::new(),
+			stopped: false
+		}
+	}
+	/// Executes one line of input, returns Ok(text to print) or Err(message for a bad command)
+	/// `quit` is handled by the REPL, not here
+	pub fn execute_command(&mut self, line: &str) -> Result<String, String> {
+		let words: Vec<&str> = line.split_whitespace().collect();
+		if words.is_empty() {
+			return Ok(String::new());
+		}
+		let args: &[&str] = &words[1..];
+		match words[0] {
+			"break" | "b" => {
+				let address = self.parse_address(Self::get_arg(args, 0)?)?;
+				self.breakpoints.insert(address);
+				Ok(format!("Breakpoint at {}", self.format_address(address)))
+			},
+			"delete" | "d" => {
+				let address = self.parse_address(Self::get_arg(args, 0)?)?;
+				match self.breakpoints.remove(&address) {
+					true => Ok(format!("Deleted breakpoint at {}", self.format_address(address))),
+					false => Err(format!("No breakpoint at {}", self.format_address(address)))
+				}
+			},
+			"breakpoints" | "bl" => {
+				let lines: Vec<String> = self.breakpoints.iter().map(|address| self.format_address(*address)).collect();
+				match lines.len() {
+					0 => Ok("No breakpoints".to_owned()),
+					_ => Ok(lines.join("\n"))
+				}
+			},
+			"step" | "s" => {
+				let n: usize = match args.first() {
+					Some(n_raw) => to_string_err(n_raw.parse::<usize>())?,
+					None => 1
+				};
+				self.run_while(n, |_| true)
+			},
+			"next" | "n" => {
+				let call_depth: usize = self.machine.call_stack().len();
+				let mut first = true;
+				// Keep going while inside a deeper call than where this started
+				self.run_while(MAX_RUN_INSTRUCTIONS, |machine: &Machine| {
+					let keep_going = first || machine.call_stack().len() > call_depth;
+					first = false;
+					keep_going
+				})
+			},
+			"continue" | "c" => self.run_while(MAX_RUN_INSTRUCTIONS, |_| true),
+			"regs" | "r" => Ok(self.format_registers()),
+			"callstack" | "bt" => {
+				let call_stack = self.machine.call_stack();
+				if call_stack.is_empty() {
+					return Ok("Call stack empty".to_owned());
+				}
+				let mut lines = Vec::<String>::new();
+				for (depth, return_address) in call_stack.iter().enumerate().rev() {
+					lines.push(format!("#{} called from {}", depth, self.format_address(*return_address)));
+				}
+				Ok(lines.join("\n"))
+			},
+			"mem" | "m" => {
+				let start: u16 = Self::parse_number(Self::get_arg(args, 1)?)?;
+				let len_: usize = match args.get(2) {
+					Some(len_raw) => Self::parse_number(len_raw)? as usize,
+					None => 16
+				};
+				match *Self::get_arg(args, 0)? {
+					"gpram" => Ok(Self::format_memory(&self.machine.general_mem, start, len_, 2)),
+					"stack" => Ok(Self::format_memory(&self.machine.stack_mem, start, len_, 2)),
+					"prog" => Ok(Self::format_memory(&self.machine.prog_mem, start, len_, 4)),
+					other => Err(format!("Unknown memory \"{}\", must be gpram, stack or prog", other))
+				}
+			},
+			"anchors" => {
+				let mut anchors: Vec<(&String, &usize)> = self.program.anchors.iter().collect();
+				anchors.sort_by_key(|(name, address)| (**address, (*name).clone()));
+				Ok(anchors.iter().map(|(name, address)| format!("{:#06X} {}", address, name)).collect::<Vec<String>>().join("\n"))
+			},
+			"help" | "h" => Ok(HELP.to_owned()),
+			other => Err(format!("Unknown command \"{}\", type `help` for a list of commands", other))
+		}
+	}
+	/// Executes up to `max_instructions` while `keep_going` returns true (checked before each instruction), stopping at breakpoints, halt or error
+	/// The instruction at the current address is always executed even if it has a breakpoint so that `continue` can leave a breakpoint
+	fn run_while<F: FnMut(&Machine) -> bool>(&mut self, max_instructions: usize, mut keep_going: F) -> Result<String, String> {
+		if self.stopped {
+			return Err("Program has stopped, nothing left to execute".to_owned());
+		}
+		for i in 0..max_instructions {
+			if !keep_going(&self.machine) {
+				break;
+			}
+			if i != 0 && self.breakpoints.contains(&self.machine.execution_pointer()) {
+				return Ok(format!("Breakpoint at {}", self.format_address(self.machine.execution_pointer())));
+			}
+			match self.machine.execute_instruction(&mut self.gpio_interface) {
+				Ok(true) => {
+					self.stopped = true;
+					return Ok(format!("Halted at {}", self.format_address(self.machine.execution_pointer().wrapping_sub(1))));
+				},
+				Ok(false) => {},
+				Err(e) => {
+					self.stopped = true;
+					return Ok(format!("Emulation error: {}", e.to_string_with_program(&self.program)));
+				}
+			}
+		}
+		Ok(format!("Stopped at {}", self.format_address(self.machine.execution_pointer())))
+	}
+	fn format_registers(&self) -> String {
+		let (goto_a, goto_b, goto_decider) = self.machine.goto_latches();
+		let (int_goto_a, int_goto_b) = self.machine.int_goto_latches();
+		let (stack_top, stack_offset) = self.machine.stack_pointers();
+		let (int_enabled, int_in_progress, int_count) = self.machine.interrupt_state();
+		let mut out = format!(
+			"PC: {}\nALU: a={:#04X} b={:#04X} c={:#04X}\nGOTO: a={:#04X} b={:#04X} decider={}\nStack: top={:#06X} value={:#04X} offset={:#04X}\nGPRAM pointer: {:#06X}",
+			self.format_address(self.machine.execution_pointer()),
+			self.machine.alu.latch_a,
+			self.machine.alu.latch_b,
+			self.machine.alu.latch_c,
+			goto_a,
+			goto_b,
+			goto_decider,
+			stack_top,
+			self.machine.stack_mem[stack_top as usize],
+			stack_offset,
+			self.machine.gpram_pointer()
+		);
+		if self.machine.version == HardwareVersion::V2 {
+			out += &format!("\nINT-GOTO: a={:#04X} b={:#04X}\nInterrupts: enabled={} in-progress={} queued={}", int_goto_a, int_goto_b, int_enabled, int_in_progress, int_count);
+		}
+		out
+	}
+	/// Rows of 16 values, `digits` is the number of hex digits per value
+	fn format_memory<N: Into<u32> + Copy>(mem: &[N], start: u16, len_: usize, digits: usize) -> String {
+		let mut lines = Vec::<String>::new();
+		let end: usize = (start as usize + len_).min(mem.len());
+		let mut row_start: usize = start as usize;
+		while row_start < end {
+			let row_end = (row_start + 16).min(end);
+			let values: Vec<String> = mem[row_start..row_end].iter().map(|n| format!("{:0digits$X}", (*n).into(), digits = digits)).collect();
+			lines.push(format!("{:#06X}: {}", row_start, values.join(" ")));
+			row_start = row_end;
+		}
+		lines.join("\n")
+	}
+	/// Address with its source location, for example `0x0012 tetris:40 (@anchor(loop)+3)`
+	pub fn format_address(&self, address: u16) -> String {
+		format!("{:#06X} {}", address, self.program.describe_address(address))
+	}
+	/// Number, anchor name or anchor name + offset
+	fn parse_address(&self, arg: &str) -> Result<u16, String> {
+		if let Ok(n) = Self::parse_number(arg) {
+			return Ok(n);
+		}
+		let (name, offset): (&str, u16) = match arg.split_once('+') {
+			Some((name, offset_raw)) => (name, Self::parse_number(offset_raw)?),
+			None => (arg, 0)
+		};
+		match self.program.anchors.get(name) {
+			Some(address) => Ok((*address as u16).wrapping_add(offset)),
+			None => Err(format!("\"{}\" is not a number or an anchor", name))
+		}
+	}
+	fn parse_number(arg: &str) -> Result<u16, String> {
+		match arg.strip_prefix("0x").or(arg.strip_prefix("0X")) {
+			Some(hex_raw) => to_string_err(u16::from_str_radix(hex_raw, 16)),
+			None => to_string_err(arg.parse::<u16>())
+		}
+	}
+	fn get_arg<'a>(args: &'a [&'a str], i: usize) -> Result<&'a &'a str, String> {
+		match args.get(i) {
+			Some(arg) => Ok(arg),
+			None => Err(format!("Missing argument #{}, type `help` for usage", i + 1))
+		}
+	}
+}
+
+/// Reads commands from stdin until `quit` or EOF
+pub fn run_repl<T: GpioInterface>(debugger: &mut Debugger<T>) {
+	println!("Debugger started at {}, type `help` for a list of commands", debugger.format_address(debugger.machine.execution_pointer()));
+	let stdin = io::stdin();
+	loop {
+		print!("(debug) ");
+		io::stdout().flush().unwrap();
+		let mut line = String::new();
+		match stdin.lock().read_line(&mut line) {
+			Ok(0) => break,// EOF
+			Ok(_) => {},
+			Err(e) => {
+				println!("Could not read input: {}", e);
+				break;
+			}
+		}
+		match line.trim() {
+			"quit" | "q" => break,
+			command => match debugger.execute_command(command) {
+				Ok(out) => if !out.is_empty() {println!("{}", out)},
+				Err(msg) => println!("Error: {}", msg)
+			}
+		}
+	}
+}
@@ -53,6 +53,8 @@ impl GpioInterface for GpioInterfaceDisplay {
 
 struct EguiApp {
 	machine: Machine,
+	/// For showing where emulation errors happened
+	program: compiler::Program,
 	keys: Vec<Key>,
 	interface: GpioInterfaceDisplay,
 	running: bool,
@@ -63,9 +65,10 @@ struct EguiApp {
 }
 
 impl EguiApp {
-	pub fn new(_cc: &eframe::CreationContext<'_>, machine: Machine, keys: Vec<Key>, halt_enable: bool) -> Self {
+	pub fn new(_cc: &eframe::CreationContext<'_>, machine: Machine, program: compiler::Program, keys: Vec<Key>, halt_enable: bool) -> Self {
 		Self {
 			machine,
+			program,
 			keys,
 			interface: GpioInterfaceDisplay::new(),
 			running: true,
@@ -114,7 +117,7 @@ impl eframe::App for EguiApp {
 			ui.ctx().request_repaint();
 			if self.is_done {
 				match &self.err_opt {
-					Some(e) => {ui.label(e.to_string());},
+					Some(e) => {ui.label(e.to_string_with_program(&self.program));},
 					None => {ui.label("Machine halted");}
 				}
 			}
@@ -166,7 +169,7 @@ impl eframe::App for EguiApp {
 	}
 }
 
-pub fn start_gui(machine: Machine) {
+pub fn start_gui(machine: Machine, program: compiler::Program) {
 	let mut key_bindings: HashMap<String, Vec<Key>> = HashMap::new();
 	key_bindings.insert(
 		String::from("pong"),
@@ -193,5 +196,5 @@ pub fn start_gui(machine: Machine) {
 	);
 	let halt_enable = false;
 	let native_options = eframe::NativeOptions::default();
-	eframe::run_native("Stack machine emulator", native_options, Box::new(|cc| Ok(Box::new(EguiApp::new(cc, machine, key_bindings.get("tetris").unwrap().clone(), halt_enable))))).unwrap();
+	eframe::run_native("Stack machine emulator", native_options, Box::new(|cc| Ok(Box::new(EguiApp::new(cc, machine, program, key_bindings.get("tetris").unwrap().clone(), halt_enable))))).unwrap();
 }
\ No newline at end of file
@@ -5,8 +5,14 @@ use std::fmt::Write;
 #[allow(unused)]
 use crate::prelude::*;
 
+// CONSTS
+/// The timer board has its own 1 MHz crystal
+pub const TIMER_BASE_CLOCK_HZ: u64 = 1_000_000;
+/// Used to convert emulated clock cycles into time for the timer board
+pub const DEFAULT_MACHINE_CLOCK_HZ: u64 = 6_000_000;
+
 /// Generalization of components
-trait MachineComponent {
+pub trait MachineComponent {
 	/// Initial state
 	fn new() -> Self;
 }
@@ -174,20 +180,20 @@ impl MachineComponent for GeneralMemController {
 }
 
 /// Read and Write pointers are incremented AFTER push/pop
-#[cfg(feature = "version_2")]
 struct InterruptHandler {
 	pub enabled: bool,
+	/// Set by the special interrupt `CALL` and cleared by `RETURN INT`
+	pub in_progress: bool,
 	pub interrupt_queue: [u8; 256],
 	pub read_pointer: u8,
 	pub write_pointer: u8,
 	pub interrupt_counter: u8
 }
 
-#[cfg(feature = "version_2")]
 impl InterruptHandler {
 	fn push(&mut self, source: u8, extra: u8) {
 		let interrupt_code: u8 = if source >= 4 {
-			(source & 0b111) & ((extra & 0xF) << 4)
+			(source & 0b111) | ((extra & 0xF) << 4)
 		}
 		else {
 			source & 0b111// Timers, no extra info
@@ -196,19 +202,37 @@ impl InterruptHandler {
 		self.write_pointer = self.write_pointer.wrapping_add(1);
 		self.interrupt_counter = self.interrupt_counter.wrapping_add(1);
 	}
+	/// Used for `INT-CODE`, does not change anything if the queue is empty
 	fn pop(&mut self) -> u8 {
+		if self.interrupt_counter == 0 {
+			return 0;
+		}
 		let out = self.interrupt_queue[self.read_pointer as usize];
 		self.read_pointer = self.read_pointer.wrapping_add(1);
 		self.interrupt_counter = self.interrupt_counter.wrapping_add(255);
 		out
 	}
+	/// Whether the next instruction should be replaced with the special interrupt `CALL`
+	fn interrupt_ready(&self) -> bool {
+		self.enabled && !self.in_progress && self.interrupt_counter != 0
+	}
+	/// `CALL INT`, interrupts are disabled while in the handler and any functions it calls
+	fn begin_handler(&mut self) {
+		self.in_progress = true;
+		self.enabled = false;
+	}
+	/// `RETURN INT`
+	fn end_handler(&mut self) {
+		self.in_progress = false;
+		self.enabled = true;
+	}
 }
 
-#[cfg(feature = "version_2")]
 impl MachineComponent for InterruptHandler {
 	fn new() -> Self {
 		Self {
 			enabled: false,// Hardware flag is set to false on startup
+			in_progress: false,
 			interrupt_queue: [0; 256],
 			read_pointer: 0,
 			write_pointer: 0,
@@ -218,45 +242,94 @@ impl MachineComponent for InterruptHandler {
 }
 
 /// Timers
-#[cfg(feature = "version_2")]
-struct Timers {
+pub struct Timers {
 	/// 1 MHz in hardware, 36 bits used
-	base_timer: u64,
-	interrupt_timers_state: [u8; 4],
+	pub base_timer: u64,
+	pub interrupt_timers_state: [u8; 4],
 	/// Max value, timer will roll over after going PAST this, not too it. This is so that 255 will cause the timer to rollover normally.
-	interrupt_timers_max: [u8; 4],
+	pub interrupt_timers_max: [u8; 4],
 	/// Timebase 3:0, Interrupt 4, Enable 5
-	interrupt_timers_timebase_and_enable: [u8; 4],
+	pub interrupt_timers_timebase_and_enable: [u8; 4],
 	/// Same as the bus input except only first 2 bits are used
-	int_and_main_timer_address: u8
+	pub int_and_main_timer_address: u8,
+	/// Machine clock cycles * 1 MHz left over from the last update that didn't add up to a whole base timer tick
+	machine_clock_remainder: u64
 }
 
-#[cfg(feature = "version_2")]
 impl Timers {
-	/// Updates interrupt timers
+	/// Converts machine clock cycles to ticks of the 1 MHz base clock and updates with those
+	/// Returns: Vec of interrupt codes
+	pub fn update_from_machine_clock(&mut self, clock_cycles: u64, machine_clock_hz: u64) -> Vec<u8> {
+		// No time passes with a 0 Hz clock
+		if machine_clock_hz == 0 {
+			return Vec::new();
+		}
+		let total: u64 = self.machine_clock_remainder + clock_cycles * TIMER_BASE_CLOCK_HZ;
+		self.machine_clock_remainder = total % machine_clock_hz;
+		self.update(total / machine_clock_hz)
+	}
+	/// Updates base timer and interrupt timers by `base_ticks` of the 1 MHz clock
 	/// Returns: Vec of interrupt codes
-	pub fn update(&mut self, clock_cycles: u64) -> Vec<u8> {
-		self.base_timer = self.base_timer.wrapping_add(clock_cycles) & 0x0000000FFFFFFFFF;// 36 bits used
+	/// ```
+	/// use stack_machine::emulator::{Timers, MachineComponent};
+	/// let mut timers = Timers::new();
+	/// timers.set_int_timer_max(49);
+	/// timers.set_int_timer_timebase_and_enable(0x30);// 1 MHz, interrupt, enable
+	/// assert!(timers.update(49).is_empty());
+	/// assert_eq!(timers.update(1), vec![0]);// Rollover after 50 ticks
+	/// assert_eq!(timers.interrupt_timers_state[0], 0);
+	/// ```
+	pub fn update(&mut self, base_ticks: u64) -> Vec<u8> {
+		// Not masked yet so the edges of the base timer bits can be counted across the 36-bit rollover
+		let prev_base_timer: u64 = self.base_timer;
+		let new_base_timer: u64 = prev_base_timer + base_ticks;
+		self.base_timer = new_base_timer & 0x0000000FFFFFFFFF;// 36 bits used
 		let mut out = Vec::new();
 		// For each of the 4 timers
 		for i in 0..4_usize {
-			if (self.interrupt_timers_timebase_and_enable[i] >> 5) & 1 == 1 {
-				let max = self.interrupt_timers_max[i];
-				let to_overflow = max - self.interrupt_timers_state[i];
-				if clock_cycles >= (to_overflow as u64) {
-					let diff = clock_cycles - (to_overflow as u64);
-					let n_overflows = diff / ((max as u64) + 1);
-					if (self.interrupt_timers_timebase_and_enable[i] >> 4) & 1 == 1 {// Check whether this timer can cause interrupts
-						for _ in 0..n_overflows {
-							out.push(i as u8 & 0b111);
-						}
+			let config: u8 = self.interrupt_timers_timebase_and_enable[i];
+			if (config >> 5) & 1 == 1 {
+				// Timebase addresses every even-indexed bit of the base timer, so each one is 4 times slower than the last
+				let timebase_shift: u64 = ((config & 0xF) as u64) * 2;
+				let edges: u64 = (new_base_timer >> timebase_shift) - (prev_base_timer >> timebase_shift);
+				let n_rollovers = self.count_interrupt_timer(i, edges);
+				if (config >> 4) & 1 == 1 {// Check whether this timer can cause interrupts
+					for _ in 0..n_rollovers {
+						out.push(i as u8 & 0b111);
 					}
 				}
-				// TODO: Update count
 			}
 		}
 		out
 	}
+	/// Adds `edges` to interrupt timer `i`
+	/// Returns: Number of times it rolled over past its max value
+	fn count_interrupt_timer(&mut self, i: usize, edges: u64) -> u64 {
+		let period: u64 = self.interrupt_timers_max[i] as u64 + 1;
+		let mut state: u64 = self.interrupt_timers_state[i] as u64;
+		let mut edges: u64 = edges;
+		// If the max was set below the current count then the comparator won't reset it until after the 8-bit counter wraps around to 0
+		if state >= period {
+			let to_wrap: u64 = 256 - state;
+			if edges < to_wrap {
+				self.interrupt_timers_state[i] = (state + edges) as u8;
+				return 0;
+			}
+			edges -= to_wrap;
+			state = 0;
+		}
+		let total: u64 = state + edges;
+		self.interrupt_timers_state[i] = (total % period) as u8;
+		total / period
+	}
+	/// `MAIN-TIMER`, bits 4 - 11, 12 - 19, 20 - 27, or 28 - 35 depending on the address
+	pub fn read_main_timer(&self) -> u8 {
+		((self.base_timer >> (4 + (self.int_and_main_timer_address as u64) * 8)) & 0xFF) as u8
+	}
+	/// `INT-TIMER`
+	pub fn read_int_timer(&self) -> u8 {
+		self.interrupt_timers_state[self.int_and_main_timer_address as usize]
+	}
 	pub fn set_int_and_main_timer_address(&mut self, bus_value: u8) {
 		self.int_and_main_timer_address = bus_value & 0b11;
 		if (bus_value >> 2) & 1 == 1 {// Bit 2 is a flag to clear given interrupt timer
@@ -266,9 +339,11 @@ impl Timers {
 	pub fn set_int_timer_max(&mut self, bus_value: u8) {
 		self.interrupt_timers_max[self.int_and_main_timer_address as usize] = bus_value;
 	}
+	pub fn set_int_timer_timebase_and_enable(&mut self, bus_value: u8) {
+		self.interrupt_timers_timebase_and_enable[self.int_and_main_timer_address as usize] = bus_value & 0b111111;
+	}
 }
 
-#[cfg(feature = "version_2")]
 impl MachineComponent for Timers {
 	fn new() -> Self {
 		Self {
@@ -276,13 +351,16 @@ impl MachineComponent for Timers {
 			interrupt_timers_state: [0; 4],
 			interrupt_timers_max: [255; 4],
 			interrupt_timers_timebase_and_enable: [0; 4],
-			int_and_main_timer_address: 0
+			int_and_main_timer_address: 0,
+			machine_clock_remainder: 0
 		}
 	}
 }
 
 /// Represents state of entire computer
 pub struct Machine {
+	/// Which hardware to emulate, all the version 2 components are still here but are not used for version 1
+	pub version: HardwareVersion,
 	pub prog_mem: [u16; POWER_16],
 	pub stack_mem: [u8; POWER_16],
 	pub general_mem: [u8; POWER_16],
@@ -295,24 +373,31 @@ pub struct Machine {
 	goto_latch_a: u8,
 	goto_latch_b: u8,
 	goto_decider_latch: bool,
+	int_goto_latch_a: u8,
+	int_goto_latch_b: u8,
+	/// Vector graphics board inputs A - D, nothing is done with these yet
+	pub vectors: [u8; 4],
+	/// Last byte sent to the flash programming interface
+	pub flash_interface_data: u8,
 	program_size: u16,
 	clock_counter: u16,
 	pub clock_counter_perf_tracking: u128,
-	#[cfg(feature = "version_2")]
+	/// Only used to convert emulated clock cycles into time for the timers
+	pub machine_clock_hz: u64,
 	interrupt_handler: InterruptHandler,
-	#[cfg(feature = "version_2")]
 	timers: Timers
 }
 
 impl Machine {
 	/// Creates new machine with given program
-	pub fn new(prog: Vec<u16>) -> Self {
+	pub fn new(prog: Vec<u16>, version: HardwareVersion) -> Self {
 		let mut prog_mem = [0; POWER_16];
 		for (i, b) in prog.iter().enumerate() {
 			prog_mem[i] = *b;
 		}
 		// Done
 		Self {
+			version,
 			prog_mem,
 			stack_mem: [0; POWER_16],
 			general_mem: [0; POWER_16],
@@ -325,43 +410,71 @@ impl Machine {
 			goto_latch_a: 0,
 			goto_latch_b: 0,
 			goto_decider_latch: false,
+			int_goto_latch_a: 0,
+			int_goto_latch_b: 0,
+			vectors: [0; 4],
+			flash_interface_data: 0,
 			program_size: prog.len() as u16,
 			clock_counter: 0,
 			clock_counter_perf_tracking: 0,
-			#[cfg(feature = "version_2")]
+			machine_clock_hz: DEFAULT_MACHINE_CLOCK_HZ,
 			interrupt_handler: InterruptHandler::new(),
-			#[cfg(feature = "version_2")]
 			timers: Timers::new()
 		}
 	}
 	/// Executes 1 instruction
 	/// Returns: Ok(whether to stop the clock (HALT)) or Err(EmulationError)
 	pub fn execute_instruction<T: GpioInterface>(&mut self, gpio_interface: &mut T) -> Result<bool, EmulationError> {
-		// Check that execution pointer is within limits
-		if self.execution_pointer >= self.program_size {
+		// Version 2 feature to load from GPRAM
+		let from_gpram: bool = self.version == HardwareVersion::V2 && self.execution_pointer >> 15 & 1 == 1;
+		// Check that execution pointer is within limits, GPRAM can always be run
+		if self.execution_pointer >= self.program_size && !from_gpram {
 			return Err(self.err_enum_to_err(EmulationErrorEnum::ExecutionPointerExceededProgramSize));
 		}
 		// Get instruction
 		let instruction: u16 = {
 			let mut out= self.prog_mem[self.execution_pointer as usize];
-			// Version 2 feature to load from GPRAM
-			#[cfg(feature = "version_2")]
-			if self.execution_pointer >> 15 & 1 == 1 {
+			if from_gpram {
 				let gpram_start: u16 = (self.execution_pointer & 0x7FFF) << 1;
 				out = (self.general_mem[gpram_start as usize] as u16) | ((self.general_mem[gpram_start as usize + 1] as u16) << 8);
 			}
 			out
 		};
+		// FOR VERSION 2: If there is an interrupt queued up the instruction is overridden with the interrupt call (0x0015, `CALL INT`)
+		let instruction: u16 = match self.version == HardwareVersion::V2 && self.interrupt_handler.interrupt_ready() {
+			true => {
+				debug_print(&format!("  Interrupt, count={}", self.interrupt_handler.interrupt_counter));
+				0x0015
+			},
+			false => instruction
+		};
 		let second_byte: u8 = ((instruction >> 8) & 255u16) as u8;
 		let opcode: u8 = (instruction & 15u16) as u8;
 		let alu_opcode: u8 = ((instruction >> 4) & 0x000Fu16) as u8;
 		// Bus read/write addresses, may not be used
 		let bus_write_addr = second_byte & 0x0F;
 		let bus_read_addr = (second_byte >> 4) & 0x0F;
+		// FOR VERSION 2: Opcodes 8 - 11 are interpreted as MOVE/WRITE with the 5th bit of the bus addresses set
+		/*
+		8. `MOVE` - MSBs TX=1, RX=0
+		9. `MOVE` - MSBs TX=0, RX=1
+		10. `MOVE` - MSBs TX=1, RX=1
+		11. `WRITE` - MSBs RX=1
+		*/
+		let (opcode, bus_write_addr, bus_read_addr): (u8, u8, u8) = match (self.version, opcode) {
+			(HardwareVersion::V2, 8) => (0, bus_write_addr | 0x10, bus_read_addr),
+			(HardwareVersion::V2, 9) => (0, bus_write_addr, bus_read_addr | 0x10),
+			(HardwareVersion::V2, 10) => (0, bus_write_addr | 0x10, bus_read_addr | 0x10),
+			(HardwareVersion::V2, 11) => (1, bus_write_addr, bus_read_addr | 0x10),
+			(_, n) => (n, bus_write_addr, bus_read_addr)
+		};
+		// FOR VERSION 2: Bit 4 is a flag for CALL, RETURN and CONFIG-INT
+		let flag_bit_4: bool = self.version == HardwareVersion::V2 && (instruction >> 4) & 1 == 1;
 		// Debug print
 		debug_print(&format!("Instruction={:#X}(#{:#X}), opcode={:#X}", instruction, self.execution_pointer, opcode));
 		// Match opcode
 		let mut halt: bool = false;
+		let mut increment_execution_pointer: bool = true;
 		let instruction_clock_counts: u16 = match opcode {
 			0 => {// MOVE
 				// Get next value
@@ -376,10 +489,9 @@ impl Machine {
 				5
 			},
 			1 => {// WRITE
-				let read_addr = (second_byte >> 4) & 0x0F;
 				let bus_value = ((instruction >> 4) & 0x00FF) as u8;
-				debug_print(&format!("  WRITE read_addr={:#X}, bus_value={:#X}", read_addr, bus_value));
-				let res = self.send_bus_value(read_addr, bus_value, gpio_interface);
+				debug_print(&format!("  WRITE read_addr={:#X}, bus_value={:#X}", bus_read_addr, bus_value));
+				let res = self.send_bus_value(bus_read_addr, bus_value, gpio_interface);
 				self.err_enum_result_to_err_result(res)?;
 				// A/B clock cycles
 				5
@@ -406,13 +518,29 @@ impl Machine {
 				// Push return address
 				self.call_stack_top = self.call_stack_top.wrapping_add(1);
 				self.call_stack[self.call_stack_top as usize] = self.execution_pointer;
-				self.goto();
+				if flag_bit_4 {// Interrupt handler, uses the interrupt GOTO latches
+					self.interrupt_handler.begin_handler();
+					self.int_goto();
+				}
+				else {
+					self.goto();
+				}
 				// A/B clock cycles
 				2
 			},
 			6 => {// RETURN
 				self.execution_pointer = self.call_stack[self.call_stack_top as usize];
 				self.call_stack_top = self.call_stack_top.wrapping_sub(1);
+				if flag_bit_4 {// Returning from the interrupt handler, the interrupted instruction still has to be run so the PC isn't incremented
+					self.interrupt_handler.end_handler();
+					increment_execution_pointer = false;
+				}
+				// A/B clock cycles
+				1
+			},
+			7 if self.version == HardwareVersion::V2 => {// CONFIG-INT
+				debug_print(&format!("  CONFIG-INT enable={}", flag_bit_4));
+				self.interrupt_handler.enabled = flag_bit_4;
 				// A/B clock cycles
 				1
 			},
@@ -422,8 +550,16 @@ impl Machine {
 		let total_clock_cycles: u16 = (instruction_clock_counts+1)*4;
 		self.clock_counter = self.clock_counter.wrapping_add(total_clock_cycles);
 		self.clock_counter_perf_tracking += total_clock_cycles as u128;
+		// FOR VERSION 2: Timer board runs off of its own clock and may queue interrupts
+		if self.version == HardwareVersion::V2 {
+			for interrupt_source in self.timers.update_from_machine_clock(total_clock_cycles as u64, self.machine_clock_hz) {
+				self.interrupt_handler.push(interrupt_source, 0);
+			}
+		}
 		// Increment execution pointer
-		self.execution_pointer = self.execution_pointer.wrapping_add(1);
+		if increment_execution_pointer {
+			self.execution_pointer = self.execution_pointer.wrapping_add(1);
+		}
 		debug_print("");
 		// Done
 		Ok(halt)
@@ -476,6 +612,27 @@ impl Machine {
 			15 => {// GPIO-WRITE-B
 				gpio_interface.write_b(bus_value);
 			},
+			16 if self.version == HardwareVersion::V2 => {// INT-GOTO-A
+				self.int_goto_latch_a = bus_value;
+			},
+			17 if self.version == HardwareVersion::V2 => {// INT-GOTO-B
+				self.int_goto_latch_b = bus_value;
+			},
+			18..=21 if self.version == HardwareVersion::V2 => {// VECTORS-A - VECTORS-D
+				self.vectors[(read_addr - 18) as usize] = bus_value;
+			},
+			22 if self.version == HardwareVersion::V2 => {// INT-AND-MAIN-TIMER-ADDRESS
+				self.timers.set_int_and_main_timer_address(bus_value);
+			},
+			23 if self.version == HardwareVersion::V2 => {// INT-TIMER-CONFIG-MAX
+				self.timers.set_int_timer_max(bus_value);
+			},
+			24 if self.version == HardwareVersion::V2 => {// INT-TIMER-CONFIG-TIMEBASE-AND-ENABLE
+				self.timers.set_int_timer_timebase_and_enable(bus_value);
+			},
+			25 if self.version == HardwareVersion::V2 => {// FLASH
+				self.flash_interface_data = bus_value;
+			},
 			_ => return Err(EmulationErrorEnum::InvalidBusReadAddr(read_addr))
 		}
 		Ok(())
@@ -509,15 +666,36 @@ impl Machine {
 			8 => {// GPIO-READ-A
 				gpio_interface.read_a()
 			},
-			9 => {// CLK-COUNTER-A
+			9 if self.version == HardwareVersion::V1 => {// CLK-COUNTER-A
 				(self.clock_counter & 0x00FF) as u8
 			},
-			10 => {// CLK-COUNTER-B
+			10 if self.version == HardwareVersion::V1 => {// CLK-COUNTER-B
 				((self.clock_counter >> 8) & 0x00FF) as u8
 			},
+			9 if self.version == HardwareVersion::V2 => {// MAIN-TIMER
+				self.timers.read_main_timer()
+			},
+			10 if self.version == HardwareVersion::V2 => {// INT-TIMER
+				self.timers.read_int_timer()
+			},
 			11 => {// GPIO-READ-B
 				gpio_interface.read_b()
 			},
+			12 if self.version == HardwareVersion::V2 => {// INT-CODE
+				self.interrupt_handler.pop()
+			},
+			13 if self.version == HardwareVersion::V2 => {// INT-COUNT
+				self.interrupt_handler.interrupt_counter
+			},
+			14 if self.version == HardwareVersion::V2 => {// GET-STACK-OFFSET
+				self.stack_controller.offset
+			},
+			15 if self.version == HardwareVersion::V2 => {// GET-GOTO-A
+				self.goto_latch_a
+			},
+			16 if self.version == HardwareVersion::V2 => {// GET-GOTO-B
+				self.goto_latch_b
+			},
 			_ => return Err(EmulationErrorEnum::InvalidBusWriteAddr(write_addr))
 		})
 	}
@@ -526,6 +704,46 @@ impl Machine {
 		debug_print(&format!("  GOTO curr pointer={:#X}, next={:#X} + 1", self.execution_pointer, next_pointer));
 		self.execution_pointer = next_pointer;
 	}
+	fn int_goto(&mut self) {
+		let next_pointer = self.int_goto_latch_a as u16 + ((self.int_goto_latch_b as u16) * 256);
+		debug_print(&format!("  INT GOTO curr pointer={:#X}, next={:#X} + 1", self.execution_pointer, next_pointer));
+		self.execution_pointer = next_pointer;
+	}
+	/// Queues an interrupt from one of the 4 configurable external inputs (sources 4 - 7), `extra` is the 4 bits from the rest of the header
+	/// Only does anything for version 2
+	pub fn trigger_interrupt(&mut self, source: u8, extra: u8) {
+		if self.version == HardwareVersion::V2 {
+			self.interrupt_handler.push(source, extra);
+		}
+	}
+	// Read-only state for the debugger
+	/// Address of the next instruction to be executed
+	pub fn execution_pointer(&self) -> u16 {
+		self.execution_pointer
+	}
+	/// (GOTO-A, GOTO-B, GOTO-DECIDER)
+	pub fn goto_latches(&self) -> (u8, u8, bool) {
+		(self.goto_latch_a, self.goto_latch_b, self.goto_decider_latch)
+	}
+	/// (INT-GOTO-A, INT-GOTO-B)
+	pub fn int_goto_latches(&self) -> (u8, u8) {
+		(self.int_goto_latch_a, self.int_goto_latch_b)
+	}
+	/// (top pointer, offset as written by `set-stack-offset`)
+	pub fn stack_pointers(&self) -> (u16, u8) {
+		(self.stack_controller.top_pointer, self.stack_controller.offset)
+	}
+	pub fn gpram_pointer(&self) -> u16 {
+		self.general_mem_controller.pointer
+	}
+	/// Return addresses, oldest first
+	pub fn call_stack(&self) -> Vec<u16> {
+		(1..=self.call_stack_top as usize).map(|i| self.call_stack[i]).collect()
+	}
+	/// (interrupts enabled, handler in progress, number of queued interrupts)
+	pub fn interrupt_state(&self) -> (bool, bool, u8) {
+		(self.interrupt_handler.enabled, self.interrupt_handler.in_progress, self.interrupt_handler.interrupt_counter)
+	}
 	fn err_enum_to_err(&self, enum_: EmulationErrorEnum) -> EmulationError {
 		EmulationError::new(enum_, self.execution_pointer)
 	}
@@ -614,6 +832,13 @@ impl EmulationError {
 	pub fn to_string(&self) -> String {
 		format!("{:?} with the program pointer at {}", self.enum_, self.prog_addr)
 	}
+	pub fn prog_addr(&self) -> u16 {
+		self.prog_addr
+	}
+	/// Same as `Self::to_string()` but with the source location from the assembler, for example `tetris:142 (@anchor(drop-piece)+3)`
+	pub fn to_string_with_program(&self, program: &compiler::Program) -> String {
+		format!("{:?} at {:#06X} {}", self.enum_, self.prog_addr, program.describe_address(self.prog_addr))
+	}
 }
 
 #[inline]
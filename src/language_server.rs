@@ -0,0 +1,490 @@
+//! Language server for editors, talks LSP (JSON-RPC with `Content-Length` headers) over stdio, run with the `stack_machine_lsp` binary
+//! Messages are handled one at a time by `LanguageServer::handle()` so that it can be scripted
+//! The hardware version (1 or 2, default 2) and `@ifdef` names can be set with the `initializationOptions` `{"version": 2, "defines": ["NAME"]}`
+//! Documents are always sent whole (`textDocumentSync` = 1), positions are in UTF-16 code units like LSP wants
+//! * Diagnostics: errors from `compiler_pipeline_at()`, and warnings when the document assembles
+//! * Completion: assembly words from `AssemblerConfig` for the position in the instruction
+//! * Go to definition and find references: anchors in the same document, including local and anonymous anchors
+//! * Hover: program address and encoding of every instruction the line under the cursor assembles to, and the address of anchors
+
+use std::{collections::HashMap, io::{self, BufRead, Write}, path::PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::prelude::*;
+use crate::compiler::{CompilerError, CompilerWarning, ErrorLocation, Program, Span, anchor::{AnchorScope, AnchorTarget}, assembly_encode::AssemblerWordContext, disassemble::disassemble_instruction, macros::MacroEnum, syntax_tree::SyntaxTreeNode};
+
+/// JSON-RPC error codes
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const PARSE_ERROR: i64 = -32700;
+/// LSP `DiagnosticSeverity`
+const SEVERITY_ERROR: u8 = 1;
+const SEVERITY_WARNING: u8 = 2;
+/// LSP `CompletionItemKind::Keyword`
+const COMPLETION_KIND_KEYWORD: u8 = 14;
+/// Most instructions listed by hover, `@data` and `@binfile` can be thousands
+const MAX_HOVER_INSTRUCTIONS: usize = 16;
+/// Largest message body read, so that a bad Content-Length header can't allocate any amount of memory
+const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Open document
+struct Document {
+	text: String,
+	/// None if it doesn't assemble
+	program_opt: Option<Program>
+}
+
+/// Definition or reference of an anchor in a document
+#[derive(Clone, Debug)]
+struct AnchorUse {
+	target: AnchorTarget,
+	/// Anchor name, as written
+	span: Span,
+	definition: bool
+}
+
+pub struct LanguageServer {
+	config: AssemblerConfig,
+	/// URI -> document
+	documents: HashMap<String, Document>,
+	shutdown: bool,
+	/// Set when `exit` is received, 0 if `shutdown` was received first
+	pub exit_code_opt: Option<i32>
+}
+
+impl LanguageServer {
+	pub fn new(config: AssemblerConfig) -> Self {
+		Self {
+			config,
+			documents: HashMap::new(),
+			shutdown: false,
+			exit_code_opt: None
+		}
+	}
+	/// Reads messages from `input` and writes replies to `output` until `exit` or the end of the input
+	pub fn run(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> Result<(), String> {
+		while self.exit_code_opt.is_none() {
+			let body: String = match read_message(input)? {
+				Some(body) => body,
+				None => break
+			};
+			let replies: Vec<Value> = match serde_json::from_str::<Value>(&body) {
+				Ok(message) => self.handle(&message),
+				Err(error) => vec![error_response(&Value::Null, PARSE_ERROR, &error.to_string())]
+			};
+			for reply in replies {
+				let reply_str: String = reply.to_string();
+				to_string_err(write!(output, "Content-Length: {}\r\n\r\n{}", reply_str.len(), reply_str))?;
+			}
+			to_string_err(output.flush())?;
+		}
+		Ok(())
+	}
+	/// Handles one request or notification, returns the response (for requests) and any notifications to send
+	pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+		let method: &str = message["method"].as_str().unwrap_or("");
+		let params: &Value = &message["params"];
+		// Notifications don't have an ID
+		let id_opt: Option<&Value> = message.get("id");
+		let result: Result<Value, (i64, String)> = match method {
+			"initialize" => self.initialize(params),
+			"shutdown" => {
+				self.shutdown = true;
+				Ok(Value::Null)
+			},
+			"exit" => {
+				self.exit_code_opt = Some(match self.shutdown {
+					true => 0,
+					false => 1
+				});
+				return Vec::new();
+			},
+			"textDocument/didOpen" => {
+				let uri: String = params["textDocument"]["uri"].as_str().unwrap_or("").to_owned();
+				let text: String = params["textDocument"]["text"].as_str().unwrap_or("").to_owned();
+				return vec![self.update_document(uri, text)];
+			},
+			"textDocument/didChange" => {
+				let uri: String = params["textDocument"]["uri"].as_str().unwrap_or("").to_owned();
+				// Whole document, so only the last change matters
+				return match params["contentChanges"].as_array().and_then(|changes| changes.last()).and_then(|change| change["text"].as_str()) {
+					Some(text) => vec![self.update_document(uri, text.to_owned())],
+					None => Vec::new()
+				};
+			},
+			"textDocument/didClose" => {
+				let uri: String = params["textDocument"]["uri"].as_str().unwrap_or("").to_owned();
+				self.documents.remove(&uri);
+				return vec![diagnostics_notification(&uri, Vec::new())];
+			},
+			"textDocument/completion" => self.with_document(params, |server, document, offset| Ok(server.completion(document, offset))),
+			"textDocument/definition" => self.with_document(params, |_, document, offset| Ok(anchor_locations(params, document, offset, false, true))),
+			"textDocument/references" => {
+				let include_declaration: bool = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+				self.with_document(params, |_, document, offset| Ok(anchor_locations(params, document, offset, true, include_declaration)))
+			},
+			"textDocument/hover" => self.with_document(params, |server, document, offset| Ok(server.hover(document, offset))),
+			_ => Err((METHOD_NOT_FOUND, format!("Unknown method \"{}\"", method)))
+		};
+		// Done
+		match id_opt {
+			Some(id) => vec![match result {
+				Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+				Err((code, error_message)) => error_response(id, code, &error_message)
+			}],
+			None => Vec::new()
+		}
+	}
+	fn initialize(&mut self, params: &Value) -> Result<Value, (i64, String)> {
+		let options: &Value = &params["initializationOptions"];
+		if let Some(version_raw) = options["version"].as_u64() {
+			let version: HardwareVersion = HardwareVersion::from_arg(&version_raw.to_string()).map_err(|error| (INVALID_PARAMS, error))?;
+			self.config = resources::load_assembler_config(version).map_err(|error| (INVALID_PARAMS, error))?;
+		}
+		if let Some(defines) = options["defines"].as_array() {
+			self.config.defines = defines.iter().filter_map(|name| name.as_str()).map(|name| name.to_owned()).collect();
+		}
+		Ok(json!({
+			"capabilities": {
+				"textDocumentSync": 1,
+				"completionProvider": {"triggerCharacters": [" "]},
+				"definitionProvider": true,
+				"referencesProvider": true,
+				"hoverProvider": true
+			},
+			"serverInfo": {"name": "stack_machine_lsp"}
+		}))
+	}
+	/// Assembles the new text, returns the diagnostics notification
+	fn update_document(&mut self, uri: String, text: String) -> Value {
+		let path: PathBuf = uri_to_path(&uri);
+		let name_opt: Option<String> = path.file_name().map(|name| name.to_string_lossy().into_owned());
+		let mut diagnostics = Vec::<Value>::new();
+		let program_opt: Option<Program> = match compiler::compiler_pipeline_at(&text, name_opt.as_deref(), &path, &self.config) {
+			Ok(program) => {
+				for warning in &program.warnings {
+					diagnostics.push(warning_diagnostic(&text, name_opt.as_deref(), warning));
+				}
+				Some(program)
+			},
+			Err(errors) => {
+				for error in &errors {
+					diagnostics.push(error_diagnostic(&text, name_opt.as_deref(), error));
+				}
+				None
+			}
+		};
+		self.documents.insert(uri.clone(), Document{text, program_opt});
+		// Done
+		diagnostics_notification(&uri, diagnostics)
+	}
+	/// Calls `f` with the document and byte offset of a `TextDocumentPositionParams`
+	fn with_document(&self, params: &Value, f: impl FnOnce(&Self, &Document, usize) -> Result<Value, (i64, String)>) -> Result<Value, (i64, String)> {
+		let uri: &str = params["textDocument"]["uri"].as_str().unwrap_or("");
+		let document: &Document = match self.documents.get(uri) {
+			Some(document) => document,
+			None => {return Err((INVALID_PARAMS, format!("Document \"{}\" isn't open", uri)));}
+		};
+		let position: &Value = &params["position"];
+		let offset: usize = position_to_offset(&document.text, position["line"].as_u64().unwrap_or(0) as usize, position["character"].as_u64().unwrap_or(0) as usize);
+		f(self, document, offset)
+	}
+	/// Assembly words that can go where the cursor is in an instruction
+	fn completion(&self, document: &Document, offset: usize) -> Value {
+		let line_start: usize = document.text[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+		let before: &str = &document.text[line_start..offset];
+		// Nothing in comments or macros
+		let instruction_before: &str = before.rsplit(';').next().unwrap_or("");
+		if before.contains(COMMENT_BEGIN) || instruction_before.trim_start().starts_with(MACRO_BEGIN) {
+			return json!([]);
+		}
+		let tokens: Vec<&str> = instruction_before.split_whitespace().collect();
+		// Index of the token being typed and what has been typed of it
+		let (index, typed): (usize, &str) = match instruction_before.ends_with(|c: char| c.is_whitespace()) || tokens.is_empty() {
+			true => (tokens.len(), ""),
+			false => (tokens.len() - 1, tokens[tokens.len() - 1])
+		};
+		let opcode_opt: Option<String> = tokens.first().filter(|_| index > 0).and_then(|raw| self.config.encode_word(AssemblerWordContext::Opcode, raw)).map(|word| word.name);
+		let alu_opcode_given: bool = tokens.get(1).filter(|_| index > 1).and_then(|raw| self.config.encode_word(AssemblerWordContext::AluOpcode, raw)).is_some();
+		// Same places that `assemble_instruction()` looks words up
+		let contexts: Vec<AssemblerWordContext> = match (index, opcode_opt.as_deref()) {
+			(0, _) => vec![AssemblerWordContext::Opcode],
+			(1, Some("move")) => vec![AssemblerWordContext::AluOpcode, AssemblerWordContext::ToBus],
+			(2, Some("move")) if alu_opcode_given => vec![AssemblerWordContext::ToBus],
+			(2, Some("move")) | (3, Some("move")) | (2, Some("write")) => vec![AssemblerWordContext::FromBus],
+			(_, Some("move" | "write")) => Vec::new(),
+			(1, Some("call")) => vec![AssemblerWordContext::AfterCall],
+			(1, Some("return")) => vec![AssemblerWordContext::AfterReturn],
+			(1, Some(_)) => vec![AssemblerWordContext::GenericAfterOpcode],
+			_ => Vec::new()
+		};
+		let mut items = Vec::<Value>::new();
+		for context in contexts {
+			let (words, kind): (&Vec<AssemblyWord>, &str) = match context {
+				AssemblerWordContext::Opcode => (&self.config.opcodes, "opcode"),
+				AssemblerWordContext::AluOpcode => (&self.config.alu_opcodes, "ALU opcode"),
+				AssemblerWordContext::ToBus => (&self.config.to_bus, "TX"),
+				AssemblerWordContext::FromBus => (&self.config.from_bus, "RX"),
+				AssemblerWordContext::AfterCall => (&self.config.after_call, "flag"),
+				AssemblerWordContext::AfterReturn => (&self.config.after_return, "flag"),
+				AssemblerWordContext::GenericAfterOpcode => (&self.config.generic_after_opcode, "flag")
+			};
+			for word in words {
+				if word.name.to_lowercase().starts_with(&typed.to_lowercase()) {
+					items.push(json!({
+						"label": word.name,
+						"kind": COMPLETION_KIND_KEYWORD,
+						"detail": format!("{} {}", kind, word.id_)
+					}));
+				}
+			}
+		}
+		// Done
+		Value::Array(items)
+	}
+	/// Markdown with the address of the anchor under the cursor and where the instruction or macro under the cursor is in the program
+	fn hover(&self, document: &Document, offset: usize) -> Value {
+		let program: &Program = match &document.program_opt {
+			Some(program) => program,
+			None => {return Value::Null;}
+		};
+		let mut out = String::new();
+		if let Some(anchor_use) = anchor_uses(&document.text).into_iter().find(|anchor_use| anchor_use.span.begin <= offset && offset <= anchor_use.span.end) {
+			if let AnchorTarget::Named(name) = &anchor_use.target {
+				if let Some(address) = program.anchors.get(name) {
+					out += &format!("`{}` at `{:#06X}`\n\n", name, address);
+				}
+			}
+		}
+		// Every instruction from the line under the cursor, flash then GPRAM
+		let sections = [(0, &program.words, &program.locations)].into_iter().chain(program.gpram_sections.iter().map(|section| (section.address, &section.words, &section.locations)));
+		let mut rows = Vec::<String>::new();
+		for (start, words, locations) in sections {
+			for (i, (word, location)) in words.iter().zip(locations.iter()).enumerate() {
+				if location.file == 0 && location.span.begin <= offset && offset < location.span.end {
+					rows.push(format!("{:#06X}  {:#06X}  {:016b}  {}", start + i, word, word, disassemble_instruction(*word, &self.config).unwrap_or_else(|_| "?".to_owned())));
+				}
+			}
+		}
+		if !rows.is_empty() {
+			let n_rows: usize = rows.len();
+			rows.truncate(MAX_HOVER_INSTRUCTIONS);
+			if n_rows > MAX_HOVER_INSTRUCTIONS {
+				rows.push(format!("... {} more", n_rows - MAX_HOVER_INSTRUCTIONS));
+			}
+			out += &format!("```\n{}\n```", rows.join("\n"));
+		}
+		// Done
+		match out.is_empty() {
+			true => Value::Null,
+			false => json!({"contents": {"kind": "markdown", "value": out.trim_end()}})
+		}
+	}
+}
+
+/// Runs the server on stdin and stdout, with the version 2 config until `initialize` says otherwise
+pub fn language_server_main() {
+	let config: AssemblerConfig = match resources::load_assembler_config(HardwareVersion::V2) {
+		Ok(config) => config,
+		Err(error) => {
+			eprintln!("Unable to load assembler config (the working directory must have `{}`): {}", resources::ASSEMBLER_CONFIG_DIR, error);
+			std::process::exit(1);
+		}
+	};
+	let mut server = LanguageServer::new(config);
+	if let Err(error) = server.run(&mut io::stdin().lock(), &mut io::stdout().lock()) {
+		eprintln!("{}", error);
+		std::process::exit(1);
+	}
+	std::process::exit(server.exit_code_opt.unwrap_or(1));
+}
+
+/// Body of the next message, None at the end of the input
+fn read_message(input: &mut impl BufRead) -> Result<Option<String>, String> {
+	let mut content_length_opt: Option<usize> = None;
+	loop {
+		let mut header = String::new();
+		if to_string_err(input.read_line(&mut header))? == 0 {
+			return Ok(None);
+		}
+		let header: &str = header.trim_end();
+		if header.is_empty() {
+			// Blank lines between messages
+			if content_length_opt.is_some() {
+				break;
+			}
+			continue;
+		}
+		if let Some((name, value)) = header.split_once(':') {
+			if name.eq_ignore_ascii_case("Content-Length") {
+				content_length_opt = Some(to_string_err_with_message(value.trim().parse::<usize>(), "Invalid Content-Length")?);
+			}
+		}
+	}
+	let content_length: usize = content_length_opt.unwrap();
+	if content_length > MAX_MESSAGE_LEN {
+		return Err(format!("Content-Length {} is more than the maximum of {}", content_length, MAX_MESSAGE_LEN));
+	}
+	let mut body: Vec<u8> = vec![0; content_length];
+	to_string_err(input.read_exact(&mut body))?;
+	// Done
+	Ok(Some(to_string_err(String::from_utf8(body))?))
+}
+
+fn error_response(id: &Value, code: i64, message: &str) -> Value {
+	json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn diagnostics_notification(uri: &str, diagnostics: Vec<Value>) -> Value {
+	json!({
+		"jsonrpc": "2.0",
+		"method": "textDocument/publishDiagnostics",
+		"params": {"uri": uri, "diagnostics": diagnostics}
+	})
+}
+
+/// Errors in `@include()`d files are shown at the start of the document with where they are
+fn error_diagnostic(text: &str, name_opt: Option<&str>, error: &CompilerError) -> Value {
+	let mut message: String = error.enum_.to_string();
+	if let Some(note) = &error.message_opt {
+		message += &format!("\nnote: {}", note);
+	}
+	let range: Value = match &error.location_opt {
+		Some(location) => location_range(text, name_opt, location, &mut message),
+		None => range(text, Span::new(0, 0))
+	};
+	json!({"range": range, "severity": SEVERITY_ERROR, "source": "stack_machine", "message": message})
+}
+
+fn warning_diagnostic(text: &str, name_opt: Option<&str>, warning: &CompilerWarning) -> Value {
+	let mut message: String = warning.lint.to_string();
+	let range: Value = location_range(text, name_opt, &warning.location, &mut message);
+	json!({"range": range, "severity": SEVERITY_WARNING, "code": warning.lint.code(), "source": "stack_machine", "message": message})
+}
+
+/// Range of `location` if it is in this document, otherwise the start of the document and the file and line are added to `message`
+fn location_range(text: &str, name_opt: Option<&str>, location: &ErrorLocation, message: &mut String) -> Value {
+	match location.source_name.as_deref() == name_opt {
+		true => range(text, location.span),
+		false => {
+			*message = format!("{}:{}:{}: {}", location.source_name.as_deref().unwrap_or("<input>"), location.line, location.column, message);
+			range(text, Span::new(0, 0))
+		}
+	}
+}
+
+/// Locations of the definitions (and with `references` the references) of the anchor under the cursor
+fn anchor_locations(params: &Value, document: &Document, offset: usize, references: bool, include_definitions: bool) -> Value {
+	let uses: Vec<AnchorUse> = anchor_uses(&document.text);
+	let target: AnchorTarget = match uses.iter().find(|anchor_use| anchor_use.span.begin <= offset && offset <= anchor_use.span.end) {
+		Some(anchor_use) => anchor_use.target.clone(),
+		None => {return Value::Null;}
+	};
+	let locations: Vec<Value> = uses.iter()
+		.filter(|anchor_use| anchor_use.target == target && match anchor_use.definition {
+			true => include_definitions,
+			false => references
+		})
+		.map(|anchor_use| json!({"uri": params["textDocument"]["uri"], "range": range(&document.text, anchor_use.span)}))
+		.collect();
+	// Done
+	Value::Array(locations)
+}
+
+/// Every anchor definition and reference in the document, in order, anything that doesn't parse is left out
+fn anchor_uses(text: &str) -> Vec<AnchorUse> {
+	let source: Vec<char> = text.chars().collect();
+	let (syntax_tree, _) = SyntaxTreeNode::build_tree_recovering(&source);
+	let mut scope = AnchorScope::default();
+	let mut out = Vec::<AnchorUse>::new();
+	for node in &syntax_tree.children {
+		let macro_ = match &node.type_ {
+			SyntaxTreeNodeType::Macro(macro_) => macro_,
+			_ => continue
+		};
+		let definition: bool = match macro_.type_ {
+			MacroEnum::Anchor => true,
+			MacroEnum::Call | MacroEnum::Goto | MacroEnum::GotoIf | MacroEnum::SetIntGoto | MacroEnum::PushAnchorAddress => false,
+			_ => continue
+		};
+		let name: String = match macro_.args.first() {
+			Some(arg) => arg.to_string(),
+			None => continue
+		};
+		// Name is the argument inside "(" and ")"
+		let args_begin: usize = match source[node.begin..node.end].iter().position(|c| *c == '(') {
+			Some(i) => node.begin + i + 1,
+			None => continue
+		};
+		let name_chars: Vec<char> = name.chars().collect();
+		let name_begin: usize = match source[args_begin..node.end].windows(name_chars.len().max(1)).position(|window| window == name_chars.as_slice()) {
+			Some(i) => args_begin + i,
+			None => continue
+		};
+		let span: Span = Span::from_char_indices(&source, name_begin, name_begin + name_chars.len());
+		let target_result = match definition {
+			true => scope.define(&name).map(|qualified_opt| match qualified_opt {
+				Some(qualified) => AnchorTarget::Named(qualified),
+				None => AnchorTarget::Anonymous(scope.anonymous_before - 1)
+			}),
+			false => scope.target(&name).map(|(target, _)| target)
+		};
+		if let Ok(target) = target_result {
+			out.push(AnchorUse{target, span, definition});
+		}
+	}
+	// Done
+	out
+}
+
+/// `file:///a/b` -> `/a/b`, with "%XX" escapes decoded
+fn uri_to_path(uri: &str) -> PathBuf {
+	let encoded: &str = uri.strip_prefix("file://").unwrap_or(uri);
+	let mut bytes = Vec::<u8>::new();
+	let mut i: usize = 0;
+	while i < encoded.len() {
+		match (encoded.as_bytes()[i], encoded.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+			(b'%', Some(byte)) => {
+				bytes.push(byte);
+				i += 3;
+			},
+			(byte, _) => {
+				bytes.push(byte);
+				i += 1;
+			}
+		}
+	}
+	PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// LSP position (line, UTF-16 character) -> byte offset, clamped to the end of the line
+fn position_to_offset(text: &str, line: usize, character: usize) -> usize {
+	let line_start: usize = match line {
+		0 => 0,
+		_ => match text.match_indices('\n').nth(line - 1) {
+			Some((i, _)) => i + 1,
+			None => {return text.len();}
+		}
+	};
+	let mut units: usize = 0;
+	for (i, char_) in text[line_start..].char_indices() {
+		if units >= character || char_ == '\n' {
+			return line_start + i;
+		}
+		units += char_.len_utf16();
+	}
+	text.len()
+}
+
+/// Byte offset -> LSP position
+fn position(text: &str, offset: usize) -> Value {
+	let offset: usize = offset.min(text.len());
+	let line_start: usize = text[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+	json!({"line": text[..offset].matches('\n').count(), "character": text[line_start..offset].encode_utf16().count()})
+}
+
+fn range(text: &str, span: Span) -> Value {
+	json!({"start": position(text, span.begin), "end": position(text, span.end)})
+}
+
@@ -0,0 +1,8 @@
+//! Language server binary, talks LSP over stdio
+//! Does not do much, just uses the library
+
+use stack_machine::language_server::language_server_main;
+
+fn main() {
+	language_server_main();
+}
@@ -1,6 +1,6 @@
 //! Maine library file
 
-use std::{collections::HashMap, env, fs};
+use std::{collections::{HashMap, HashSet}, env, fs};
 
 #[cfg(test)]
 pub mod tests;
@@ -10,6 +10,8 @@ pub mod resources;
 pub mod program_upload;
 pub mod display_emulator;
 pub mod music_assembly_generator;
+pub mod debugger;
+pub mod language_server;
 pub use crate::prelude::*;
 
 /// Prelude
@@ -19,11 +21,29 @@ pub mod prelude {
 	// Version dependent imports
     pub use crate::compiler::{self, assembly_encode::{AssemblyWord, AssemblerConfig}, syntax_tree::{ParseError, ParseErrorType, SyntaxTreeNodeType, ParseContext}, macros::Macro};
     pub use crate::emulator::{self, Machine, GpioInterface, GpioInterfaceDoesNothing, CliInterface};
-	// Version dependent CONSTS
-	#[cfg(feature = "version_1")]
-	pub const ASSEMBLER_CONFIG_FILE: &str = "assembler_config_v1";
-	#[cfg(feature = "version_2")]
-	pub const ASSEMBLER_CONFIG_FILE: &str = "assembler_config_v2";
+	/// Which version of the computer to assemble for or emulate
+	#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+	pub enum HardwareVersion {
+		V1,
+		#[default]
+		V2
+	}
+	impl HardwareVersion {
+		pub fn assembler_config_file(&self) -> &'static str {
+			match self {
+				Self::V1 => "assembler_config_v1",
+				Self::V2 => "assembler_config_v2"
+			}
+		}
+		/// For the `-version=` command line argument, "1" or "2"
+		pub fn from_arg(arg: &str) -> Result<Self, String> {
+			match arg {
+				"1" => Ok(Self::V1),
+				"2" => Ok(Self::V2),
+				other => Err(format!("Invalid hardware version \"{}\", must be 1 or 2", other))
+			}
+		}
+	}
 	// CONSTS
 	pub const IDENTIFIER_CHARS: [char; 64] = [
 		'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
@@ -240,6 +260,30 @@ fn paste_to_gpram(program: &Vec<u16>) {
 	}
 }
 
+/// Takes out the flags that every command reads (`-version=`, `-optimize`, `--deny-warnings` and `-D NAME` or `-DNAME`), returns (the other arguments unchanged, names given with `-D`)
+fn split_common_flags(args: &[String]) -> (Vec<String>, HashSet<String>) {
+	let mut other_args = Vec::<String>::new();
+	let mut defines = HashSet::<String>::new();
+	let mut i: usize = 0;
+	while i < args.len() {
+		let arg: &str = &args[i];
+		match arg.strip_prefix("-D") {
+			Some("") => {
+				if let Some(name) = args.get(i + 1) {
+					defines.insert(name.clone());
+				}
+				i += 1;// Skip name
+			},
+			Some(name) => {defines.insert(name.to_owned());},
+			None if arg == "-optimize" || arg == "--deny-warnings" || arg.starts_with("-version=") => {},
+			None => other_args.push(arg.to_owned())
+		}
+		i += 1;
+	}
+	// Done
+	(other_args, defines)
+}
+
 fn parse_args(args: &Vec<String>) -> HashMap<String, String> {
 	let mut out = HashMap::<String, String>::new();
 	for arg in args {
@@ -271,15 +315,32 @@ fn parse_args(args: &Vec<String>) -> HashMap<String, String> {
 }
 
 pub fn ui_main() {
+	// Parse arguments
+	let args: Vec<String> = env::args().collect();
+	// Hardware version, `-version=1` or `-version=2` anywhere after the command, defaults to 2
+	let version: HardwareVersion = match parse_args(&args).get("version") {
+		Some(version_raw) => match HardwareVersion::from_arg(version_raw) {
+			Ok(version) => version,
+			Err(error) => {
+				println!("{}", error);
+				std::process::exit(1);
+			}
+		},
+		None => HardwareVersion::default()
+	};
 	// Used by almost every command
-	let assembler_config = resources::load_assembler_config().expect("Unable to load assembler config");
+	let mut assembler_config = resources::load_assembler_config(version).expect("Unable to load assembler config");
+	// `-D NAME` for `@ifdef`
+	let (other_args, defines) = split_common_flags(&args);
+	assembler_config.defines = defines;
+	assembler_config.optimize = parse_args(&args).contains_key("optimize");
+	// `--deny-warnings`, which `parse_args()` reads as "-deny-warnings"
+	assembler_config.deny_warnings = parse_args(&args).contains_key("-deny-warnings");
 	// Warning if ceratain features are enabled
 	#[cfg(feature = "replicate_stack_issue")]
 	{
 		println!("WARNING: Certain feature(s) are enabled which are meant to replicate faults in the computer");
 	}
-	// Parse arguments
-	let args: Vec<String> = env::args().collect();
 	if args.len() < 2 {// Just the program name, default to running the server GUI
 		panic!("Not enough arguments, see crate::ui_maine()");
 	}
@@ -289,11 +350,12 @@ pub fn ui_main() {
 				println!("Not implemented");
 			},
 			"-assemble-line" => {
-				if args.len() < 3 {
+				if other_args.len() < 3 {
 					println!("Plz include single line of assembly");
 				}
 				else {
-					match compiler::compiler_pipeline_formated_errors(&args[2..].join(" "), &assembler_config) {
+					// Tokens can start with "-" (`write -1 alu-a;`), so only the flags are taken out
+					match compiler::compiler_pipeline_formated_errors(&other_args[2..].join(" "), &assembler_config) {
 						Ok(program) => println!("Instruction: {}, {:#X}, {:#018b}", program[0], program[0], program[0]),
 						Err(s) => panic!("{}", s)
 					};
@@ -304,7 +366,28 @@ pub fn ui_main() {
 					println!("Plz include name of file in `/assembly_sources`");
 				}
 				else {
-					compiler::assemble_file(&args[2], &assembler_config).unwrap();
+					// `-listing` to also write `out/<name>.lst`
+					compiler::assemble_file(&args[2], &assembler_config, parse_args(&args).contains_key("listing")).unwrap();
+				}
+			},
+			"-analyze-stack" => {
+				if args.len() < 3 {
+					println!("Plz include name of file in `{}`", resources::ASSEMBLY_SOURCES_DIR);
+				}
+				else {
+					compiler::analyze_stack_file(&args[2], &assembler_config).unwrap();
+				}
+			},
+			"-fmt" => {
+				if args.len() < 3 {
+					println!("Plz include name of file in `{}`", resources::ASSEMBLY_SOURCES_DIR);
+				}
+				else {
+					// `--check`, which `parse_args()` reads as "-check"
+					let check: bool = parse_args(&args).contains_key("-check");
+					if !compiler::format_file(&args[2], &assembler_config, check).unwrap() && check {
+						std::process::exit(1);
+					}
 				}
 			},
 			"-assemble-upload" => {
@@ -367,15 +450,63 @@ pub fn ui_main() {
 						Ok(s) => s,
 						Err(e) => panic!("Could not load test file at \"{}\" because {}", &path, e)
 					};
-					match compiler::compiler_pipeline_formated_errors(&file_raw, &assembler_config) {
+					match compiler::compiler_pipeline(&file_raw, Some(name), &assembler_config) {
 						Ok(program) => {
-							let mut machine = Machine::new(program);
-							machine.run(&mut CliInterface::new()).unwrap();
+							let mut machine = Machine::new(program.words.clone(), version);
+							if let Err(e) = machine.run(&mut CliInterface::new()) {
+								println!("Emulation error: {}", e.to_string_with_program(&program));
+							}
 						},
-						Err(s) => println!("{}", s)
+						Err(errors) => println!("{}", compiler::format_errors(&errors))
+					}
+				}
+			},
+			"-debug" => {
+				if args.len() < 3 {
+					println!("Plz include name of file in `{}`", resources::ASSEMBLY_SOURCES_DIR);
+				}
+				else {
+					let name = &args[2];
+					let path: String = resources::ASSEMBLY_SOURCES_DIR.to_owned() + name;
+					let file_raw = match fs::read_to_string(&path) {
+						Ok(s) => s,
+						Err(e) => panic!("Could not load test file at \"{}\" because {}", &path, e)
+					};
+					match compiler::compiler_pipeline(&file_raw, Some(name), &assembler_config) {
+						Ok(program) => {
+							let mut debugger = debugger::Debugger::new(program, version, CliInterface::new());
+							debugger::run_repl(&mut debugger);
+						},
+						Err(errors) => println!("{}", compiler::format_errors(&errors))
 					}
 				}
 			},
+			"-disassemble" => {
+				if args.len() < 3 {
+					println!("Plz include path to a `.dec_list` or a raw flash image with `-format=bin`");
+				}
+				else {
+					let parsed_args: HashMap<String, String> = parse_args(&args);
+					let path = &args[2];
+					let program: Vec<u16> = match parsed_args.get("format").map(|format| format.as_str()) {
+						Some("bin") => {// Little endian 16-bit words
+							let bytes = fs::read(path).expect("Could not read program file");
+							bytes.chunks(2).map(|pair| (pair[0] as u16) | ((*pair.get(1).unwrap_or(&0) as u16) << 8)).collect()
+						},
+						Some("dec") | None => {
+							let raw = fs::read_to_string(path).expect("Could not read program file");
+							raw.split(',').map(|n_raw| n_raw.trim().parse::<u16>().expect("`.dec_list` must be comma-separated u16s")).collect()
+						},
+						Some(other) => panic!("Invalid format \"{}\", must be \"dec\" or \"bin\"", other)
+					};
+					// Symbol table, such as a `.lst` listing
+					let anchors_opt = parsed_args.get("symbols").map(|symbols_path| {
+						let raw = fs::read_to_string(symbols_path).expect("Could not read symbol table file");
+						compiler::disassemble::parse_symbol_table(&raw).unwrap()
+					});
+					print!("{}", compiler::disassemble::disassemble(&program, &assembler_config, anchors_opt.as_ref()));
+				}
+			},
 			"-assemble-to-csv" => {
 				if args.len() < 3 {
 					println!("Plz include name of file in `{}`", resources::ASSEMBLY_SOURCES_DIR);
@@ -425,12 +556,12 @@ pub fn ui_main() {
 						Ok(s) => s,
 						Err(e) => panic!("Could not load test file at \"{}\" because {}", &path, e)
 					};
-					match compiler::compiler_pipeline_formated_errors(&file_raw, &assembler_config) {
+					match compiler::compiler_pipeline(&file_raw, Some(name), &assembler_config) {
 						Ok(program) => {
-							let machine = Machine::new(program);
-							display_emulator::start_gui(machine);
+							let machine = Machine::new(program.words.clone(), version);
+							display_emulator::start_gui(machine, program);
 						},
-						Err(s) => println!("{}", s)
+						Err(errors) => println!("{}", compiler::format_errors(&errors))
 					}
 				}
 			},
@@ -1,4 +1,4 @@
-//! All old stuff that only works for version 1 is on this module and enabled by the `version_1` cargo feature
+//! All old stuff that only works for version 1 is on this module
 
 pub mod compiler;
 pub mod emulator;
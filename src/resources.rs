@@ -5,12 +5,21 @@ use serde_json;
 
 use crate::prelude::*;
 
-const ASSEMBLER_CONFIG_DIR: &str = "assembler_config/";
+pub const ASSEMBLER_CONFIG_DIR: &str = "assembler_config/";
 pub const ASSEMBLY_SOURCES_DIR: &str = "assembly_sources/";
 pub const OUTPUT_DIR: &str = "out/";
 
-pub fn load_assembler_config() -> Result<AssemblerConfig, String> {
-    let path = format!("{}/{}.json", ASSEMBLER_CONFIG_DIR, ASSEMBLER_CONFIG_FILE);
+pub fn load_assembler_config(version: HardwareVersion) -> Result<AssemblerConfig, String> {
+    let path = format!("{}/{}.json", ASSEMBLER_CONFIG_DIR, version.assembler_config_file());
     let raw_string = to_string_err(fs::read_to_string(&path))?;
-    to_string_err(serde_json::from_str(&raw_string))
+    let mut config: AssemblerConfig = to_string_err(serde_json::from_str(&raw_string))?;
+    config.version = version;
+    // So that errors can say why a word doesn't exist
+    if version == HardwareVersion::V1 {
+        let config_v2: AssemblerConfig = load_assembler_config(HardwareVersion::V2)?;
+        let v2_words = [&config_v2.opcodes, &config_v2.to_bus, &config_v2.from_bus, &config_v2.after_call, &config_v2.after_return, &config_v2.generic_after_opcode].into_iter().flatten();
+        let v1_words: Vec<&AssemblyWord> = [&config.opcodes, &config.to_bus, &config.from_bus].into_iter().flatten().collect();
+        config.version_2_only_words = v2_words.filter(|word| !v1_words.iter().any(|v1_word| v1_word.name.to_lowercase() == word.name.to_lowercase())).map(|word| word.name.to_lowercase()).collect();
+    }
+    Ok(config)
 }
\ No newline at end of file
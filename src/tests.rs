@@ -3,6 +3,20 @@
 use super::{compiler::{self, assembly_encode::{Token, TokenEnum}, macros::{Macro, MacroEnum, MacroArgument}, syntax_tree::{SyntaxTreeNode, SyntaxTreeNodeType}}};
 use crate::prelude::*;
 
+/// Assembles `source` for version 2 hardware
+fn assemble_v2(source: &str) -> Result<compiler::Program, Vec<compiler::CompilerError>> {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	compiler::compiler_pipeline(source, None, &assembler_config)
+}
+
+/// Message of the first error from assembling something that shouldn't assemble
+fn first_error(result: Result<compiler::Program, Vec<compiler::CompilerError>>) -> String {
+	match result {
+		Ok(_) => panic!("Should not assemble"),
+		Err(errors) => errors[0].enum_.to_string()
+	}
+}
+
 #[test]
 fn parse_binary_literal() {
 	// Simple
@@ -113,7 +127,7 @@ fn macro_expansion() {
 		write 0xFF goto-a;write 0xFF goto-b;call;
 	";
 	// Load assembler config
-	let assembler_config = resources::load_assembler_config().expect("Unable to load assembler config");
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
 	// Compile both programs
 	let program_test: Vec<u16> = match compiler::compiler_pipeline_formated_errors(assembly_source_test, &assembler_config) {
 		Ok(program) => program,
@@ -136,7 +150,7 @@ move stack-pop alu-a;
 move stack-pop alu-b;
 move add alu stack-push;
 halt;";
-	let assembler_config = resources::load_assembler_config().expect("Unable to load assembler config");
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
 	let program: Vec<u16> = match compiler::compiler_pipeline_formated_errors(assembly_source, &assembler_config) {
 		Ok(program) => program,
 		Err(s) => panic!("{}", s)
@@ -152,7 +166,7 @@ halt;";
 	];
 	assert_eq!(program, binary_program_check);
 	// Run
-	let mut machine = Machine::new(program);
+	let mut machine = Machine::new(program, HardwareVersion::V2);
 	machine.run(&mut GpioInterfaceDoesNothing).unwrap();
 	// All that work to add 1 + 2
 	//println!("stack: {:?}", &machine.stack_mem[0..5]);
@@ -184,7 +198,7 @@ WRITE 0x06 GOTO-A;
 WRITE 0x00 GOTO-B;
 GOTO;
 HALT;";
-	let assembler_config = resources::load_assembler_config().expect("Unable to load assembler config");
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
 	let program: Vec<u16> = match compiler::compiler_pipeline_formated_errors(assembly_source, &assembler_config) {
 		Ok(program) => program,
 		Err(s) => panic!("{}", s)
@@ -222,19 +236,1066 @@ HALT;";
 	];
 	assert_eq!(program, binary_program_check);
 	// Run
-	let mut machine = Machine::new(program);
+	let mut machine = Machine::new(program, HardwareVersion::V2);
 	machine.run(&mut GpioInterfaceDoesNothing).unwrap();
 	// Check for fibonacci sequence in GPRAM
 	assert_eq!(machine.general_mem[0..10], [1, 1, 2, 3, 5, 8, 13, 21, 34, 55]);
 }
 
+#[test]
+fn select_hardware_version_at_runtime() {
+	// Version 2 only words are rejected by the version 1 config
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V1).expect("Unable to load assembler config");
+	assert_eq!(assembler_config.version, HardwareVersion::V1);
+	assert!(compiler::compiler_pipeline_formated_errors("move get-goto-a stack-push;\n", &assembler_config).is_err());
+	assert!(compiler::compiler_pipeline_formated_errors("config-int true;\n", &assembler_config).is_err());
+	// Version 1 reads the clock counter on TX 9 and 10
+	let program: Vec<u16> = match compiler::compiler_pipeline_formated_errors("move clk-counter-a stack-push;move clk-counter-a stack-push;halt;", &assembler_config) {
+		Ok(program) => program,
+		Err(s) => panic!("{}", s)
+	};
+	let mut machine = Machine::new(program, HardwareVersion::V1);
+	machine.run(&mut GpioInterfaceDoesNothing).unwrap();
+	assert_eq!(machine.stack_mem[1], 0);
+	assert!(machine.stack_mem[2] > 0);// Counted the clock cycles of the first instruction
+	// Same source assembles differently for each version
+	let source = "write 0x00 int-goto-b;\n";
+	let v2_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	assert_eq!(compiler::compiler_pipeline_formated_errors(source, &v2_config).unwrap()[..], [0x100B]);
+	assert!(compiler::compiler_pipeline_formated_errors(source, &assembler_config).is_err());
+}
+
+#[test]
+fn debugger_breakpoints_and_step_over() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let assembly_source = "
+		write 0x01 stack-push;
+		@call(add-two);
+		@anchor(after-call);
+		write 0x05 alu-a;
+		halt;
+		@anchor(add-two);
+		move stack-pop alu-a;
+		write 0x02 alu-b;
+		move add alu stack-push;
+		return;
+	";
+	let program = match compiler::compiler_pipeline(assembly_source, None, &assembler_config) {
+		Ok(program) => program,
+		Err(errors) => panic!("{}", compiler::format_errors(&errors))
+	};
+	let mut debugger = crate::debugger::Debugger::new(program, HardwareVersion::V2, GpioInterfaceDoesNothing);
+	// Breakpoint inside the function, by anchor + offset
+	assert_eq!(debugger.execute_command("break add-two+2").unwrap(), "Breakpoint at 0x0008 line 10 (@anchor(add-two)+2)");
+	assert_eq!(debugger.execute_command("c").unwrap(), "Breakpoint at 0x0008 line 10 (@anchor(add-two)+2)");
+	assert_eq!(debugger.machine.alu.latch_a, 0x01);
+	assert_eq!(debugger.execute_command("bt").unwrap(), "#0 called from 0x0003 line 3");
+	assert!(debugger.execute_command("regs").unwrap().contains("ALU: a=0x01 b=0x02"));
+	// Finish the function and step over the next call-free instruction
+	assert_eq!(debugger.execute_command("step 2").unwrap(), "Stopped at 0x0004 line 5 (@anchor(after-call))");
+	assert!(debugger.execute_command("bt").unwrap().contains("empty"));
+	assert_eq!(debugger.execute_command("mem stack 0 3").unwrap(), "0x0000: 00 03 00");
+	assert_eq!(debugger.execute_command("next").unwrap(), "Stopped at 0x0005 line 6 (@anchor(after-call)+1)");
+	assert_eq!(debugger.execute_command("c").unwrap(), "Halted at 0x0005 line 6 (@anchor(after-call)+1)");
+	assert!(debugger.execute_command("step").is_err());
+	assert!(debugger.execute_command("break nowhere").is_err());
+}
+
+#[test]
+fn debugger_next_steps_over_call() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let assembly_source = "
+		@call(function);
+		halt;
+		@anchor(function);
+		write 0x42 stack-push;
+		return;
+	";
+	let program = compiler::compiler_pipeline(assembly_source, None, &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	let mut debugger = crate::debugger::Debugger::new(program, HardwareVersion::V2, GpioInterfaceDoesNothing);
+	// Loading the GOTO latches
+	debugger.execute_command("s 2").unwrap();
+	// `call` itself
+	assert_eq!(debugger.execute_command("n").unwrap(), "Stopped at 0x0003 line 3");
+	assert_eq!(debugger.machine.stack_mem[1], 0x42);
+}
+
+#[test]
+fn program_source_map() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let assembly_source = "write 0x00 alu-a;
+@anchor(drop-piece);
+  write 0x01 alu-b;@goto(end);
+@anchor(end);
+move stack-pop alu-a;
+";
+	let program = match compiler::compiler_pipeline(assembly_source, Some("tetris"), &assembler_config) {
+		Ok(program) => program,
+		Err(errors) => panic!("{}", compiler::format_errors(&errors))
+	};
+	assert_eq!(program.words.len(), 6);
+	assert_eq!(program.locations.len(), 6);
+	assert_eq!(program.anchors.get("drop-piece"), Some(&1));
+	assert_eq!(program.anchors.get("end"), Some(&5));
+	// Line and column
+	assert_eq!((program.locations[1].line, program.locations[1].column), (3, 3));
+	assert_eq!((program.locations[2].line, program.locations[2].column), (3, 20));
+	// Originating macro
+	assert!(program.locations[1].macro_opt.is_none());
+	assert_eq!(program.locations[4].macro_opt.as_ref().unwrap().format(), "@goto(end)");
+	// Descriptions
+	assert_eq!(program.describe_address(0), "tetris:1");
+	assert_eq!(program.describe_address(4), "tetris:3 (@anchor(drop-piece)+3)");
+	// Emulation errors, stack pop is fine but there is no halt
+	let mut machine = Machine::new(program.words.clone(), HardwareVersion::V2);
+	let error = machine.run(&mut GpioInterfaceDoesNothing).unwrap_err();
+	assert_eq!(error.prog_addr(), 6);
+	assert_eq!(error.to_string_with_program(&program), "ExecutionPointerExceededProgramSize at 0x0006 (@anchor(end)+1)");
+}
+
+#[test]
+fn listing() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let assembly_source = "write 0x42 stack-push;
+@anchor(loop);
+move xnor alu stack-push;# ALU
+@goto(loop);
+";
+	let program = compiler::compiler_pipeline(assembly_source, Some("listing-test"), &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	let listing = compiler::listing::listing(&program, &assembler_config);
+	let lines: Vec<&str> = listing.lines().collect();
+	assert_eq!(lines[0], "# Listing of listing-test, hardware version 2");
+	// Address, encoding and decoded fields
+	let fields: Vec<&str> = lines[2].split_whitespace().collect();
+	assert_eq!(fields, ["0x0000", "0x1421", "0001010000100001", "write", "-", "-", "stack-push", "0x42", "1", "write", "0x42", "stack-push;"]);
+	let fields: Vec<&str> = lines[3].split_whitespace().collect();
+	assert_eq!(fields[3..7], ["move", "xnor", "alu", "stack-push"]);
+	// Macro expansion only shows the source once
+	assert!(lines[4].ends_with("4     @goto(loop);  [from @goto(loop)]"));
+	let fields: Vec<&str> = lines[5].split_whitespace().collect();
+	assert_eq!(fields, ["0x0003", "0x5001", "0101000000000001", "write", "-", "-", "goto-b", "0x00", "[from", "@goto(loop)]"]);
+	let fields: Vec<&str> = lines[6].split_whitespace().collect();
+	assert_eq!(fields[3], "goto");
+	// Symbol table
+	assert_eq!(lines[lines.len() - 2..], ["# Anchors", "0x0001  loop"]);
+}
+
+#[test]
+fn disassemble_round_trip() {
+	for (version, source_name) in [(HardwareVersion::V2, "fibonacci"), (HardwareVersion::V2, "function_test"), (HardwareVersion::V2, "interrupt_and_timers_test"), (HardwareVersion::V1, "fibonacci")] {
+		let assembler_config = resources::load_assembler_config(version).expect("Unable to load assembler config");
+		let path: String = resources::ASSEMBLY_SOURCES_DIR.to_owned() + source_name;
+		let file_raw = std::fs::read_to_string(&path).unwrap();
+		let program = compiler::compiler_pipeline(&file_raw, None, &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+		// With and without the symbol table
+		for anchors_opt in [None, Some(&program.anchors)] {
+			let disassembly = compiler::disassemble::disassemble(&program.words, &assembler_config, anchors_opt);
+			assert!(!disassembly.contains("Could not disassemble"), "{}", disassembly);
+			let reassembled = compiler::compiler_pipeline(&disassembly, None, &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+			assert_eq!(reassembled.words, program.words, "{}", source_name);
+			if anchors_opt.is_some() {
+				assert_eq!(reassembled.anchors, program.anchors);
+			}
+		}
+	}
+}
+
+#[test]
+fn disassemble_symbols_and_v2_opcodes() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let assembly_source = "@anchor(start);
+move tx-17 int-goto-b;
+write 0x10 int-goto-a;
+@call(function);
+@goto_if(start);
+halt;
+@anchor(function);
+write 0x05 set-stack-offset;
+return;
+";
+	let program = compiler::compiler_pipeline(assembly_source, None, &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	let listing = compiler::listing::listing(&program, &assembler_config);
+	let anchors = compiler::disassemble::parse_symbol_table(&listing).unwrap();
+	assert_eq!(anchors, program.anchors);
+	assert_eq!(compiler::disassemble::disassemble(&program.words, &assembler_config, Some(&anchors)), assembly_source);
+	// Opcode 15 is not valid
+	assert!(compiler::disassemble::disassemble(&[0xFFFF], &assembler_config, None).starts_with("# 0x0000: Could not disassemble 0xFFFF, Invalid opcode 0xF\n"));
+}
+
+#[test]
+fn error_spans() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let format_first_error = |source: &str| -> String {
+		match compiler::compiler_pipeline(source, Some("tetris"), &assembler_config) {
+			Ok(_) => panic!("Should not assemble"),
+			Err(errors) => errors[0].to_string()
+		}
+	};
+	// Parse error
+	assert_eq!(format_first_error("write 0x42 stack-push;\n\tmove $ alu-a;\n"), "error: Invalid character '$' in instruction
+ --> tetris:2:7
+  |
+2 | \tmove $ alu-a;
+  | \t     ^");
+	// Skeleton error, whole macro
+	assert_eq!(format_first_error("@goto(nowhere);"), "error: Anchor \"nowhere\" is not defined
+ --> tetris:1:1
+  |
+1 | @goto(nowhere);
+  | ^^^^^^^^^^^^^^");
+	// Encode error, only the token that couldn't be encoded
+	let error = format_first_error("halt;\nmve stack-pop alu-a;");
+	assert!(error.starts_with("error: Invalid token \"mve\"\n --> tetris:2:1\n"));
+	assert!(error.ends_with("2 | mve stack-pop alu-a;\n  | ^^^"));
+	let error = format_first_error("move  stack-pop stack-pop;");
+	assert!(error.contains(" --> tetris:1:17\n"));
+	assert!(error.ends_with("1 | move  stack-pop stack-pop;\n  |                 ^^^^^^^^^"));
+	// Wrong number of tokens, whole instruction
+	assert!(format_first_error("write 0x01;").ends_with("1 | write 0x01;\n  | ^^^^^^^^^^^"));
+	// Byte span, not character index
+	match compiler::compiler_pipeline("# é\nmve;", None, &assembler_config) {
+		Ok(_) => panic!("Should not assemble"),
+		Err(errors) => {
+			let location = errors[0].location_opt.as_ref().unwrap();
+			assert_eq!((location.span.begin, location.line, location.column), (5, 2, 1));
+			assert_eq!(location.source_name, None);
+		}
+	}
+}
+
+#[test]
+fn parser_error_recovery() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let source = "write 0x4G stack-push;
+mve stack-pop alu-a;
+@goto(nowhere);
+move $ alu-a; write 0x01 alu-b;
+@bogus(x);
+@anchor(end);
+halt";
+	let errors = match compiler::compiler_pipeline(source, Some("tetris"), &assembler_config) {
+		Ok(_) => panic!("Should not assemble"),
+		Err(errors) => errors
+	};
+	// Syntax, encoding and macro errors all reported in source order
+	let lines_and_kinds: Vec<(usize, &str)> = errors.iter().map(|error| (
+		error.location_opt.as_ref().unwrap().line,
+		match error.enum_ {
+			compiler::CompilerErrorEnum::Parse(_) => "parse",
+			compiler::CompilerErrorEnum::ProgramSkeleton(_) => "skeleton",
+			compiler::CompilerErrorEnum::Assembly(_) => "assembly",
+			compiler::CompilerErrorEnum::Include(_) => "include",
+			compiler::CompilerErrorEnum::Lint(_) => "lint"
+		}
+	)).collect();
+	assert_eq!(lines_and_kinds, vec![(1, "parse"), (2, "assembly"), (3, "skeleton"), (4, "parse"), (5, "skeleton"), (7, "parse")]);
+	// Parsing picks up again after the ";", so the rest of line 4 is fine
+	assert!(compiler::compiler_pipeline("move $ alu-a; write 0x01 alu-b;\n", None, &assembler_config).unwrap_err().len() == 1);
+	// Same as before for a single error
+	assert!(SyntaxTreeNode::build_tree(&"mve $;".chars().collect()).is_err());
+}
+
+#[test]
+fn include_files() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let dir = std::env::temp_dir().join(format!("stack_machine_include_test_{}", std::process::id()));
+	std::fs::create_dir_all(dir.join("lib")).unwrap();
+	let write_file = |name: &str, text: &str| std::fs::write(dir.join(name), text).unwrap();
+	write_file("lib/math.asm", "@anchor(add-two);\nwrite 0x02 alu-b;\nmove add alu stack-push;\nreturn;\n");
+	write_file("lib/display.asm", "@include(\"math.asm\");\n@anchor(update-display);\nreturn;\n");
+	write_file("lib/bad.asm", "halt;\nmove $ alu-a;\n");
+	write_file("lib/cycle_a.asm", "@include(\"cycle_b.asm\");\n");
+	write_file("lib/cycle_b.asm", "@include(\"cycle_a.asm\");\n");
+	write_file("lib/also_math.asm", "@include(\"math.asm\");\n@anchor(also-math);\nreturn;\n");
+	// Paths relative to the including file, anchors shared between files
+	let main_source = "write 0x01 alu-a;\n@call(add-two);\nhalt;\n@include(\"lib/display.asm\");\n";
+	let program = compiler::compiler_pipeline_at(main_source, Some("main"), &dir.join("main"), &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(program.words.len(), 9);
+	assert_eq!(program.anchors.get("add-two"), Some(&5));
+	assert_eq!(program.anchors.get("update-display"), Some(&8));
+	assert_eq!(program.files.iter().map(|file| file.name.clone().unwrap()).collect::<Vec<String>>(), vec!["main", "lib/display.asm", "lib/math.asm"]);
+	assert_eq!(program.describe_address(6), "lib/math.asm:3 (@anchor(add-two)+1)");
+	// Diamond, math.asm is only included the first time
+	let main_source = "halt;\n@include(\"lib/display.asm\");\n@include(\"lib/also_math.asm\");\n@include(\"./lib/math.asm\");\n";
+	let program = compiler::compiler_pipeline_at(main_source, Some("main"), &dir.join("main"), &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(program.words.len(), 6);
+	assert_eq!(program.anchors.get("also-math"), Some(&5));
+	assert_eq!(program.files.len(), 4);
+	// Errors point into the included file
+	let format_errors = |source: &str| -> Vec<String> {
+		match compiler::compiler_pipeline_at(source, Some("main"), &dir.join("main"), &assembler_config) {
+			Ok(_) => panic!("Should not assemble"),
+			Err(errors) => errors.iter().map(|error| error.to_string()).collect()
+		}
+	};
+	let errors = format_errors("mve;\n@include(\"lib/bad.asm\");\n");
+	assert_eq!(errors.len(), 2);
+	assert!(errors[0].contains("--> main:1:1"));
+	assert!(errors[1].starts_with("error: Invalid character '$' in instruction\n --> lib/bad.asm:2:6\n"));
+	// Cycles and missing files
+	let errors = format_errors("@include(\"lib/cycle_a.asm\");\n");
+	assert_eq!(errors.len(), 1);
+	assert!(errors[0].starts_with("error: Include cycle: lib/cycle_a.asm -> lib/cycle_b.asm -> lib/cycle_a.asm\n --> lib/cycle_b.asm:1:1\n"), "{}", errors[0]);
+	assert!(format_errors("@include(\"lib/missing.asm\");")[0].starts_with("error: Could not read included file"));
+	// Same anchor defined in 2 files
+	let errors = format_errors("@anchor(add-two);\n@include(\"lib/math.asm\");\n");
+	assert!(errors[0].starts_with("error: Anchor \"add-two\" is already defined\n --> lib/math.asm:1:1\n"), "{}", errors[0]);
+	std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn user_macros() {
+	// Used before it is defined, arguments substituted into literals, bus names and other macros
+	let source = "@store_and_jump(0x42, stack-push, end);
+@anchor(middle);
+halt;
+@macro store(value, destination)
+	write value destination;
+@endmacro
+@macro store_and_jump(value, destination, target)
+	@store(value, destination);
+	@goto(target);
+@endmacro
+@anchor(end);
+@store(0b00000111, alu-a);
+halt;";
+	let program = assemble_v2(source).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	let expected = assemble_v2("write 0x42 stack-push;\n@goto(end);\n@anchor(middle);\nhalt;\n@anchor(end);\nwrite 0x07 alu-a;\nhalt;").unwrap();
+	assert_eq!(program.words, expected.words);
+	// Anchor addresses count the expanded instructions
+	assert_eq!(program.anchors.get("middle"), Some(&4));
+	assert_eq!(program.anchors.get("end"), Some(&5));
+	// Expanded instructions point at where the macro was used
+	assert_eq!(program.locations[0].line, 1);
+	assert_eq!(program.locations[0].macro_opt.as_ref().unwrap().format(), "@store_and_jump(0x42, stack-push, end)");
+	assert_eq!(program.locations[5].line, 12);
+	// Errors
+	assert_eq!(first_error(assemble_v2("@nothing(a);")), "Unknown macro \"@nothing\"");
+	assert_eq!(first_error(assemble_v2("@macro one(a)\nhalt;\n@endmacro\n@one(a, b);")), "Macro \"@one\" takes 1 argument(s) but was given 2");
+	assert_eq!(first_error(assemble_v2("@macro loop()\n@loop();\n@endmacro\n@loop();")), "Macro \"@loop\" uses itself");
+	assert_eq!(first_error(assemble_v2("@macro goto(a)\nhalt;\n@endmacro")), "Macro \"@goto\" is already defined");
+	assert_eq!(first_error(assemble_v2("@macro open()\nhalt;")), "Macro definition \"@open\" has no @endmacro");
+	assert_eq!(first_error(assemble_v2("halt;\n@endmacro")), "@endmacro without @macro");
+	// Anchors in the body are renamed for each use
+	let source = "@anchor(main);
+@macro skip_zero(source)
+	move source goto-decider;
+	@goto_if(skip);
+	write 0x01 stack-push;
+	@anchor(skip);
+	@goto(.after+1);
+	@anchor(.after);
+	halt;
+@endmacro
+@skip_zero(alu);
+@skip_zero(stack-pop);
+@goto(.after);
+@anchor(.after);";
+	let program = assemble_v2(source).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(program.anchors.get("%skip_zero-0.skip"), Some(&5));
+	assert_eq!(program.anchors.get("%skip_zero-1.skip"), Some(&14));
+	assert_eq!(program.anchors.get("%skip_zero-1.after"), Some(&17));
+	assert_eq!(program.anchors.get("main.after"), Some(&21));
+	// `@goto(.after+1)` in the second use, written as the address before it
+	assert_eq!(((program.words[14] >> 4) & 0xFF) | (((program.words[15] >> 4) & 0xFF) << 8), 17);
+	// The disassembler leaves out the renamed anchors, since they can't be written in source
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let disassembly = compiler::disassemble::disassemble(&program.words, &assembler_config, Some(&program.anchors));
+	assert!(!disassembly.contains("%skip_zero"), "{}", disassembly);
+	assert_eq!(assemble_v2(&disassembly).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors))).words, program.words);
+	// Can't clash with anchors from the source
+	let program = assemble_v2("@macro jump()\n@anchor(x);\n@goto(x);\n@endmacro\n@anchor(jump-0);\n@anchor(.x);\n@jump();").unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(program.anchors.get("jump-0.x"), Some(&0));
+	assert_eq!(program.anchors.get("%jump-0.x"), Some(&0));
+	assert_eq!(first_error(assemble_v2("@anchor(%jump-0.x);")), "Invalid character '%' in macro, Expected \",\"");
+}
+
+#[test]
+fn constants_and_variables() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let source = "@const SCREEN = 0x1234
+@var count[2]
+@var flag
+# Used before declared
+write lo(buffer) gpram-addr-a;
+write hi(buffer) gpram-addr-b;
+write lo(SCREEN) alu-a;
+write hi(SCREEN) set-stack-offset;
+write lo(flag) stack-push;
+halt;
+@var buffer[0x100]
+@const BIG = 65535";
+	let program = assemble_v2(source).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	// Allocated in the order they're declared
+	assert_eq!(program.symbols.get("count"), Some(&compiler::program_skeleton::Symbol::Var{address: 0, size: 2}));
+	assert_eq!(program.symbols.get("flag"), Some(&compiler::program_skeleton::Symbol::Var{address: 2, size: 1}));
+	assert_eq!(program.symbols.get("buffer"), Some(&compiler::program_skeleton::Symbol::Var{address: 3, size: 256}));
+	assert_eq!(program.symbols.get("BIG"), Some(&compiler::program_skeleton::Symbol::Const(0xFFFF)));
+	let expected = assemble_v2("write 0x03 gpram-addr-a;\nwrite 0x00 gpram-addr-b;\nwrite 0x34 alu-a;\nwrite 0x12 set-stack-offset;\nwrite 0x02 stack-push;\nhalt;").unwrap();
+	assert_eq!(program.words, expected.words);
+	// In the listing
+	let listing = compiler::listing::listing(&program, &assembler_config);
+	assert!(listing.contains("# Symbols\n0x1234  SCREEN\n0xFFFF  BIG\n0x0000  count[2]\n0x0002  flag[1]\n0x0003  buffer[256]\n\n# Anchors"), "{}", listing);
+	// Also works in user macros
+	let program = assemble_v2("@var x[2]\n@macro load(name)\nwrite hi(name) alu-a;\n@endmacro\n@var y\n@load(y);").unwrap();
+	assert_eq!(program.words, assemble_v2("write 0x00 alu-a;").unwrap().words);
+	// Errors
+	assert_eq!(first_error(assemble_v2("write lo(nothing) alu-a;")), "\"nothing\" is not a @const, @var or anchor");
+	assert_eq!(first_error(assemble_v2("@const A = 1\n@var A")), "\"A\" is already declared");
+	assert_eq!(first_error(assemble_v2("@var a[0xFFFF]\n@var b[2]")), "Not enough GPRAM for \"b\", it is 2 byte(s) and only 1 are left");
+	assert_eq!(first_error(assemble_v2("@const A = 0x123456")), "Invalid hex literal, number too large to fit in target type");
+	assert!(first_error(assemble_v2("@const A 5")).starts_with("Invalid character '5' in macro"));
+	assert_eq!(first_error(assemble_v2("@var x[0]")), "@var size must be at least 1 byte");
+}
+
+#[test]
+fn literal_expressions() {
+	let write_values = |source: &str| -> Vec<u8> {
+		let program = assemble_v2(source).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+		program.words.iter().map(|word| ((word >> 4) & 0xFF) as u8).collect()
+	};
+	// Precedence, parenthesis, characters and 16-bit decimal
+	assert_eq!(write_values("write 1+2*3 alu-a;\nwrite (1 + 2)*3 alu-a;\nwrite 'A'+1 alu-a;\nwrite '\\n' alu-a;\nwrite (1000 - 990) alu-a;\nwrite 1<<4|0b11 alu-a;\nwrite 0xF0>>4&0x3 alu-a;\nwrite -1 alu-a;\nwrite 5-1 alu-a;"), vec![7, 9, 66, 10, 10, 0x13, 3, 0xFF, 4]);
+	// Every operator straight after a literal
+	assert_eq!(write_values("write 0x10-1 alu-a;\nwrite 0b101-1 alu-a;\nwrite 0d10-1 alu-a;\nwrite 0x10+1 alu-a;\nwrite 0x10*2 alu-a;\nwrite 0x13&0x3 alu-a;\nwrite 0x10|1 alu-a;\nwrite 0x10<<1 alu-a;\nwrite 0x10>>1 alu-a;"), vec![0x0F, 4, 9, 0x11, 0x20, 3, 0x11, 0x20, 8]);
+	// Anchors, constants and variables, plain literals still work
+	let source = "@const BASE = 0x1234
+@var padding[0x10]
+@var buffer[4]
+write lo(table) alu-a;
+write hi(table) alu-a;
+write lo(BASE + 0x100) alu-a;
+write (lo(buffer) + 3) alu-a;
+write (hi(end) - hi(table)) alu-a;
+write 0x42 alu-a;
+@anchor(table);
+halt;
+@anchor(end);";
+	assert_eq!(write_values(source), vec![6, 0, 0x34, 0x13, 0, 0x42, 0]);
+	// Range checking and errors
+	assert_eq!(first_error(assemble_v2("write 256 alu-a;")), "256 doesn't fit in 8 bits");
+	assert_eq!(first_error(assemble_v2("write ('A' * 4) alu-a;")), "('A' * 4) is 260, which doesn't fit in 8 bits");
+	assert_eq!(first_error(assemble_v2("write -129 alu-a;")), "-129 doesn't fit in 8 bits");
+	assert_eq!(first_error(assemble_v2("write 1<<64 alu-a;")), "Overflow in (1 << 64)");
+	assert_eq!(first_error(assemble_v2("write -(-9223372036854775807 - 1) alu-a;")), "Overflow in -(-9223372036854775807 - 1)");
+	assert_eq!(first_error(assemble_v2("write (1 + 2")), "Unfinished expression");
+	assert_eq!(first_error(assemble_v2("write (nowhere + 1) alu-a;")), "\"nowhere\" is not a @const, @var or anchor");
+	assert_eq!(first_error(assemble_v2("write mid(5) alu-a;")), "Unknown function \"mid\", must be lo or hi");
+	assert_eq!(first_error(assemble_v2("write 'AB' alu-a;")), "Invalid character literal, must be 1 character or escape sequence in single quotes");
+	assert_eq!(first_error(assemble_v2("write (1 + 2 alu-a;")), "Invalid character 'a' in expression, Expected \")\"");
+	assert_eq!(first_error(assemble_v2("@anchor(x);\n@const x = 1")), "\"x\" is already declared");
+	// Expressions in user macros
+	assert_eq!(write_values("@macro offset(base, n)\nwrite (base + n) alu-a;\n@endmacro\n@const A = 0x10\n@offset(A, 2);\n@offset(0x20, A);"), vec![0x12, 0x30]);
+}
+
+#[test]
+fn local_and_anonymous_anchors() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let assembly_source = "@anchor(main);
+@anchor(.loop);
+write 0x01 alu-a;
+@goto_if(.done);
+@goto(.loop);
+@anchor(.done);
+@call(other.loop);
+@anchor(:);
+@goto(:+);
+@anchor(:);
+@goto(:-);
+@goto(:--+1);
+@push_anchor_address(main+0x4);
+@anchor(other);
+@anchor(.loop);
+halt;";
+	let program = compiler::compiler_pipeline(assembly_source, None, &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	// Local anchors are stored with their global anchor, anonymous ones aren't stored
+	let mut anchors: Vec<(&String, &usize)> = program.anchors.iter().collect();
+	anchors.sort();
+	assert_eq!(anchors, vec![(&"main".to_owned(), &0), (&"main.done".to_owned(), &7), (&"main.loop".to_owned(), &0), (&"other".to_owned(), &21), (&"other.loop".to_owned(), &21)]);
+	// Addresses written by each macro, 1 less than the anchor
+	let write_address_in = |words: &[u16], address: usize| -> u16 {((words[address] >> 4) & 0xFF) | (((words[address + 1] >> 4) & 0xFF) << 8)};
+	assert_eq!([1, 4, 7, 10, 13, 16, 19].map(|address| write_address_in(&program.words, address)), [6, 0xFFFF, 20, 12, 12, 10, 3]);
+	// Disassembly uses the full names and assembles to the same program
+	let disassembly = compiler::disassemble::disassemble(&program.words, &assembler_config, Some(&program.anchors));
+	assert!(disassembly.contains("@goto_if(main.done);"), "{}", disassembly);
+	let reassembled = compiler::compiler_pipeline(&disassembly, None, &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(reassembled.words, program.words);
+	// Offset after `:+` and `:++` without its "+"
+	let program = assemble_v2("@goto(:+1);\n@anchor(:);\nhalt;\nhalt;").unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(write_address_in(&program.words, 0), 3);
+	let program = assemble_v2("@goto(:++1);\n@anchor(:);\nhalt;\n@anchor(:);\nhalt;\nhalt;").unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(write_address_in(&program.words, 0), 4);
+	// Local and qualified anchors in expressions
+	let program = assemble_v2("@anchor(main);\nhalt;\n@anchor(.end);\nwrite lo(.end) alu-a;\n@anchor(other);\nwrite (lo(main.end) + 1) alu-a;").unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(program.words.iter().skip(1).map(|word| (word >> 4) & 0xFF).collect::<Vec<u16>>(), vec![1, 2]);
+	// Errors
+	assert_eq!(first_error(assemble_v2("@anchor(main);\n@anchor(.x);\n@anchor(.x);")), "Anchor \"main.x\" is already defined");
+	assert_eq!(first_error(assemble_v2("@anchor(main);\n@goto(.nowhere);")), "Anchor \"main.nowhere\" is not defined");
+	assert_eq!(first_error(assemble_v2("@anchor(:);\n@goto(:+);")), "There is no anonymous anchor for \":+\"");
+	assert_eq!(first_error(assemble_v2("@goto(:-);")), "There is no anonymous anchor for \":-\"");
+	assert_eq!(first_error(assemble_v2("@anchor(:);\n@goto(:-1);")), "Invalid anchor reference \":-1\", must be a name, .local-name, :+ or :- with an optional +offset");
+	assert_eq!(first_error(assemble_v2("@anchor(main);\n@anchor(.end);\n@anchor(other);\nwrite lo(.end) alu-a;")), "\".end\" is not a @const, @var or anchor");
+	assert_eq!(first_error(assemble_v2("@anchor(a+1);")), "Invalid anchor name \"a+1\", must be a name, .local-name or :");
+	assert_eq!(first_error(assemble_v2("@anchor(a);\n@goto(a+);")), "Invalid anchor reference \"a+\", must be a name, .local-name, :+ or :- with an optional +offset");
+	assert_eq!(first_error(assemble_v2("halt;\n@anchor(a);\n@goto(a+0xFFFF);")), "Invalid anchor reference \"a+0xFFFF\", must be a name, .local-name, :+ or :- with an optional +offset");
+}
+
+#[test]
+fn block_macros() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V1).expect("Unable to load assembler config");
+	// Pushes 1 to 5, then 0xEE if the last value was 5
+	let assembly_source = "write 0x00 alu-a;
+write 0x01 goto-decider;
+@while
+	write 0x01 alu-b;
+	move add alu alu-a;
+	move a alu stack-push;
+	write 0x05 alu-b;
+	@if(eq, alu)
+		write 0x00 goto-decider;
+	@else
+		@anchor(.not-done);
+		write 0x01 goto-decider;
+	@endif
+@endwhile
+@if(eq, alu)
+	write 0xEE stack-push;
+@endif
+halt;";
+	let program = compiler::compiler_pipeline(assembly_source, None, &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	let mut machine = Machine::new(program.words.clone(), HardwareVersion::V1);
+	machine.run(&mut GpioInterfaceDoesNothing).unwrap();
+	assert_eq!(machine.stack_mem[1..7], [1, 2, 3, 4, 5, 0xEE]);
+	// Generated anchors are local so they don't change the scope of the program's own
+	assert_eq!(program.anchors.get("%while-0.start"), Some(&2));
+	assert_eq!(program.anchors.get("%while-0.end"), Some(&27));
+	assert_eq!(program.anchors.get("%if-1.end"), Some(&24));
+	assert_eq!(program.anchors.get("%if-2.end"), Some(&35));
+	assert!(program.anchors.contains_key(".not-done"));
+	// Nesting errors
+	let errors = |source: &str| -> Vec<String> {
+		match compiler::compiler_pipeline(source, None, &assembler_config) {
+			Ok(_) => panic!("Should not assemble"),
+			Err(errors) => errors.iter().map(|error| error.enum_.to_string()).collect()
+		}
+	};
+	assert_eq!(errors("@if\n@while\n@endif\n"), vec!["@while has no matching @endwhile"]);
+	assert_eq!(errors("@endif\n@while\nhalt;"), vec!["@endif without @if", "@while has no matching @endwhile"]);
+	assert_eq!(errors("@while\n@else\n@endwhile"), vec!["@else without @if"]);
+	assert_eq!(errors("@if\n@else\n@else\n@endif"), vec!["@if already has an @else"]);
+	assert_eq!(errors("@macro m()\n@if\n@endmacro\n@endif"), vec!["@if has no matching @endif", "@endif without @if"]);
+	assert_eq!(errors("@if(eq, alu, alu)\n@endif"), vec!["Macro \"@if\" takes 2 argument(s) but was given 3"]);
+	// Every expansion of a user macro gets its own anchors
+	let program = compiler::compiler_pipeline("@macro skip-if-zero()\n@if(alu)\nhalt;\n@endif\n@endmacro\n@skip-if-zero();\n@skip-if-zero();", None, &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert!(program.anchors.contains_key("%if-0.end") && program.anchors.contains_key("%if-1.end"));
+	// Can't clash with anchors from the source
+	let program = compiler::compiler_pipeline("@anchor(if-0);\n@anchor(.then);\n@if(alu)\nhalt;\n@endif\n@anchor(if-0.end);\nhalt;", None, &assembler_config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(program.anchors.get("if-0.then"), Some(&0));
+	assert_eq!(program.anchors.get("%if-0.end"), program.anchors.get("if-0.end"));
+	assert!(program.anchors.contains_key("%if-0.then"));
+}
+
+#[test]
+fn conditional_assembly() {
+	let mut v1_config = resources::load_assembler_config(HardwareVersion::V1).expect("Unable to load assembler config");
+	let v2_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let words = |source: &str, config: &AssemblerConfig| -> Vec<u16> {
+		compiler::compiler_pipeline(source, None, config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors))).words
+	};
+	// Hardware version, excluded `@include()`s aren't read
+	let source = "@ifdef(HARDWARE_V2)\nwrite 0x02 alu-a;\n@else\n@include(\"does_not_exist.asm\");\nwrite 0x01 alu-a;\n@endif\n";
+	assert!(compiler::compiler_pipeline(source, None, &v1_config).is_err());
+	assert_eq!(words(source, &v2_config), [0x2021]);
+	// `-D` flags, with `@if` blocks inside sharing `@else` and `@endif`
+	let source = "@ifndef(FAST)
+	@if(alu)
+		write 0x10 alu-a;
+	@else
+		write 0x11 alu-a;
+	@endif
+@else
+	write 0x20 alu-a;
+@endif
+@ifdef(FAST)
+	@ifdef(HARDWARE_V1)
+		write 0x30 alu-a;
+	@endif
+@endif";
+	assert_eq!(words(source, &v1_config).len(), 12);
+	v1_config.defines.insert("FAST".to_owned());
+	assert_eq!(words(source, &v1_config), [0x2201, 0x2301]);
+	// Nesting
+	let errors: Vec<String> = match compiler::compiler_pipeline("@ifdef(FAST)\n@endwhile\n", None, &v1_config) {
+		Ok(_) => panic!("Should not assemble"),
+		Err(errors) => errors.iter().map(|error| error.enum_.to_string()).collect()
+	};
+	assert_eq!(errors, ["@ifdef has no matching @endif", "@endwhile without @while"]);
+}
+
+#[test]
+fn command_line_flags() {
+	let args: Vec<String> = ["stack_machine", "-assemble-line", "-D", "FAST", "write", "-1", "alu-a;", "-optimize", "-DSLOW", "--deny-warnings", "-version=2"].iter().map(|arg| arg.to_string()).collect();
+	let (other_args, defines) = crate::split_common_flags(&args);
+	assert_eq!(other_args, ["stack_machine", "-assemble-line", "write", "-1", "alu-a;"]);
+	assert_eq!(defines, std::collections::HashSet::from(["FAST".to_owned(), "SLOW".to_owned()]));
+	// Negative literal is kept
+	assert_eq!(assemble_v2(&other_args[2..].join(" ")).unwrap().words, assemble_v2("write 0xFF alu-a;").unwrap().words);
+}
+
+#[test]
+fn data_blocks() {
+	let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let dir = std::env::temp_dir().join(format!("stack_machine_data_test_{}", std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	std::fs::write(dir.join("raw.bin"), [9, 8, 7]).unwrap();
+	// 10 x 2 image, rows are padded to 2 bytes
+	std::fs::write(dir.join("image.pbm"), [b"P4\n# comment\n10 2\n".as_slice(), &[0xFF, 0xC0, 0x80, 0x40]].concat()).unwrap();
+	std::fs::write(dir.join("ascii.pbm"), "P1\n1 1\n1\n").unwrap();
+	std::fs::write(dir.join("huge.pbm"), "P4\n18446744073709551615 18446744073709551615\n").unwrap();
+	std::fs::write(dir.join("short.pbm"), "P4\n16 2\n\x7F").unwrap();
+	std::fs::write(dir.join("large.bin"), vec![0u8; u16::MAX as usize]).unwrap();
+	let assemble = |source: &str| compiler::compiler_pipeline_at(source, Some("main"), &dir.join("main"), &assembler_config);
+	let source = "@var buffer[5]
+@const C = 3
+@data(buffer) 1, 'A', (C * 2),
+	\"hi\"
+@macro fill(at, value)
+@data(at) value, (value + 1)
+@endmacro
+@fill(0x20, 7);
+@binfile(0x40, \"raw.bin\")
+@binfile(\"image.pbm\")
+halt;";
+	let program = assemble(source).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(program.words.len(), 7 + 4 + 5 + 4 + 1);
+	let mut machine = Machine::new(program.words.clone(), HardwareVersion::V2);
+	machine.run(&mut GpioInterfaceDoesNothing).unwrap();
+	assert_eq!(machine.general_mem[0..5], [1, b'A', 6, b'h', b'i']);
+	assert_eq!(machine.general_mem[0x20..0x22], [7, 8]);
+	assert_eq!(machine.general_mem[0x40..0x47], [9, 8, 7, 0xFF, 0xC0, 0x80, 0x40]);
+	// Cost in the listing
+	let listing = compiler::listing::listing(&program, &assembler_config);
+	assert!(listing.contains("# Data\n0x0000  7     @data(buffer) 1, 'A', (C * 2), \"hi\"\n0x0007  4     @data(0x20) 7, (7 + 1)\n0x000B  5     @binfile(0x40, \"raw.bin\")\n0x0010  4     @binfile(\"image.pbm\")\n"), "{}", listing);
+	// Errors
+	assert_eq!(first_error(assemble("@data(0x10000) 1")), "65536 is not a GPRAM address");
+	assert_eq!(first_error(assemble("@data(0) 1, 300")), "300 doesn't fit in 8 bits");
+	assert_eq!(first_error(assemble("@data(missing) 1")), "\"missing\" is not a @const, @var or anchor");
+	assert!(first_error(assemble("@binfile(\"missing.bin\")")).starts_with("Could not read included file"));
+	assert!(first_error(assemble("@binfile(\"ascii.pbm\")")).starts_with("Invalid image"));
+	assert!(first_error(assemble("@binfile(\"huge.pbm\")")).ends_with(": 18446744073709551615 x 18446744073709551615 image is too large"));
+	assert!(first_error(assemble("@binfile(\"short.pbm\")")).ends_with(": 16 x 2 image should have 4 bytes of pixels"));
+	assert!(first_error(assemble("@binfile(\"large.bin\")")).ends_with("large.bin\" is 65535 bytes, which is too large to load in a program"));
+	assert_eq!(first_error(assemble("@data(0) \"é\"")), "'é' is not an ASCII character, @data strings are 1 byte per character");
+	assert!(first_error(assemble("@binfile(0, 1)")).starts_with("Include path must be a string literal"));
+	assert_eq!(first_error(assemble("@var a[2]\n@var b[2]\n@data(b) 1, 2, 3")), "Data goes past the edge of @var \"b\"");
+	assert_eq!(first_error(assemble("@var a[0x41]\n@binfile(0x40, \"raw.bin\")")), "Data goes past the edge of @var \"a\"");
+	std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn gpram_code() {
+	let v1_config = resources::load_assembler_config(HardwareVersion::V1).expect("Unable to load assembler config");
+	let v2_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let assemble = |source: &str| compiler::compiler_pipeline(source, Some("main"), &v2_config);
+	// Calls both ways between flash and GPRAM
+	let source = "@const CODE = 0x8000
+write 0x05 alu-a;
+@call(add-three);
+move a alu stack-push;
+halt;
+@anchor(add-one);
+write 0x01 alu-b;
+move add alu alu-a;
+return;
+@org(CODE)
+@anchor(add-three);
+@call(add-one);
+@call(add-one);
+@call(add-one);
+return;
+@org(0x8010)
+@anchor(second);
+halt;";
+	let program = assemble(source).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	// Bootstrap is 2 instructions to set the address and 2 per instruction for each section
+	let section_sizes: [usize; 2] = [10, 1];
+	let bootstrap_size: usize = section_sizes.iter().map(|section_size| 2 + 2 * section_size).sum();
+	assert_eq!(program.words.len(), bootstrap_size + 9);
+	assert_eq!(program.anchors.get("add-one"), Some(&(bootstrap_size + 6)));
+	assert_eq!(program.anchors.get("add-three"), Some(&0x8000));
+	assert_eq!(program.anchors.get("second"), Some(&0x8010));
+	assert_eq!(program.gpram_sections.iter().map(|section| (section.address, section.words.len())).collect::<Vec<(usize, usize)>>(), [(0x8000, section_sizes[0]), (0x8010, section_sizes[1])]);
+	assert_eq!(program.describe_address(0x8003), "main:13 (@anchor(add-three)+3)");
+	let mut machine = Machine::new(program.words.clone(), HardwareVersion::V2);
+	machine.run(&mut GpioInterfaceDoesNothing).unwrap();
+	assert_eq!(machine.stack_mem[1], 8);
+	assert_eq!(machine.general_mem[0x20..0x22], program.gpram_sections[1].words[0].to_le_bytes());
+	let listing = compiler::listing::listing(&program, &v2_config);
+	assert!(listing.contains("\n# GPRAM code at 0x8010, GPRAM address 0x0020\n0x8010  0x0004"), "{}", listing);
+	// Gaps in flash are filled
+	let program = assemble("halt;\n@org(4)\nhalt;").unwrap();
+	assert_eq!(program.words, [0x0004, 0x0001, 0x0001, 0x0001, 0x0004]);
+	// `@data` run from GPRAM is counted as data too
+	let program = assemble("halt;\n@org(0x8000)\n@data(0x40) 1, 2\nreturn;").unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	assert_eq!(program.data_blocks().iter().map(|(address, len, _)| (*address, *len)).collect::<Vec<(usize, usize)>>(), [(0x8000, 4)]);
+	assert!(compiler::listing::listing(&program, &v2_config).contains("# Data\n0x8000  4     @data(0x40) 1, 2\n"));
+	// Errors
+	assert_eq!(first_error(compiler::compiler_pipeline("@org(0x8000)\nhalt;", None, &v1_config)), "Hardware version 1 can't run code from GPRAM");
+	assert_eq!(first_error(assemble_v2("halt;\nhalt;\n@org(1)")), "Code at 0x0001 overlaps code before it, which ends at 0x0002");
+	assert_eq!(first_error(assemble_v2("@org(0x8000)\nhalt;\nhalt;\n@org(0x8001)")), "Code at 0x8001 overlaps code before it, which ends at 0x8002");
+	// Bootstrap is in flash too
+	assert_eq!(first_error(assemble_v2("@org(0x8000)\nhalt;\n@org(0)")), "Code at 0x0000 overlaps code before it, which ends at 0x0004");
+	assert_eq!(first_error(assemble_v2("@var buffer[4]\n@org(0x8001)\nhalt;")), "Code in GPRAM overlaps @var \"buffer\"");
+	assert_eq!(first_error(assemble_v2("@org(0x8000)\nhalt;\n@data(1) 1")), "Data overlaps code in GPRAM");
+	assert_eq!(first_error(assemble_v2("@org(0x10000)")), "65536 is not a program address");
+}
+
+#[test]
+fn peephole_optimizer() {
+	let mut config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let unoptimized_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	config.optimize = true;
+	let assemble = |source: &str, config: &AssemblerConfig| compiler::compiler_pipeline(source, None, config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+	let source = "@goto_if(a);
+@goto_if(b);
+write 0x05 alu-b;
+write 0x05 alu-b;
+move gpio-read-a stack-push;
+move stack-pop alu-a;
+write 0x05 alu-b;
+halt;
+@anchor(a);
+write 0x05 alu-b;
+halt;
+@anchor(b);
+halt;";
+	let program = assemble(source, &config);
+	// Anchors are moved by the removed instructions, nothing is known at an anchor
+	let expected = "write 0x07 goto-a;
+write 0x00 goto-b;
+goto-if;
+write 0x09 goto-a;
+goto-if;
+write 0x05 alu-b;
+move gpio-read-a alu-a;
+halt;
+write 0x05 alu-b;
+halt;
+halt;";
+	assert_eq!(program.words, assemble(expected, &unoptimized_config).words);
+	assert_eq!(program.anchors.get("b"), Some(&10));
+	let report = program.optimization_opt.unwrap();
+	assert_eq!((report.writes_removed, report.push_pop_pairs, report.instructions_saved(), report.cycles_saved()), (3, 1, 4, 96));
+	assert!(assemble(source, &unoptimized_config).optimization_opt.is_none());
+	// Removing the second `goto-b` write would move `y` back to 0x100, so its high byte would be different and the write is needed after all
+	let source = format!("@goto_if(x);\n@goto_if(y);\n{}@anchor(y);\n{}@anchor(x);\nhalt;", "halt;\n".repeat(0x101 - 6), "halt;\n".repeat(0x150 - 0x101));
+	let program = assemble(&source, &config);
+	assert_eq!(program.words, assemble(&source, &unoptimized_config).words);
+	assert_eq!(program.optimization_opt.unwrap().instructions_saved(), 0);
+	// Offsets could jump anywhere
+	let program = assemble("@anchor(main);\nwrite 0x01 alu-a;\nwrite 0x01 alu-a;\n@goto(main+1);", &config);
+	assert_eq!(program.words.len(), 5);
+	assert!(program.optimization_opt.unwrap().skipped_opt.is_some());
+}
+
+#[test]
+fn stack_analysis() {
+	use compiler::stack_analysis::{self, StackIssueEnum};
+	let config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let analyze = |source: &str| {
+		let program = compiler::compiler_pipeline(source, None, &config).unwrap_or_else(|errors| panic!("{}", compiler::format_errors(&errors)));
+		(stack_analysis::analyze(&program, &config), program)
+	};
+	let issues = |analysis: &stack_analysis::StackAnalysis| -> Vec<(usize, StackIssueEnum)> {analysis.issues.iter().map(|issue| (issue.address, issue.enum_.clone())).collect()};
+	// Per-function deltas, depths at each call and unreachable code
+	let (analysis, program) = analyze("@call(push-two);
+@call(pop-one);
+move stack-pop alu-a;
+@call(peek);
+halt;
+@anchor(push-two);
+write 0x01 stack-push;
+write 0x02 stack-push;
+return;
+@anchor(pop-one);
+move stack-pop alu-a;
+return;
+@anchor(peek);
+write 0x00 set-stack-offset;
+move offset-read alu-a;
+return;
+@anchor(unused);
+halt;");
+	let summary = |name: &str| {
+		let function = analysis.function(name).unwrap();
+		(function.net_delta_opt, function.max_depth, function.entry_depth_opt)
+	};
+	assert_eq!(summary("push-two"), (Some(2), 2, Some(0)));
+	assert_eq!(summary("pop-one"), (Some(-1), 0, Some(2)));
+	assert_eq!(summary("peek"), (Some(0), 0, Some(0)));
+	assert_eq!(analysis.function("0x0000").unwrap().max_depth, 2);
+	assert_eq!(analysis.max_call_depth_opt, Some(1));
+	assert_eq!(issues(&analysis), vec![
+		(program.anchors["peek"] + 1, StackIssueEnum::OffsetBeyondStack{position: 0, known: 0}),
+		(program.anchors["unused"], StackIssueEnum::Unreachable(1))
+	]);
+	// Recursion, and a push on only one path
+	let (analysis, program) = analyze("@call(recurse);
+halt;
+@anchor(recurse);
+@goto_if(skip);
+write 0x01 stack-push;
+@anchor(skip);
+@call(recurse);
+return;");
+	assert_eq!(issues(&analysis), vec![
+		(program.anchors["skip"], StackIssueEnum::UnbalancedPaths(0, 1)),
+		(program.anchors["skip"] + 2, StackIssueEnum::Recursion(vec!["recurse".to_owned(), "recurse".to_owned()]))
+	]);
+	assert_eq!(analysis.max_call_depth_opt, None);
+	assert!(!analysis.function("recurse").unwrap().depths_known);
+	// Interrupt handlers have to leave the stack as it was, and are part of the call depth
+	let (analysis, program) = analyze("@set_interrupt(handler);
+config-int true;
+move stack-pop alu-a;
+halt;
+@anchor(handler);
+write 0x01 stack-push;
+return int;");
+	assert_eq!(analysis.interrupt_handlers, vec![program.anchors["handler"]]);
+	assert_eq!(analysis.max_call_depth_opt, Some(1));
+	assert_eq!(issues(&analysis), vec![
+		(3, StackIssueEnum::PopUnderflow(0)),
+		(program.anchors["handler"], StackIssueEnum::UnbalancedInterruptHandler(1))
+	]);
+	assert!(analysis.format(&program).contains("Warning at line 3: Pops from the stack when it can have 0 byte(s) on it"));
+}
+
+#[test]
+fn lints() {
+	let mut config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let source = "move alu stack-push;
+move add alu stack-push;
+move ext-12 alu alu-a;
+move not stack-pop alu-a;
+move TX-20 alu-a;
+write 0x01 RX-30;
+move alu alu-b;# allow(implicit-add)
+move alu alu-b; # Sum # allow( unused-alu-op, implicit-add )
+move alu alu-b;# allow(implicit-ad)
+halt;";
+	let program = compiler::compiler_pipeline(source, None, &config).unwrap();
+	let warnings: Vec<(usize, &str)> = program.warnings.iter().map(|warning| (warning.location.line, warning.lint.code())).collect();
+	assert_eq!(warnings, vec![
+		(1, "implicit-add"),
+		(3, "ext-alu-op"),
+		(4, "unused-alu-op"),
+		(5, "invalid-device"),
+		(6, "invalid-device"),
+		(9, "implicit-add"),
+		(9, "unknown-lint")
+	]);
+	assert_eq!(program.warnings[0].to_string(), "warning[implicit-add]: `move` from `alu` without an ALU opcode uses `add`
+ --> <input>:1:1
+  |
+1 | move alu stack-push;
+  | ^^^^^^^^^^^^^^^^^^^^
+  = note: `# allow(implicit-add)` at the end of the line hides this");
+	assert_eq!(program.warnings[6].location.column, 24);
+	// Every warning is an error
+	config.deny_warnings = true;
+	let errors = compiler::compiler_pipeline(source, None, &config).unwrap_err();
+	assert_eq!(errors.len(), 7);
+	assert!(errors[0].to_string().starts_with("error: `move` from `alu` without an ALU opcode uses `add` (`implicit-add`, denied by --deny-warnings)"));
+	// Version 2 words don't exist for version 1
+	let config = resources::load_assembler_config(HardwareVersion::V1).expect("Unable to load assembler config");
+	let errors = compiler::compiler_pipeline("write 0x01 int-goto-a;", None, &config).unwrap_err();
+	assert_eq!(errors[0].message_opt.as_deref(), Some("`int-goto-a` only exists on hardware version 2"));
+}
+
+#[test]
+fn formatter() {
+	use compiler::format::format_source;
+	let config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let source = "# Header  \r
+
+
+@const LIMIT = 0x10;
+Write 0x01 GPRAM-addr-a; MOVE gpram stack-push;# Load
+@anchor(main);
+# Loop
+@anchor(.loop);
+move Add alu stack-push; # Sum
+write lo(LIMIT)  alu-b;   #   Limit
+@if(eq, alu)
+call;
+@else
+@data(0x0000) 0x01,
+    0x02;#after data
+@endif
+@goto_if(.loop)
+@macro store(value, destination)
+write value destination;
+@endmacro
+";
+	let formatted = format_source(source, None, &config).unwrap();
+	assert_eq!(formatted, "# Header  
+
+@const LIMIT = 0x10;
+write 0x01 GPRAM-addr-a;
+move GPRAM stack-push; # Load
+@anchor(main);
+# Loop
+@anchor(.loop);
+	move add alu stack-push; # Sum
+	write lo(LIMIT) alu-b;   #   Limit
+	@if(eq, alu);
+		call;
+	@else;
+		@data(0x0000) 0x01,
+			0x02; #after data
+	@endif;
+	@goto_if(.loop);
+	@macro store(value, destination);
+		write value destination;
+	@endmacro;
+");
+	// Already formatted
+	assert_eq!(format_source(&formatted, None, &config).unwrap(), formatted);
+	// Doesn't format something that doesn't parse
+	assert!(format_source("move alu;;\nmve $ alu-a;", None, &config).is_err());
+	// Every source that parses has the same instructions, macros and comments after formatting, and formatting it again changes nothing
+	let contents = |text: &str| -> Vec<String> {
+		let source: Vec<char> = text.chars().collect();
+		SyntaxTreeNode::build_tree(&source).unwrap().children.iter().map(|node| match &node.type_ {
+			SyntaxTreeNodeType::Instruction => node.children.iter().map(|child| match &child.type_ {
+				SyntaxTreeNodeType::InstructionToken(token) => token.raw.to_lowercase(),
+				_ => String::new()
+			}).collect::<Vec<String>>().join(" "),
+			SyntaxTreeNodeType::Macro(macro_) => macro_.format(),
+			_ => source[node.begin..node.end].iter().collect::<String>().trim_end_matches('\r').to_owned()
+		}).collect()
+	};
+	let mut n_formatted: usize = 0;
+	for entry in std::fs::read_dir(resources::ASSEMBLY_SOURCES_DIR).unwrap() {
+		let path = entry.unwrap().path();
+		let text: String = match std::fs::read_to_string(&path) {
+			Ok(text) => text,
+			Err(_) => continue
+		};
+		if let Ok(formatted) = format_source(&text, None, &config) {
+			assert_eq!(contents(&formatted), contents(&text), "{:?}", path);
+			assert_eq!(format_source(&formatted, None, &config).unwrap(), formatted, "{:?}", path);
+			n_formatted += 1;
+		}
+	}
+	assert!(n_formatted > 10);
+}
+
+#[test]
+fn language_server() {
+	use serde_json::{json, Value};
+	use crate::language_server::LanguageServer;
+	let config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+	let uri = "file:///tmp/language_server_test.asm";
+	let source = "@anchor(main);
+write 0x01 alu-a;
+@anchor(.loop);
+move alu stack-push;
+@goto(.loop);
+@call(helper);
+halt;
+@anchor(helper);
+return;";
+	let position = |id: u64, method: &str, line: u64, character: u64| -> Value {
+		json!({"jsonrpc": "2.0", "id": id, "method": method, "params": {"textDocument": {"uri": uri}, "position": {"line": line, "character": character}, "context": {"includeDeclaration": true}}})
+	};
+	// Scripted client
+	let messages: Vec<Value> = vec![
+		json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"capabilities": {}}}),
+		json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+		json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {"textDocument": {"uri": uri, "languageId": "asm", "version": 1, "text": source}}}),
+		json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {"textDocument": {"uri": uri, "version": 2}, "contentChanges": [{"text": "mve alu-a;"}]}}),
+		json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {"textDocument": {"uri": uri, "version": 3}, "contentChanges": [{"text": source}]}}),
+		position(2, "textDocument/completion", 1, 2),
+		position(3, "textDocument/completion", 3, 5),
+		position(4, "textDocument/completion", 3, 9),
+		position(5, "textDocument/definition", 4, 7),
+		position(6, "textDocument/references", 2, 9),
+		position(7, "textDocument/hover", 5, 8),
+		position(8, "textDocument/hover", 1, 3),
+		json!({"jsonrpc": "2.0", "id": 9, "method": "workspace/symbol", "params": {}}),
+		json!({"jsonrpc": "2.0", "id": 10, "method": "shutdown"}),
+		json!({"jsonrpc": "2.0", "method": "exit"})
+	];
+	let mut input = Vec::<u8>::new();
+	for message in &messages {
+		let body: String = message.to_string();
+		input.extend(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).bytes());
+	}
+	let mut output = Vec::<u8>::new();
+	let mut server = LanguageServer::new(config);
+	server.run(&mut std::io::Cursor::new(input), &mut output).unwrap();
+	assert_eq!(server.exit_code_opt, Some(0));
+	// Split replies
+	let output = String::from_utf8(output).unwrap();
+	let mut replies = Vec::<Value>::new();
+	let mut rest: &str = &output;
+	while let Some(header_end) = rest.find("\r\n\r\n") {
+		let len: usize = rest[..header_end].strip_prefix("Content-Length: ").unwrap().parse().unwrap();
+		replies.push(serde_json::from_str(&rest[header_end + 4..header_end + 4 + len]).unwrap());
+		rest = &rest[header_end + 4 + len..];
+	}
+	assert!(rest.is_empty());
+	let labels = |result: &Value| -> Vec<String> {result.as_array().unwrap().iter().map(|item| item["label"].as_str().unwrap().to_owned()).collect()};
+	let line_range = |line: u64, begin: u64, end: u64| -> Value {json!({"start": {"line": line, "character": begin}, "end": {"line": line, "character": end}})};
+	assert_eq!(replies.len(), 13);
+	assert_eq!(replies[0]["id"], 1);
+	assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+	// Diagnostics: warning, error, warning again
+	let diagnostics = &replies[1]["params"]["diagnostics"];
+	assert_eq!(replies[1]["method"], "textDocument/publishDiagnostics");
+	assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+	assert_eq!(diagnostics[0]["severity"], 2);
+	assert_eq!(diagnostics[0]["code"], "implicit-add");
+	assert_eq!(diagnostics[0]["range"], line_range(3, 0, 20));
+	let diagnostics = &replies[2]["params"]["diagnostics"];
+	assert_eq!(diagnostics[0]["severity"], 1);
+	assert_eq!(diagnostics[0]["range"], line_range(0, 0, 3));
+	assert_eq!(replies[3]["params"]["diagnostics"].as_array().unwrap().len(), 1);
+	// Completion
+	assert_eq!(labels(&replies[4]["result"]), vec!["write"]);
+	let after_move: Vec<String> = labels(&replies[5]["result"]);
+	assert!(after_move.contains(&"add".to_owned()) && after_move.contains(&"stack-pop".to_owned()) && !after_move.contains(&"stack-push".to_owned()));
+	let after_source: Vec<String> = labels(&replies[6]["result"]);
+	assert!(after_source.contains(&"stack-push".to_owned()) && !after_source.contains(&"add".to_owned()));
+	assert_eq!(replies[6]["result"][0]["kind"], 14);
+	// Definition and references of `.loop`
+	assert_eq!(replies[7]["result"], json!([{"uri": uri, "range": line_range(2, 8, 13)}]));
+	assert_eq!(replies[8]["result"], json!([{"uri": uri, "range": line_range(2, 8, 13)}, {"uri": uri, "range": line_range(4, 6, 11)}]));
+	// Hover
+	let hover: &str = replies[9]["result"]["contents"]["value"].as_str().unwrap();
+	assert!(hover.starts_with("`helper` at `0x0009`"), "{}", hover);
+	assert_eq!(hover.matches("\n0x000").count(), 3);
+	assert!(hover.contains("0x0005") && hover.contains("0x0007  0x0005  0000000000000101  call"), "{}", hover);
+	assert!(replies[10]["result"]["contents"]["value"].as_str().unwrap().contains("0x0000  0x2011  0010000000010001  write 0x01 alu-a"), "{}", replies[10]);
+	// Unknown method, shutdown
+	assert_eq!(replies[11]["error"]["code"], -32601);
+	assert_eq!(replies[12], json!({"jsonrpc": "2.0", "id": 10, "result": null}));
+	// Content-Length too large to allocate
+	let mut server = LanguageServer::new(resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config"));
+	let result = server.run(&mut std::io::Cursor::new(b"Content-Length: 18446744073709551615\r\n\r\n".to_vec()), &mut Vec::<u8>::new());
+	assert_eq!(result, Err("Content-Length 18446744073709551615 is more than the maximum of 67108864".to_owned()));
+}
+
 // Version 2
 #[cfg(test)]
 mod tests_v2 {
 	use super::*;
+	use crate::emulator::{Timers, MachineComponent};
 	#[test]
 	fn move_5_bit_bus_addresses() {
-		let assembler_config = resources::load_assembler_config().expect("Unable to load assembler config");
+		let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
 		let assembly_source = "
 			move get-goto-a int-goto-b;# get-goto-a = 15, int-goto-b = 17
 			move tx-17 gpio-write-b;# tx-17 = 17, gpio-write-b = 15
@@ -248,7 +1309,7 @@ mod tests_v2 {
 	}
 	#[test]
 	fn write_5_bit_bus_addresses() {
-		let assembler_config = resources::load_assembler_config().expect("Unable to load assembler config");
+		let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
 		let assembly_source = "
 			write 0x42 int-goto-b;# int-goto-b = 17
 		";
@@ -260,7 +1321,7 @@ mod tests_v2 {
 	}
 	#[test]
 	fn call_and_return_and_config_interrupt() {
-		let assembler_config = resources::load_assembler_config().expect("Unable to load assembler config");
+		let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
 		let assembly_source = "
 			call int;
 			return int;
@@ -273,4 +1334,233 @@ mod tests_v2 {
 		};
 		assert_eq!(program[..], [0x0015, 0x0016, 0x0017, 0x0007]);
 	}
+	#[test]
+	fn execute_v2_opcodes() {
+		let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+		// CONFIG-INT and the opcode 11 WRITE
+		let assembly_source = "
+			config-int true;
+			config-int false;
+			write 0x42 rx-26;
+		";
+		let program: Vec<u16> = match compiler::compiler_pipeline_formated_errors(assembly_source, &assembler_config) {
+			Ok(program) => program,
+			Err(s) => panic!("{}", s)
+		};
+		let mut machine = Machine::new(program, HardwareVersion::V2);
+		assert!(!machine.execute_instruction(&mut GpioInterfaceDoesNothing).unwrap());
+		assert!(!machine.execute_instruction(&mut GpioInterfaceDoesNothing).unwrap());
+		// RX 26 is an empty expansion slot, so this should fail with the reconstructed 5-bit address
+		assert!(matches!(machine.execute_instruction(&mut GpioInterfaceDoesNothing).unwrap_err().enum_, emulator::EmulationErrorEnum::InvalidBusReadAddr(26)));
+		// MOVE opcodes 8, 9 and 10
+		for (assembly_source, tx, rx) in [("move tx-17 gpio-write-b;", Some(17), None), ("move stack-pop rx-26;", None, Some(26)), ("move tx-17 rx-26;", Some(17), Some(26))] {
+			let program: Vec<u16> = match compiler::compiler_pipeline_formated_errors(assembly_source, &assembler_config) {
+				Ok(program) => program,
+				Err(s) => panic!("{}", s)
+			};
+			let mut machine = Machine::new(program, HardwareVersion::V2);
+			match machine.execute_instruction(&mut GpioInterfaceDoesNothing).unwrap_err().enum_ {
+				emulator::EmulationErrorEnum::InvalidBusWriteAddr(addr) => assert_eq!(Some(addr), tx),
+				emulator::EmulationErrorEnum::InvalidBusReadAddr(addr) => {
+					assert_eq!(tx, None);
+					assert_eq!(Some(addr), rx);
+				},
+				other => panic!("Unexpected error {:?}", other)
+			}
+		}
+	}
+	#[test]
+	fn bus_devices_5_bit() {
+		let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+		let assembly_source = "
+			write 0x12 goto-a;
+			write 0x34 goto-b;
+			write 0x05 set-stack-offset;
+			move get-goto-a stack-push;
+			move get-goto-b stack-push;
+			move get-stack-offset stack-push;
+			move int-count stack-push;
+			move int-code stack-push;
+			write 0x56 vectors-a;
+			write 0x78 vectors-d;
+			write 0x9A flash;
+			write 0x01 int-and-main-timer-address;
+			write 0x31 int-timer-config-max;
+			write 0x30 int-timer-config-timebase-and-enable;
+			halt;
+		";
+		let program: Vec<u16> = match compiler::compiler_pipeline_formated_errors(assembly_source, &assembler_config) {
+			Ok(program) => program,
+			Err(s) => panic!("{}", s)
+		};
+		let mut machine = Machine::new(program, HardwareVersion::V2);
+		machine.run(&mut GpioInterfaceDoesNothing).unwrap();
+		// Stack offset is inverted by the assembler, empty interrupt queue reads as 0
+		assert_eq!(machine.stack_mem[1..6], [0x12, 0x34, 0xFA, 0x00, 0x00]);
+		assert_eq!(machine.vectors, [0x56, 0x00, 0x00, 0x78]);
+		assert_eq!(machine.flash_interface_data, 0x9A);
+	}
+	/// Remembers everything written to the GPIO outputs
+	struct GpioInterfaceRecorder {
+		pub a: Vec<u8>,
+		pub b: Vec<u8>
+	}
+	impl GpioInterface for GpioInterfaceRecorder {
+		fn write_a(&mut self, in_: u8) {
+			self.a.push(in_);
+		}
+		fn write_b(&mut self, in_: u8) {
+			self.b.push(in_);
+		}
+	}
+	#[test]
+	fn interrupt_code() {
+		let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+		let assembly_source = "
+			move int-count stack-push;
+			move int-code stack-push;
+			move int-code stack-push;
+			move int-count stack-push;
+			halt;
+		";
+		let program: Vec<u16> = match compiler::compiler_pipeline_formated_errors(assembly_source, &assembler_config) {
+			Ok(program) => program,
+			Err(s) => panic!("{}", s)
+		};
+		let mut machine = Machine::new(program.clone(), HardwareVersion::V2);
+		machine.trigger_interrupt(5, 0xA);
+		machine.trigger_interrupt(7, 0x3);
+		machine.run(&mut GpioInterfaceDoesNothing).unwrap();
+		// Source in bits 0 - 2, extra bits 4 - 7
+		assert_eq!(machine.stack_mem[1..5], [2, 0xA5, 0x37, 0]);
+		// Version 1 has no interrupts
+		let mut machine = Machine::new(program, HardwareVersion::V1);
+		machine.trigger_interrupt(5, 0xA);
+		assert_eq!(machine.interrupt_state(), (false, false, 0));
+	}
+	#[test]
+	fn timer_rollover_counts() {
+		let mut timers = Timers::new();
+		// Max of n rolls over at n+1
+		timers.set_int_timer_max(49);
+		timers.set_int_timer_timebase_and_enable(0x30);
+		assert_eq!(timers.update(50 * 3 + 10).len(), 3);
+		assert_eq!(timers.interrupt_timers_state[0], 10);
+		// Max of 255 rolls over normally
+		timers.set_int_timer_max(255);
+		assert!(timers.update(245).is_empty());
+		assert_eq!(timers.update(1), vec![0]);
+		// Interrupt disabled, still counts
+		timers.set_int_timer_timebase_and_enable(0x20);
+		assert!(timers.update(256 + 5).is_empty());
+		assert_eq!(timers.interrupt_timers_state[0], 5);
+		// Counting disabled
+		timers.set_int_timer_timebase_and_enable(0x10);
+		assert!(timers.update(1000).is_empty());
+		assert_eq!(timers.interrupt_timers_state[0], 5);
+		// Reset with bit 2 of the address
+		timers.set_int_and_main_timer_address(0b100);
+		assert_eq!(timers.interrupt_timers_state[0], 0);
+	}
+	#[test]
+	fn timer_timebase() {
+		let mut timers = Timers::new();
+		// Timer 1: 250 KHz, timer 2: 62.5 KHz
+		timers.set_int_and_main_timer_address(1);
+		timers.set_int_timer_max(9);
+		timers.set_int_timer_timebase_and_enable(0x31);
+		timers.set_int_and_main_timer_address(2);
+		timers.set_int_timer_max(0);
+		timers.set_int_timer_timebase_and_enable(0x32);
+		// Ticks that don't line up with the timebase are remembered by the base timer
+		let mut interrupts = Vec::<(u64, u8)>::new();
+		for tick in 1..=64 {
+			for code in timers.update(1) {
+				interrupts.push((tick, code));
+			}
+		}
+		assert_eq!(interrupts, [(16, 2), (32, 2), (40, 1), (48, 2), (64, 2)]);
+		// Max set below current count, has to wrap around the 8-bit counter first
+		timers.set_int_timer_timebase_and_enable(0x00);
+		timers.set_int_and_main_timer_address(1);
+		assert_eq!(timers.read_int_timer(), 6);
+		timers.set_int_timer_max(3);
+		assert_eq!(timers.update(4 * 249).len(), 0);
+		assert_eq!(timers.read_int_timer(), 255);
+		assert_eq!(timers.update(4 * 5).len(), 1);
+		assert_eq!(timers.read_int_timer(), 0);
+	}
+	#[test]
+	fn main_timer() {
+		let mut timers = Timers::new();
+		timers.update(0xABCDEF123);
+		let bytes: Vec<u8> = (0..4).map(|address| {
+			timers.set_int_and_main_timer_address(address);
+			timers.read_main_timer()
+		}).collect();
+		assert_eq!(bytes, [0x12, 0xEF, 0xCD, 0xAB]);
+		// 36-bit rollover
+		timers.update(0xFFFFFFFFF - 0xABCDEF123 + 1);
+		assert_eq!(timers.base_timer, 0);
+	}
+	#[test]
+	fn timers_from_machine_clock() {
+		let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+		let assembly_source = "
+			write 0x01 int-and-main-timer-address;
+			write 0x20 int-timer-config-timebase-and-enable;
+			move clk-counter-b stack-push;
+			move clk-counter-b stack-push;
+			halt;
+		";
+		let program: Vec<u16> = match compiler::compiler_pipeline_formated_errors(assembly_source, &assembler_config) {
+			Ok(program) => program,
+			Err(s) => panic!("{}", s)
+		};
+		let mut machine = Machine::new(program.clone(), HardwareVersion::V2);
+		// 2 machine clock cycles per timer tick, MOVE/WRITE take 24 cycles
+		machine.machine_clock_hz = 2_000_000;
+		machine.run(&mut GpioInterfaceDoesNothing).unwrap();
+		assert_eq!(machine.stack_mem[1..3], [12, 24]);
+		// Timers don't move with a 0 Hz clock
+		let mut machine = Machine::new(program, HardwareVersion::V2);
+		machine.machine_clock_hz = 0;
+		machine.run(&mut GpioInterfaceDoesNothing).unwrap();
+		assert_eq!(machine.stack_mem[1..3], [0, 0]);
+	}
+	#[test]
+	fn interrupt_dispatch() {
+		let assembler_config = resources::load_assembler_config(HardwareVersion::V2).expect("Unable to load assembler config");
+		let path: String = resources::ASSEMBLY_SOURCES_DIR.to_owned() + "interrupt_and_timers_test";
+		let file_raw = std::fs::read_to_string(&path).unwrap();
+		let program: Vec<u16> = match compiler::compiler_pipeline_formated_errors(&file_raw, &assembler_config) {
+			Ok(program) => program,
+			Err(s) => panic!("{}", s)
+		};
+		let mut machine = Machine::new(program, HardwareVersion::V2);
+		// The program sets timer 0 to cause an interrupt every 50 us, so the clock has to be fast enough to run the handler in that time
+		machine.machine_clock_hz = 40_000_000;
+		let mut gpio = GpioInterfaceRecorder{a: Vec::new(), b: Vec::new()};
+		// Interrupts queued before `config-int true` should wait
+		machine.trigger_interrupt(4, 0);
+		for i in 0..300 {
+			// Interrupt in the middle of the main loop, and 2 at once to check that they don't nest
+			if i == 100 || i == 101 || i == 213 {
+				machine.trigger_interrupt(5, 0);
+			}
+			machine.execute_instruction(&mut gpio).unwrap();
+		}
+		// Setup, then the main loop counting up without being disturbed by the interrupts
+		assert_eq!(gpio.a[0..2], [0xFF, 0x00]);
+		for (i, n) in gpio.a[2..].iter().enumerate() {
+			assert_eq!(*n as usize, i + 1);
+		}
+		assert!(gpio.a.len() > 20);
+		// The interrupt handler shifts a 1 left each time, 4 external interrupts and at least 1 from the timer
+		assert_eq!(gpio.b[0..2], [0x00, 0x02]);
+		for i in 2..gpio.b.len() {
+			assert_eq!(gpio.b[i], gpio.b[i - 1].rotate_left(1));
+		}
+		assert!(gpio.b.len() >= 6);
+	}
 }
\ No newline at end of file